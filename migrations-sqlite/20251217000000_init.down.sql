@@ -0,0 +1,4 @@
+DROP TABLE feed_cache;
+DROP TABLE failures;
+DROP TABLE feed_items;
+DROP TABLE feed_groups;
@@ -0,0 +1,33 @@
+-- Timestamps are stored as RFC 3339 text in UTC, which sorts chronologically.
+
+CREATE TABLE feed_groups (
+    urls_hash BLOB PRIMARY KEY,
+    last_check TEXT NOT NULL,
+    last_update TEXT,
+    last_seen TEXT NOT NULL,
+    criteria_hash BLOB
+);
+
+CREATE TABLE feed_items (
+    id INTEGER PRIMARY KEY,
+    urls_hash BLOB NOT NULL REFERENCES feed_groups(urls_hash) ON DELETE CASCADE,
+    update_hash BLOB NOT NULL,
+    last_seen TEXT NOT NULL,
+    UNIQUE(urls_hash, update_hash)
+);
+
+CREATE TABLE failures (
+    urls_hash BLOB PRIMARY KEY,
+    fail_count INTEGER NOT NULL,
+    error TEXT NOT NULL,
+    fail_time TEXT NOT NULL
+);
+
+CREATE TABLE feed_cache (
+    urls_hash BLOB NOT NULL REFERENCES feed_groups(urls_hash) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    etag TEXT,
+    last_modified TEXT,
+    content_hash BLOB NOT NULL,
+    PRIMARY KEY (urls_hash, url)
+);
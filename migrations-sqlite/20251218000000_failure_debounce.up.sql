@@ -0,0 +1,6 @@
+CREATE TABLE failure_debounce (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    failing_hash BLOB NOT NULL,
+    debouncing_hash BLOB NOT NULL,
+    debounce_count INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+-- When the item was first recorded, unlike `last_seen` which is updated on every check
+ALTER TABLE feed_items ADD COLUMN first_seen TEXT;
+UPDATE feed_items SET first_seen = last_seen;
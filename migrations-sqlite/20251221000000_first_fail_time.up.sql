@@ -0,0 +1,3 @@
+-- When the feed group started failing, unlike `fail_time` which is updated on every failure
+ALTER TABLE failures ADD COLUMN first_fail_time TEXT;
+UPDATE failures SET first_fail_time = fail_time;
@@ -0,0 +1,11 @@
+-- Statistics of each check that processed the feed content, for dashboards
+CREATE TABLE feed_stats (
+    id INTEGER PRIMARY KEY,
+    urls_hash BLOB NOT NULL REFERENCES feed_groups(urls_hash) ON DELETE CASCADE,
+    check_time TEXT NOT NULL,
+    items_seen INTEGER NOT NULL,
+    new_items INTEGER NOT NULL,
+    mails_sent INTEGER NOT NULL,
+    fetch_duration_ms INTEGER NOT NULL
+);
+CREATE INDEX feed_stats_urls_hash_check_time_idx ON feed_stats (urls_hash, check_time);
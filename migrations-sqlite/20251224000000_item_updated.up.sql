@@ -0,0 +1,2 @@
+-- The `updated` date declared by the feed, for `update-on-updated-date`
+ALTER TABLE feed_items ADD COLUMN updated TEXT;
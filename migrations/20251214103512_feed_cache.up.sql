@@ -0,0 +1,7 @@
+CREATE TABLE feed_cache (
+    urls_hash BYTEA NOT NULL REFERENCES feed_groups(urls_hash) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    etag TEXT,
+    content_hash BYTEA NOT NULL,
+    PRIMARY KEY (urls_hash, url)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE feed_cache
+    DROP COLUMN last_modified;
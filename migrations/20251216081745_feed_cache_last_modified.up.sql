@@ -0,0 +1,2 @@
+ALTER TABLE feed_cache
+    ADD COLUMN last_modified TEXT;
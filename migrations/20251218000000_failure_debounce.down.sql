@@ -0,0 +1 @@
+DROP TABLE failure_debounce;
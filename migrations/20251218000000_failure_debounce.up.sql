@@ -0,0 +1,6 @@
+CREATE TABLE failure_debounce (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    failing_hash BYTEA NOT NULL,
+    debouncing_hash BYTEA NOT NULL,
+    debounce_count INTEGER NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE feed_groups DROP COLUMN advertised_interval;
@@ -0,0 +1,2 @@
+-- How often the feeds say they are updated, in seconds, from `<ttl>` or `sy:updatePeriod`
+ALTER TABLE feed_groups ADD COLUMN advertised_interval BIGINT;
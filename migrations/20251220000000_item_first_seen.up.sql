@@ -0,0 +1,4 @@
+-- When the item was first recorded, unlike `last_seen` which is updated on every check
+ALTER TABLE feed_items ADD COLUMN first_seen TIMESTAMPTZ;
+UPDATE feed_items SET first_seen = last_seen;
+ALTER TABLE feed_items ALTER COLUMN first_seen SET NOT NULL;
@@ -0,0 +1 @@
+ALTER TABLE failures DROP COLUMN first_fail_time;
@@ -0,0 +1,4 @@
+-- When the feed group started failing, unlike `fail_time` which is updated on every failure
+ALTER TABLE failures ADD COLUMN first_fail_time TIMESTAMPTZ;
+UPDATE failures SET first_fail_time = fail_time;
+ALTER TABLE failures ALTER COLUMN first_fail_time SET NOT NULL;
@@ -0,0 +1,3 @@
+DROP INDEX feed_items_item_key_idx;
+ALTER TABLE feed_items DROP COLUMN title;
+ALTER TABLE feed_items DROP COLUMN item_key;
@@ -0,0 +1,5 @@
+-- Identifies the versions of the same item, to find the previous version when an item updates
+ALTER TABLE feed_items ADD COLUMN item_key BYTEA;
+-- A small snapshot of the item, exposed to templates as the previous version
+ALTER TABLE feed_items ADD COLUMN title TEXT;
+CREATE INDEX feed_items_item_key_idx ON feed_items (urls_hash, item_key);
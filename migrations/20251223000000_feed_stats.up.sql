@@ -0,0 +1,11 @@
+-- Statistics of each check that processed the feed content, for dashboards
+CREATE TABLE feed_stats (
+    id BIGSERIAL PRIMARY KEY,
+    urls_hash BYTEA NOT NULL REFERENCES feed_groups(urls_hash) ON DELETE CASCADE,
+    check_time TIMESTAMPTZ NOT NULL,
+    items_seen BIGINT NOT NULL,
+    new_items BIGINT NOT NULL,
+    mails_sent BIGINT NOT NULL,
+    fetch_duration_ms BIGINT NOT NULL
+);
+CREATE INDEX feed_stats_urls_hash_check_time_idx ON feed_stats (urls_hash, check_time);
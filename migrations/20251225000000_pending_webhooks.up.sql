@@ -0,0 +1,7 @@
+-- Webhooks of checks whose mails were sent, kept until they are posted
+CREATE TABLE pending_webhooks (
+    id BIGSERIAL PRIMARY KEY,
+    urls_hash BYTEA NOT NULL REFERENCES feed_groups(urls_hash) ON DELETE CASCADE,
+    body TEXT NOT NULL
+);
+CREATE INDEX pending_webhooks_urls_hash_idx ON pending_webhooks (urls_hash);
@@ -0,0 +1,465 @@
+mod postgres;
+mod sqlite;
+
+use crate::config::FeedGroup;
+use crate::feed::FeedCache;
+use crate::worker::DebounceState;
+use ammonia::clean_text;
+use blake3::Hash;
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::Result;
+use color_eyre::eyre::{Report, WrapErr, eyre};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{PgConnection, PgPool, Postgres, Sqlite, SqliteConnection, SqlitePool, Transaction};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A connection pool to either of the supported database backends.
+#[derive(Clone)]
+pub enum Pool {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+impl Pool {
+    /// Connects to the database, choosing the backend by the scheme of the URL.
+    pub async fn connect(url: &str) -> Result<Self> {
+        if url.starts_with("sqlite:") {
+            let options = SqliteConnectOptions::from_str(url)
+                .wrap_err("Invalid SQLite connection string")?
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal)
+                .foreign_keys(true)
+                // writers wait for each other instead of failing
+                .busy_timeout(Duration::from_mins(2));
+            let pool = SqlitePoolOptions::new()
+                .max_connections(20)
+                .acquire_slow_threshold(Duration::from_secs(10))
+                .acquire_timeout(Duration::from_mins(2))
+                .connect_with(options)
+                .await?;
+            Ok(Self::Sqlite(pool))
+        } else {
+            let pool = PgPoolOptions::new()
+                .max_connections(20)
+                .acquire_slow_threshold(Duration::from_secs(10))
+                .acquire_timeout(Duration::from_mins(2))
+                .connect(url)
+                .await?;
+            Ok(Self::Postgres(pool))
+        }
+    }
+
+    pub fn is_sqlite(&self) -> bool {
+        matches!(self, Self::Sqlite(_))
+    }
+
+    pub async fn begin(&self) -> Result<Tx> {
+        Ok(match self {
+            Self::Postgres(pool) => Tx::Postgres(pool.begin().await?),
+            // take the write lock upfront, the same way the row lock is taken in Postgres,
+            // instead of failing when upgrading a read transaction
+            Self::Sqlite(pool) => Tx::Sqlite(pool.begin_with("BEGIN IMMEDIATE").await?),
+        })
+    }
+}
+
+pub enum Tx {
+    Postgres(Transaction<'static, Postgres>),
+    Sqlite(Transaction<'static, Sqlite>),
+}
+
+impl Tx {
+    pub async fn commit(self) -> Result<()> {
+        match self {
+            Self::Postgres(tx) => tx.commit().await?,
+            Self::Sqlite(tx) => tx.commit().await?,
+        }
+        Ok(())
+    }
+}
+
+/// Where a query runs: the pool, or the connection of a transaction.
+pub enum Executor<'a> {
+    PgPool(&'a PgPool),
+    PgConn(&'a mut PgConnection),
+    SqlitePool(&'a SqlitePool),
+    SqliteConn(&'a mut SqliteConnection),
+}
+
+impl<'a> From<&'a Pool> for Executor<'a> {
+    fn from(pool: &'a Pool) -> Self {
+        match pool {
+            Pool::Postgres(pool) => Self::PgPool(pool),
+            Pool::Sqlite(pool) => Self::SqlitePool(pool),
+        }
+    }
+}
+
+impl<'a> From<&'a mut Tx> for Executor<'a> {
+    fn from(tx: &'a mut Tx) -> Self {
+        match tx {
+            Tx::Postgres(tx) => Self::PgConn(tx),
+            Tx::Sqlite(tx) => Self::SqliteConn(tx),
+        }
+    }
+}
+
+impl Executor<'_> {
+    /// Borrows the executor for one query, so that it can be used for more.
+    pub fn reborrow(&mut self) -> Executor<'_> {
+        match self {
+            Self::PgPool(pool) => Executor::PgPool(pool),
+            Self::PgConn(conn) => Executor::PgConn(conn),
+            Self::SqlitePool(pool) => Executor::SqlitePool(pool),
+            Self::SqliteConn(conn) => Executor::SqliteConn(conn),
+        }
+    }
+}
+
+/// Calls the function with the same name in the module of the backend.
+macro_rules! dispatch {
+    ($e:expr, $f:ident($($arg:expr),* $(,)?)) => {
+        match $e.into() {
+            Executor::PgPool(e) => postgres::$f(e, $($arg),*).await,
+            Executor::PgConn(e) => postgres::$f(e, $($arg),*).await,
+            Executor::SqlitePool(e) => sqlite::$f(&mut *e.acquire().await?, $($arg),*).await,
+            Executor::SqliteConn(e) => sqlite::$f(e, $($arg),*).await,
+        }
+    };
+}
+
+pub async fn init_db(pool: &Pool) -> Result<()> {
+    match pool {
+        Pool::Postgres(pool) => postgres::init_db(pool).await,
+        Pool::Sqlite(pool) => sqlite::init_db(pool).await,
+    }
+}
+
+pub async fn delete_old_groups<'a>(
+    e: impl Into<Executor<'a>>,
+    keep_old: TimeDelta,
+    groups_in_config: &[Vec<u8>],
+) -> Result<()> {
+    dispatch!(e, delete_old_groups(keep_old, groups_in_config))
+}
+
+/// Deletes feed groups that are not in the config, regardless of `keep-old`.
+pub async fn prune_removed_feeds<'a>(
+    e: impl Into<Executor<'a>>,
+    groups_in_config: &[Vec<u8>],
+) -> Result<()> {
+    dispatch!(e, prune_removed_feeds(groups_in_config))
+}
+
+pub async fn touch_feed_group_last_seen<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+) -> Result<()> {
+    dispatch!(e, touch_feed_group_last_seen(urls_hash))
+}
+
+/// Feed groups last checked before `update_cutoff` are due.
+pub async fn is_feed_group_waiting<'a>(
+    e: impl Into<Executor<'a>>,
+    feed_config: &FeedGroup,
+    update_cutoff: DateTime<Utc>,
+) -> Result<bool> {
+    dispatch!(e, is_feed_group_waiting(feed_config, update_cutoff))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedStatus {
+    NewFeed,
+    NewCriteria,
+    Update,
+    Wait,
+}
+
+impl FromStr for FeedStatus {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "new_feed" => Ok(FeedStatus::NewFeed),
+            "new_criteria" => Ok(FeedStatus::NewCriteria),
+            "update" => Ok(FeedStatus::Update),
+            "wait" => Ok(FeedStatus::Wait),
+            _ => Err(eyre!("Invalid feed status: {s}")),
+        }
+    }
+}
+
+/// Feed groups last checked before `update_cutoff` are due.
+pub async fn try_check_feed_group<'a>(
+    e: impl Into<Executor<'a>>,
+    feed_config: &FeedGroup,
+    update_cutoff: DateTime<Utc>,
+) -> Result<FeedStatus> {
+    dispatch!(e, try_check_feed_group(feed_config, update_cutoff))
+}
+
+/// Like `try_check_feed_group`, but read-only and ignoring the check interval.
+pub async fn peek_feed_status<'a>(
+    e: impl Into<Executor<'a>>,
+    feed_config: &FeedGroup,
+) -> Result<FeedStatus> {
+    dispatch!(e, peek_feed_status(feed_config))
+}
+
+/// Like `upsert_and_check_item_new`, but read-only.
+pub async fn check_item_new<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    update_hash: Hash,
+) -> Result<bool> {
+    dispatch!(e, check_item_new(urls_hash, update_hash))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecordedItem {
+    pub new: bool,
+    pub first_seen: DateTime<Utc>,
+    /// The title of the last recorded version of the same item, set for new versions of items.
+    pub previous_title: Option<String>,
+}
+
+/// `item_key` identifies the versions of the same item, i.e. items with the same key but different
+/// `update_hash`, and `title` and the feed-declared `updated` date are kept as the snapshot of this
+/// version.
+pub async fn upsert_and_check_item_new<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    update_hash: Hash,
+    item_key: Hash,
+    title: &str,
+    updated: Option<DateTime<Utc>>,
+) -> Result<RecordedItem> {
+    dispatch!(
+        e,
+        upsert_and_check_item_new(urls_hash, update_hash, item_key, title, updated)
+    )
+}
+
+/// A recorded version of an item.
+pub struct StoredItem {
+    pub first_seen: DateTime<Utc>,
+    /// When the item was last seen in the feed and passed the filter.
+    pub last_seen: DateTime<Utc>,
+    pub title: Option<String>,
+    pub updated: Option<DateTime<Utc>>,
+}
+
+pub async fn get_item<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    update_hash: Hash,
+) -> Result<Option<StoredItem>> {
+    dispatch!(e, get_item(urls_hash, update_hash))
+}
+
+/// The latest feed-declared `updated` date recorded for any version of the item.
+pub async fn last_item_updated<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    item_key: Hash,
+) -> Result<Option<DateTime<Utc>>> {
+    dispatch!(e, last_item_updated(urls_hash, item_key))
+}
+
+pub async fn delete_old_items<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    keep_old: TimeDelta,
+) -> Result<()> {
+    dispatch!(e, delete_old_items(urls_hash, keep_old))
+}
+
+/// Deletes the items of the feed group beyond the `keep_max` most recently seen ones, except the
+/// ones seen in the check started at `checked_at`, which would be sent again if deleted.
+pub async fn delete_excess_items<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    keep_max: u32,
+    checked_at: DateTime<Utc>,
+) -> Result<()> {
+    dispatch!(e, delete_excess_items(urls_hash, keep_max, checked_at))
+}
+
+/// Statistics of a check of a feed group that processed the feed content.
+pub struct CycleStats {
+    pub check_time: DateTime<Utc>,
+    pub items_seen: i64,
+    pub new_items: i64,
+    pub mails_sent: i64,
+    pub fetch_duration: Duration,
+}
+
+pub async fn record_cycle_stats<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    stats: &CycleStats,
+) -> Result<()> {
+    dispatch!(e, record_cycle_stats(urls_hash, stats))
+}
+
+pub async fn delete_old_stats<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    keep_old: TimeDelta,
+) -> Result<()> {
+    dispatch!(e, delete_old_stats(urls_hash, keep_old))
+}
+
+/// Keeps a webhook body to post after the transaction is committed, or on the next check.
+pub async fn add_pending_webhook<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    body: &str,
+) -> Result<()> {
+    dispatch!(e, add_pending_webhook(urls_hash, body))
+}
+
+/// Returns the IDs and bodies of the pending webhooks, oldest first.
+pub async fn get_pending_webhooks<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+) -> Result<Vec<(i64, String)>> {
+    dispatch!(e, get_pending_webhooks(urls_hash))
+}
+
+pub async fn delete_pending_webhook<'a>(e: impl Into<Executor<'a>>, id: i64) -> Result<()> {
+    dispatch!(e, delete_pending_webhook(id))
+}
+
+pub async fn get_feed_cache<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    url: &str,
+) -> Result<Option<FeedCache>> {
+    dispatch!(e, get_feed_cache(urls_hash, url))
+}
+
+pub async fn set_feed_cache<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    url: &str,
+    cache: &FeedCache,
+) -> Result<()> {
+    dispatch!(e, set_feed_cache(urls_hash, url, cache))
+}
+
+pub async fn get_last_check<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+) -> Result<Option<DateTime<Utc>>> {
+    dispatch!(e, get_last_check(urls_hash))
+}
+
+pub async fn get_advertised_interval<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+) -> Result<Option<TimeDelta>> {
+    dispatch!(e, get_advertised_interval(urls_hash))
+}
+
+pub async fn set_advertised_interval<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    interval: Option<TimeDelta>,
+) -> Result<()> {
+    dispatch!(e, set_advertised_interval(urls_hash, interval))
+}
+
+pub async fn set_feed_group_update_time<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+) -> Result<()> {
+    dispatch!(e, set_feed_group_update_time(urls_hash))
+}
+
+pub async fn clear_failure<'a>(e: impl Into<Executor<'a>>, urls_hash: Hash) -> Result<()> {
+    dispatch!(e, clear_failure(urls_hash))
+}
+
+pub async fn record_failure<'a>(
+    e: impl Into<Executor<'a>>,
+    urls_hash: Hash,
+    report: Report,
+) -> Result<()> {
+    let now = Utc::now();
+    let ansi_error = format!("Error at {now}:{report:?}");
+    let error = ansi_to_html::convert(&ansi_error).unwrap_or_else(|_| clean_text(&ansi_error));
+    dispatch!(e, record_failure(urls_hash, &error, now))
+}
+
+pub async fn delete_old_failures<'a>(
+    e: impl Into<Executor<'a>>,
+    keep_old: TimeDelta,
+) -> Result<()> {
+    dispatch!(e, delete_old_failures(keep_old))
+}
+
+/// A feed group that failed in consecutive checks.
+pub struct Failure {
+    pub urls_hash: Hash,
+    pub error: String,
+    pub fail_count: i64,
+    /// When the last failure happened.
+    pub fail_time: DateTime<Utc>,
+    /// When the feed group started failing.
+    pub first_fail_time: DateTime<Utc>,
+}
+
+pub async fn get_failing_feeds<'a>(e: impl Into<Executor<'a>>) -> Result<Vec<Failure>> {
+    dispatch!(e, get_failing_feeds())
+}
+
+pub async fn get_debounce_state<'a>(e: impl Into<Executor<'a>>) -> Result<Option<DebounceState>> {
+    dispatch!(e, get_debounce_state())
+}
+
+pub async fn set_debounce_state<'a>(
+    e: impl Into<Executor<'a>>,
+    state: &DebounceState,
+) -> Result<()> {
+    dispatch!(e, set_debounce_state(state))
+}
+
+pub fn saturating_sub_datetime(dt: DateTime<Utc>, delta: TimeDelta) -> DateTime<Utc> {
+    match dt.checked_sub_signed(delta) {
+        Some(d) if d.timestamp() > 0 => d,
+        _ => DateTime::UNIX_EPOCH,
+    }
+}
+
+fn log_deletion(name: &str, count: u64, cutoff: DateTime<Utc>) {
+    let level = if count == 0 {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    log::log!(level, "Deleted {count} {name} older than {cutoff}",);
+}
+
+fn log_pruning(count: u64) {
+    let level = if count == 0 {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    log::log!(level, "Deleted {count} feed groups removed from the config");
+}
+
+fn log_excess_deletion(count: u64, keep_max: u32) {
+    let level = if count == 0 {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    log::log!(
+        level,
+        "Deleted {count} feed items beyond the newest {keep_max}"
+    );
+}
@@ -0,0 +1,544 @@
+use super::{
+    CycleStats, Failure, FeedStatus, RecordedItem, StoredItem, log_deletion, log_excess_deletion,
+    log_pruning, saturating_sub_datetime,
+};
+use crate::config::FeedGroup;
+use crate::feed::FeedCache;
+use crate::worker::DebounceState;
+use blake3::Hash;
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::Result;
+use color_eyre::eyre::WrapErr;
+use sqlx::{PgExecutor, PgPool};
+
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::migrate!("./migrations")
+        .run(pool)
+        .await
+        .wrap_err("Failed to run database migrations")
+}
+
+pub async fn delete_old_groups(
+    e: impl PgExecutor<'_>,
+    keep_old: TimeDelta,
+    groups_in_config: &[Vec<u8>],
+) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let result = sqlx::query!(
+        "DELETE FROM feed_groups WHERE last_seen < $1 AND NOT (urls_hash = ANY($2))",
+        cutoff,
+        groups_in_config,
+    )
+    .execute(e)
+    .await?;
+    log_deletion("feed groups", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn prune_removed_feeds(
+    e: impl PgExecutor<'_>,
+    groups_in_config: &[Vec<u8>],
+) -> Result<()> {
+    let result = sqlx::query!(
+        "DELETE FROM feed_groups WHERE NOT (urls_hash = ANY($1))",
+        groups_in_config,
+    )
+    .execute(e)
+    .await?;
+    log_pruning(result.rows_affected());
+    Ok(())
+}
+
+pub async fn touch_feed_group_last_seen(e: impl PgExecutor<'_>, urls_hash: Hash) -> Result<()> {
+    sqlx::query!(
+        "UPDATE feed_groups SET last_seen = $1 WHERE urls_hash = $2",
+        Utc::now(),
+        urls_hash.as_bytes()
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn is_feed_group_waiting(
+    e: impl PgExecutor<'_>,
+    feed_config: &FeedGroup,
+    update_cutoff: DateTime<Utc>,
+) -> Result<bool> {
+    let waiting = sqlx::query_scalar!(
+        "SELECT 1 AS \"waiting!\" FROM feed_groups WHERE urls_hash = $1 AND last_check > $2 AND criteria_hash = $3",
+        feed_config.urls_hash.as_bytes(),
+        update_cutoff,
+        feed_config.criteria_hash.as_bytes(),
+    )
+    .fetch_optional(e)
+    .await?
+    .is_some();
+
+    Ok(waiting)
+}
+
+pub async fn try_check_feed_group(
+    e: impl PgExecutor<'_>,
+    feed_config: &FeedGroup,
+    update_cutoff: DateTime<Utc>,
+) -> Result<FeedStatus> {
+    let now = Utc::now();
+
+    sqlx::query_scalar!(
+        r#"
+        WITH upsert AS (
+            INSERT INTO feed_groups (urls_hash, criteria_hash, last_check, last_seen)
+            VALUES ($1, $2, $3, $3)
+            ON CONFLICT (urls_hash)
+            DO UPDATE SET last_check = $3, criteria_hash = $2
+                WHERE feed_groups.last_check < $4 OR feed_groups.criteria_hash IS DISTINCT FROM $2
+            RETURNING
+                (xmax = 0) AS new_feed,
+                (OLD.criteria_hash IS DISTINCT FROM $2) AS new_criteria
+        )
+        SELECT
+            CASE
+                WHEN EXISTS (SELECT 1 FROM upsert WHERE new_feed) THEN 'new_feed'
+                WHEN EXISTS (SELECT 1 FROM upsert WHERE new_criteria) THEN 'new_criteria'
+                WHEN EXISTS (SELECT 1 FROM upsert) THEN 'update'
+                ELSE 'wait'
+            END AS "status!"
+        "#,
+        feed_config.urls_hash.as_bytes(),
+        feed_config.criteria_hash.as_bytes(),
+        now,
+        update_cutoff,
+    )
+    .fetch_one(e)
+    .await?
+    .parse()
+}
+
+/// Like `try_check_feed_group`, but read-only and ignoring the check interval.
+pub async fn peek_feed_status(
+    e: impl PgExecutor<'_>,
+    feed_config: &FeedGroup,
+) -> Result<FeedStatus> {
+    let criteria_hash = sqlx::query_scalar!(
+        "SELECT criteria_hash FROM feed_groups WHERE urls_hash = $1",
+        feed_config.urls_hash.as_bytes(),
+    )
+    .fetch_optional(e)
+    .await?;
+
+    Ok(match criteria_hash {
+        None => FeedStatus::NewFeed,
+        Some(hash) if hash.as_deref() != Some(feed_config.criteria_hash.as_bytes()) => {
+            FeedStatus::NewCriteria
+        }
+        Some(_) => FeedStatus::Update,
+    })
+}
+
+/// Like `upsert_and_check_item_new`, but read-only.
+pub async fn check_item_new(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    update_hash: Hash,
+) -> Result<bool> {
+    let known = sqlx::query_scalar!(
+        r#"SELECT 1 AS "known!" FROM feed_items WHERE urls_hash = $1 AND update_hash = $2"#,
+        urls_hash.as_bytes(),
+        update_hash.as_bytes(),
+    )
+    .fetch_optional(e)
+    .await?
+    .is_some();
+    Ok(!known)
+}
+
+pub async fn upsert_and_check_item_new(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    update_hash: Hash,
+    item_key: Hash,
+    title: &str,
+    updated: Option<DateTime<Utc>>,
+) -> Result<RecordedItem> {
+    let row = sqlx::query!(
+        r#"
+        WITH previous AS (
+            SELECT title FROM feed_items
+            WHERE urls_hash = $1 AND item_key = $4 AND update_hash <> $2
+            ORDER BY last_seen DESC
+            LIMIT 1
+        )
+        INSERT INTO feed_items
+            (urls_hash, update_hash, last_seen, first_seen, item_key, title, updated)
+        VALUES ($1, $2, $3, $3, $4, $5, $6)
+        ON CONFLICT (urls_hash, update_hash) DO UPDATE
+            SET last_seen = EXCLUDED.last_seen, item_key = EXCLUDED.item_key,
+                title = EXCLUDED.title, updated = EXCLUDED.updated
+        RETURNING (xmax = 0) as "new!", first_seen, (SELECT title FROM previous) as previous_title
+        "#,
+        urls_hash.as_bytes(),
+        update_hash.as_bytes(),
+        Utc::now(),
+        item_key.as_bytes(),
+        title,
+        updated,
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(RecordedItem {
+        new: row.new,
+        first_seen: row.first_seen,
+        previous_title: row.previous_title.filter(|_| row.new),
+    })
+}
+
+pub async fn get_item(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    update_hash: Hash,
+) -> Result<Option<StoredItem>> {
+    let item = sqlx::query_as!(
+        StoredItem,
+        r#"
+        SELECT first_seen, last_seen, title, updated FROM feed_items
+        WHERE urls_hash = $1 AND update_hash = $2
+        "#,
+        urls_hash.as_bytes(),
+        update_hash.as_bytes(),
+    )
+    .fetch_optional(e)
+    .await?;
+    Ok(item)
+}
+
+pub async fn last_item_updated(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    item_key: Hash,
+) -> Result<Option<DateTime<Utc>>> {
+    let updated = sqlx::query_scalar!(
+        "SELECT MAX(updated) FROM feed_items WHERE urls_hash = $1 AND item_key = $2",
+        urls_hash.as_bytes(),
+        item_key.as_bytes(),
+    )
+    .fetch_one(e)
+    .await?;
+    Ok(updated)
+}
+
+pub async fn delete_old_items(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    keep_old: TimeDelta,
+) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let result = sqlx::query!(
+        "DELETE FROM feed_items WHERE urls_hash = $1 AND last_seen < $2",
+        urls_hash.as_bytes(),
+        cutoff
+    )
+    .execute(e)
+    .await?;
+    log_deletion("feed items", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn delete_excess_items(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    keep_max: u32,
+    checked_at: DateTime<Utc>,
+) -> Result<()> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM feed_items
+        WHERE urls_hash = $1 AND last_seen < $3 AND id NOT IN (
+            SELECT id FROM feed_items
+            WHERE urls_hash = $1
+            ORDER BY last_seen DESC, id DESC
+            LIMIT $2
+        )
+        "#,
+        urls_hash.as_bytes(),
+        i64::from(keep_max),
+        checked_at,
+    )
+    .execute(e)
+    .await?;
+    log_excess_deletion(result.rows_affected(), keep_max);
+    Ok(())
+}
+
+pub async fn record_cycle_stats(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    stats: &CycleStats,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO feed_stats
+            (urls_hash, check_time, items_seen, new_items, mails_sent, fetch_duration_ms)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        urls_hash.as_bytes(),
+        stats.check_time,
+        stats.items_seen,
+        stats.new_items,
+        stats.mails_sent,
+        i64::try_from(stats.fetch_duration.as_millis())?,
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_old_stats(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    keep_old: TimeDelta,
+) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let result = sqlx::query!(
+        "DELETE FROM feed_stats WHERE urls_hash = $1 AND check_time < $2",
+        urls_hash.as_bytes(),
+        cutoff
+    )
+    .execute(e)
+    .await?;
+    log_deletion("feed stats", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn add_pending_webhook(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    body: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO pending_webhooks (urls_hash, body) VALUES ($1, $2)",
+        urls_hash.as_bytes(),
+        body,
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_pending_webhooks(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+) -> Result<Vec<(i64, String)>> {
+    Ok(sqlx::query!(
+        "SELECT id, body FROM pending_webhooks WHERE urls_hash = $1 ORDER BY id",
+        urls_hash.as_bytes(),
+    )
+    .fetch_all(e)
+    .await?
+    .into_iter()
+    .map(|row| (row.id, row.body))
+    .collect())
+}
+
+pub async fn delete_pending_webhook(e: impl PgExecutor<'_>, id: i64) -> Result<()> {
+    sqlx::query!("DELETE FROM pending_webhooks WHERE id = $1", id)
+        .execute(e)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_feed_cache(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    url: &str,
+) -> Result<Option<FeedCache>> {
+    sqlx::query!(
+        "SELECT etag, last_modified, content_hash FROM feed_cache WHERE urls_hash = $1 AND url = $2",
+        urls_hash.as_bytes(),
+        url,
+    )
+    .fetch_optional(e)
+    .await?
+    .map(|row| {
+        Ok(FeedCache {
+            etag: row.etag,
+            last_modified: row.last_modified,
+            content_hash: Hash::from_slice(&row.content_hash)?,
+        })
+    })
+    .transpose()
+}
+
+pub async fn set_feed_cache(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    url: &str,
+    cache: &FeedCache,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO feed_cache (urls_hash, url, etag, last_modified, content_hash)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (urls_hash, url) DO UPDATE
+            SET etag = $3, last_modified = $4, content_hash = $5
+        "#,
+        urls_hash.as_bytes(),
+        url,
+        cache.etag,
+        cache.last_modified,
+        cache.content_hash.as_bytes(),
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_last_check(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+) -> Result<Option<DateTime<Utc>>> {
+    Ok(sqlx::query_scalar!(
+        "SELECT last_check FROM feed_groups WHERE urls_hash = $1",
+        urls_hash.as_bytes(),
+    )
+    .fetch_optional(e)
+    .await?)
+}
+
+pub async fn get_advertised_interval(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+) -> Result<Option<TimeDelta>> {
+    let seconds = sqlx::query_scalar!(
+        "SELECT advertised_interval FROM feed_groups WHERE urls_hash = $1",
+        urls_hash.as_bytes(),
+    )
+    .fetch_optional(e)
+    .await?
+    .flatten();
+    Ok(seconds.map(TimeDelta::seconds))
+}
+
+pub async fn set_advertised_interval(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    interval: Option<TimeDelta>,
+) -> Result<()> {
+    sqlx::query!(
+        "UPDATE feed_groups SET advertised_interval = $1 WHERE urls_hash = $2",
+        interval.map(|interval| interval.num_seconds()),
+        urls_hash.as_bytes(),
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_feed_group_update_time(e: impl PgExecutor<'_>, urls_hash: Hash) -> Result<()> {
+    sqlx::query!(
+        "UPDATE feed_groups SET last_update = $1 WHERE urls_hash = $2",
+        Utc::now(),
+        urls_hash.as_bytes()
+    )
+    .execute(e)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn clear_failure(e: impl PgExecutor<'_>, urls_hash: Hash) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM failures WHERE urls_hash = $1",
+        urls_hash.as_bytes()
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn record_failure(
+    e: impl PgExecutor<'_>,
+    urls_hash: Hash,
+    error: &str,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO failures (urls_hash, fail_count, error, fail_time, first_fail_time)
+        VALUES ($1, 1, $2, $3, $3)
+        ON CONFLICT (urls_hash) DO UPDATE
+            SET fail_count = failures.fail_count + 1, error = $2, fail_time = $3
+        "#,
+        urls_hash.as_bytes(),
+        error,
+        now,
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_old_failures(e: impl PgExecutor<'_>, keep_old: TimeDelta) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let result = sqlx::query!("DELETE FROM failures WHERE fail_time < $1", cutoff)
+        .execute(e)
+        .await?;
+    log_deletion("failures", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn get_failing_feeds(e: impl PgExecutor<'_>) -> Result<Vec<Failure>> {
+    sqlx::query!(
+        r#"
+        SELECT urls_hash, error, fail_count, fail_time, first_fail_time
+        FROM failures
+        WHERE fail_count >= 2
+        "#
+    )
+    .fetch_all(e)
+    .await?
+    .into_iter()
+    .map(|row| {
+        Ok(Failure {
+            urls_hash: Hash::from_slice(&row.urls_hash)?,
+            error: row.error,
+            fail_count: row.fail_count,
+            fail_time: row.fail_time,
+            first_fail_time: row.first_fail_time,
+        })
+    })
+    .collect()
+}
+
+pub async fn get_debounce_state(e: impl PgExecutor<'_>) -> Result<Option<DebounceState>> {
+    sqlx::query!(
+        "SELECT failing_hash, debouncing_hash, debounce_count FROM failure_debounce WHERE id = 1"
+    )
+    .fetch_optional(e)
+    .await?
+    .map(|row| {
+        Ok(DebounceState {
+            failing_hash: Hash::from_slice(&row.failing_hash)?,
+            debouncing_hash: Hash::from_slice(&row.debouncing_hash)?,
+            debounce_count: row.debounce_count.try_into()?,
+        })
+    })
+    .transpose()
+}
+
+pub async fn set_debounce_state(e: impl PgExecutor<'_>, state: &DebounceState) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO failure_debounce (id, failing_hash, debouncing_hash, debounce_count)
+        VALUES (1, $1, $2, $3)
+        ON CONFLICT (id) DO UPDATE
+            SET failing_hash = $1, debouncing_hash = $2, debounce_count = $3
+        "#,
+        state.failing_hash.as_bytes(),
+        state.debouncing_hash.as_bytes(),
+        i32::from(state.debounce_count),
+    )
+    .execute(e)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,954 @@
+use super::{
+    CycleStats, Failure, FeedStatus, RecordedItem, StoredItem, log_deletion, log_excess_deletion,
+    log_pruning, saturating_sub_datetime,
+};
+use crate::config::FeedGroup;
+use crate::feed::FeedCache;
+use crate::worker::DebounceState;
+use blake3::Hash;
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::Result;
+use color_eyre::eyre::WrapErr;
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+
+pub async fn init_db(pool: &SqlitePool) -> Result<()> {
+    sqlx::migrate!("./migrations-sqlite")
+        .run(pool)
+        .await
+        .wrap_err("Failed to run database migrations")
+}
+
+pub async fn delete_old_groups(
+    conn: &mut SqliteConnection,
+    keep_old: TimeDelta,
+    groups_in_config: &[Vec<u8>],
+) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM feed_groups WHERE last_seen < ");
+    query.push_bind(cutoff).push(" AND urls_hash NOT IN (");
+    let mut hashes = query.separated(", ");
+    for hash in groups_in_config {
+        hashes.push_bind(hash);
+    }
+    hashes.push_unseparated(")");
+    let result = query.build().execute(conn).await?;
+    log_deletion("feed groups", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn prune_removed_feeds(
+    conn: &mut SqliteConnection,
+    groups_in_config: &[Vec<u8>],
+) -> Result<()> {
+    let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM feed_groups WHERE urls_hash NOT IN (");
+    let mut hashes = query.separated(", ");
+    for hash in groups_in_config {
+        hashes.push_bind(hash);
+    }
+    hashes.push_unseparated(")");
+    let result = query.build().execute(conn).await?;
+    log_pruning(result.rows_affected());
+    Ok(())
+}
+
+pub async fn touch_feed_group_last_seen(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+) -> Result<()> {
+    sqlx::query("UPDATE feed_groups SET last_seen = ? WHERE urls_hash = ?")
+        .bind(Utc::now())
+        .bind(urls_hash.as_bytes().as_slice())
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn is_feed_group_waiting(
+    conn: &mut SqliteConnection,
+    feed_config: &FeedGroup,
+    update_cutoff: DateTime<Utc>,
+) -> Result<bool> {
+    let waiting = sqlx::query(
+        "SELECT 1 FROM feed_groups WHERE urls_hash = ? AND last_check > ? AND criteria_hash = ?",
+    )
+    .bind(feed_config.urls_hash.as_bytes().as_slice())
+    .bind(update_cutoff)
+    .bind(feed_config.criteria_hash.as_bytes().as_slice())
+    .fetch_optional(conn)
+    .await?
+    .is_some();
+
+    Ok(waiting)
+}
+
+/// SQLite has no `RETURNING OLD`, so the row is read before it is written. The caller holds a
+/// write transaction, so no other worker can change the row in between.
+pub async fn try_check_feed_group(
+    conn: &mut SqliteConnection,
+    feed_config: &FeedGroup,
+    update_cutoff: DateTime<Utc>,
+) -> Result<FeedStatus> {
+    let now = Utc::now();
+    let urls_hash = feed_config.urls_hash.as_bytes().as_slice();
+    let criteria_hash = feed_config.criteria_hash.as_bytes().as_slice();
+
+    let row: Option<(Option<Vec<u8>>, bool)> =
+        sqlx::query_as("SELECT criteria_hash, last_check < ? FROM feed_groups WHERE urls_hash = ?")
+            .bind(update_cutoff)
+            .bind(urls_hash)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+    let status = match row {
+        None => {
+            sqlx::query(
+                r#"
+                INSERT INTO feed_groups (urls_hash, criteria_hash, last_check, last_seen)
+                VALUES (?1, ?2, ?3, ?3)
+                "#,
+            )
+            .bind(urls_hash)
+            .bind(criteria_hash)
+            .bind(now)
+            .execute(&mut *conn)
+            .await?;
+            return Ok(FeedStatus::NewFeed);
+        }
+        Some((old_criteria_hash, _)) if old_criteria_hash.as_deref() != Some(criteria_hash) => {
+            FeedStatus::NewCriteria
+        }
+        Some((_, true)) => FeedStatus::Update,
+        Some((_, false)) => return Ok(FeedStatus::Wait),
+    };
+
+    sqlx::query("UPDATE feed_groups SET last_check = ?, criteria_hash = ? WHERE urls_hash = ?")
+        .bind(now)
+        .bind(criteria_hash)
+        .bind(urls_hash)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(status)
+}
+
+pub async fn peek_feed_status(
+    conn: &mut SqliteConnection,
+    feed_config: &FeedGroup,
+) -> Result<FeedStatus> {
+    let criteria_hash: Option<Option<Vec<u8>>> =
+        sqlx::query_scalar("SELECT criteria_hash FROM feed_groups WHERE urls_hash = ?")
+            .bind(feed_config.urls_hash.as_bytes().as_slice())
+            .fetch_optional(conn)
+            .await?;
+
+    Ok(match criteria_hash {
+        None => FeedStatus::NewFeed,
+        Some(hash) if hash.as_deref() != Some(feed_config.criteria_hash.as_bytes()) => {
+            FeedStatus::NewCriteria
+        }
+        Some(_) => FeedStatus::Update,
+    })
+}
+
+pub async fn check_item_new(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    update_hash: Hash,
+) -> Result<bool> {
+    let known = sqlx::query("SELECT 1 FROM feed_items WHERE urls_hash = ? AND update_hash = ?")
+        .bind(urls_hash.as_bytes().as_slice())
+        .bind(update_hash.as_bytes().as_slice())
+        .fetch_optional(conn)
+        .await?
+        .is_some();
+    Ok(!known)
+}
+
+pub async fn upsert_and_check_item_new(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    update_hash: Hash,
+    item_key: Hash,
+    title: &str,
+    updated: Option<DateTime<Utc>>,
+) -> Result<RecordedItem> {
+    let now = Utc::now();
+
+    let inserted = sqlx::query(
+        r#"
+        INSERT INTO feed_items
+            (urls_hash, update_hash, last_seen, first_seen, item_key, title, updated)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (urls_hash, update_hash) DO NOTHING
+        "#,
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(update_hash.as_bytes().as_slice())
+    .bind(now)
+    .bind(now)
+    .bind(item_key.as_bytes().as_slice())
+    .bind(title)
+    .bind(updated)
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    if inserted > 0 {
+        let previous_title = sqlx::query_scalar(
+            r#"
+            SELECT title FROM feed_items
+            WHERE urls_hash = ? AND item_key = ? AND update_hash <> ?
+            ORDER BY last_seen DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(urls_hash.as_bytes().as_slice())
+        .bind(item_key.as_bytes().as_slice())
+        .bind(update_hash.as_bytes().as_slice())
+        .fetch_optional(&mut *conn)
+        .await?
+        .flatten();
+        return Ok(RecordedItem {
+            new: true,
+            first_seen: now,
+            previous_title,
+        });
+    }
+
+    let first_seen = sqlx::query_scalar(
+        r#"
+        UPDATE feed_items SET last_seen = ?, item_key = ?, title = ?, updated = ?
+        WHERE urls_hash = ? AND update_hash = ?
+        RETURNING first_seen
+        "#,
+    )
+    .bind(now)
+    .bind(item_key.as_bytes().as_slice())
+    .bind(title)
+    .bind(updated)
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(update_hash.as_bytes().as_slice())
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(RecordedItem {
+        new: false,
+        first_seen,
+        previous_title: None,
+    })
+}
+
+pub async fn get_item(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    update_hash: Hash,
+) -> Result<Option<StoredItem>> {
+    type Row = (
+        DateTime<Utc>,
+        DateTime<Utc>,
+        Option<String>,
+        Option<DateTime<Utc>>,
+    );
+    let row: Option<Row> = sqlx::query_as(
+        r#"
+        SELECT first_seen, last_seen, title, updated FROM feed_items
+        WHERE urls_hash = ? AND update_hash = ?
+        "#,
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(update_hash.as_bytes().as_slice())
+    .fetch_optional(conn)
+    .await?;
+    Ok(
+        row.map(|(first_seen, last_seen, title, updated)| StoredItem {
+            first_seen,
+            last_seen,
+            title,
+            updated,
+        }),
+    )
+}
+
+pub async fn last_item_updated(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    item_key: Hash,
+) -> Result<Option<DateTime<Utc>>> {
+    let updated = sqlx::query_scalar(
+        "SELECT MAX(updated) FROM feed_items WHERE urls_hash = ? AND item_key = ?",
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(item_key.as_bytes().as_slice())
+    .fetch_one(conn)
+    .await?;
+    Ok(updated)
+}
+
+pub async fn delete_old_items(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    keep_old: TimeDelta,
+) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let result = sqlx::query("DELETE FROM feed_items WHERE urls_hash = ? AND last_seen < ?")
+        .bind(urls_hash.as_bytes().as_slice())
+        .bind(cutoff)
+        .execute(conn)
+        .await?;
+    log_deletion("feed items", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn delete_excess_items(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    keep_max: u32,
+    checked_at: DateTime<Utc>,
+) -> Result<()> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM feed_items
+        WHERE urls_hash = ?1 AND last_seen < ?3 AND id NOT IN (
+            SELECT id FROM feed_items
+            WHERE urls_hash = ?1
+            ORDER BY last_seen DESC, id DESC
+            LIMIT ?2
+        )
+        "#,
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(keep_max)
+    .bind(checked_at)
+    .execute(conn)
+    .await?;
+    log_excess_deletion(result.rows_affected(), keep_max);
+    Ok(())
+}
+
+pub async fn record_cycle_stats(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    stats: &CycleStats,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO feed_stats
+            (urls_hash, check_time, items_seen, new_items, mails_sent, fetch_duration_ms)
+        VALUES (?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(stats.check_time)
+    .bind(stats.items_seen)
+    .bind(stats.new_items)
+    .bind(stats.mails_sent)
+    .bind(i64::try_from(stats.fetch_duration.as_millis())?)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_old_stats(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    keep_old: TimeDelta,
+) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let result = sqlx::query("DELETE FROM feed_stats WHERE urls_hash = ? AND check_time < ?")
+        .bind(urls_hash.as_bytes().as_slice())
+        .bind(cutoff)
+        .execute(conn)
+        .await?;
+    log_deletion("feed stats", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn add_pending_webhook(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    body: &str,
+) -> Result<()> {
+    sqlx::query("INSERT INTO pending_webhooks (urls_hash, body) VALUES (?, ?)")
+        .bind(urls_hash.as_bytes().as_slice())
+        .bind(body)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_pending_webhooks(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+) -> Result<Vec<(i64, String)>> {
+    Ok(
+        sqlx::query_as("SELECT id, body FROM pending_webhooks WHERE urls_hash = ? ORDER BY id")
+            .bind(urls_hash.as_bytes().as_slice())
+            .fetch_all(conn)
+            .await?,
+    )
+}
+
+pub async fn delete_pending_webhook(conn: &mut SqliteConnection, id: i64) -> Result<()> {
+    sqlx::query("DELETE FROM pending_webhooks WHERE id = ?")
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_feed_cache(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    url: &str,
+) -> Result<Option<FeedCache>> {
+    let row: Option<(Option<String>, Option<String>, Vec<u8>)> = sqlx::query_as(
+        "SELECT etag, last_modified, content_hash FROM feed_cache WHERE urls_hash = ? AND url = ?",
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(url)
+    .fetch_optional(conn)
+    .await?;
+
+    row.map(|(etag, last_modified, content_hash)| {
+        Ok(FeedCache {
+            etag,
+            last_modified,
+            content_hash: Hash::from_slice(&content_hash)?,
+        })
+    })
+    .transpose()
+}
+
+pub async fn set_feed_cache(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    url: &str,
+    cache: &FeedCache,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO feed_cache (urls_hash, url, etag, last_modified, content_hash)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (urls_hash, url) DO UPDATE
+            SET etag = ?3, last_modified = ?4, content_hash = ?5
+        "#,
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(url)
+    .bind(&cache.etag)
+    .bind(&cache.last_modified)
+    .bind(cache.content_hash.as_bytes().as_slice())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_last_check(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+) -> Result<Option<DateTime<Utc>>> {
+    Ok(
+        sqlx::query_scalar("SELECT last_check FROM feed_groups WHERE urls_hash = ?")
+            .bind(urls_hash.as_bytes().as_slice())
+            .fetch_optional(conn)
+            .await?,
+    )
+}
+
+pub async fn get_advertised_interval(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+) -> Result<Option<TimeDelta>> {
+    let seconds: Option<Option<i64>> =
+        sqlx::query_scalar("SELECT advertised_interval FROM feed_groups WHERE urls_hash = ?")
+            .bind(urls_hash.as_bytes().as_slice())
+            .fetch_optional(conn)
+            .await?;
+    Ok(seconds.flatten().map(TimeDelta::seconds))
+}
+
+pub async fn set_advertised_interval(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    interval: Option<TimeDelta>,
+) -> Result<()> {
+    sqlx::query("UPDATE feed_groups SET advertised_interval = ? WHERE urls_hash = ?")
+        .bind(interval.map(|interval| interval.num_seconds()))
+        .bind(urls_hash.as_bytes().as_slice())
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn set_feed_group_update_time(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+) -> Result<()> {
+    sqlx::query("UPDATE feed_groups SET last_update = ? WHERE urls_hash = ?")
+        .bind(Utc::now())
+        .bind(urls_hash.as_bytes().as_slice())
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn clear_failure(conn: &mut SqliteConnection, urls_hash: Hash) -> Result<()> {
+    sqlx::query("DELETE FROM failures WHERE urls_hash = ?")
+        .bind(urls_hash.as_bytes().as_slice())
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn record_failure(
+    conn: &mut SqliteConnection,
+    urls_hash: Hash,
+    error: &str,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO failures (urls_hash, fail_count, error, fail_time, first_fail_time)
+        VALUES (?1, 1, ?2, ?3, ?3)
+        ON CONFLICT (urls_hash) DO UPDATE
+            SET fail_count = failures.fail_count + 1, error = ?2, fail_time = ?3
+        "#,
+    )
+    .bind(urls_hash.as_bytes().as_slice())
+    .bind(error)
+    .bind(now)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_old_failures(conn: &mut SqliteConnection, keep_old: TimeDelta) -> Result<()> {
+    let cutoff = saturating_sub_datetime(Utc::now(), keep_old);
+    let result = sqlx::query("DELETE FROM failures WHERE fail_time < ?")
+        .bind(cutoff)
+        .execute(conn)
+        .await?;
+    log_deletion("failures", result.rows_affected(), cutoff);
+    Ok(())
+}
+
+pub async fn get_failing_feeds(conn: &mut SqliteConnection) -> Result<Vec<Failure>> {
+    type Row = (Vec<u8>, String, i64, DateTime<Utc>, DateTime<Utc>);
+    let rows: Vec<Row> = sqlx::query_as(
+        r#"
+        SELECT urls_hash, error, fail_count, fail_time, first_fail_time
+        FROM failures
+        WHERE fail_count >= 2
+        "#,
+    )
+    .fetch_all(conn)
+    .await?;
+    rows.into_iter()
+        .map(
+            |(urls_hash, error, fail_count, fail_time, first_fail_time)| {
+                Ok(Failure {
+                    urls_hash: Hash::from_slice(&urls_hash)?,
+                    error,
+                    fail_count,
+                    fail_time,
+                    first_fail_time,
+                })
+            },
+        )
+        .collect()
+}
+
+pub async fn get_debounce_state(conn: &mut SqliteConnection) -> Result<Option<DebounceState>> {
+    let row: Option<(Vec<u8>, Vec<u8>, u8)> = sqlx::query_as(
+        "SELECT failing_hash, debouncing_hash, debounce_count FROM failure_debounce WHERE id = 1",
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    row.map(|(failing_hash, debouncing_hash, debounce_count)| {
+        Ok(DebounceState {
+            failing_hash: Hash::from_slice(&failing_hash)?,
+            debouncing_hash: Hash::from_slice(&debouncing_hash)?,
+            debounce_count,
+        })
+    })
+    .transpose()
+}
+
+pub async fn set_debounce_state(conn: &mut SqliteConnection, state: &DebounceState) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO failure_debounce (id, failing_hash, debouncing_hash, debounce_count)
+        VALUES (1, ?1, ?2, ?3)
+        ON CONFLICT (id) DO UPDATE
+            SET failing_hash = ?1, debouncing_hash = ?2, debounce_count = ?3
+        "#,
+    )
+    .bind(state.failing_hash.as_bytes().as_slice())
+    .bind(state.debouncing_hash.as_bytes().as_slice())
+    .bind(state.debounce_count)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use sqlx::Connection;
+
+    async fn connect() -> Result<SqliteConnection> {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await?;
+        sqlx::migrate!("./migrations-sqlite").run(&mut conn).await?;
+        Ok(conn)
+    }
+
+    /// The update cutoff for checking once per `interval`.
+    fn due(interval: TimeDelta) -> DateTime<Utc> {
+        Utc::now() - interval
+    }
+
+    fn build_feed_group(criteria: &str, interval: TimeDelta) -> FeedGroup {
+        FeedGroup {
+            urls_hash: blake3::hash(b"https://example.com/rss"),
+            criteria_hash: blake3::hash(criteria.as_bytes()),
+            urls: vec!["https://example.com/rss".to_string()],
+            filter: None,
+            item_transform: None,
+            pin: Vec::new(),
+            priority: 0,
+            settings: Settings {
+                interval,
+                ..Default::default()
+            },
+            shared_templates: Default::default(),
+            timezone: None,
+        }
+    }
+
+    /// Connects and checks a new feed group, so that items can be recorded for it.
+    async fn checked_feed_group() -> Result<(SqliteConnection, FeedGroup)> {
+        let mut conn = connect().await?;
+        let feed = build_feed_group("a", TimeDelta::hours(1));
+        try_check_feed_group(&mut conn, &feed, due(feed.settings.interval)).await?;
+        Ok((conn, feed))
+    }
+
+    #[tokio::test]
+    async fn feed_group_status_follows_checks_and_criteria() -> Result<()> {
+        let mut conn = connect().await?;
+        let feed = build_feed_group("a", TimeDelta::hours(1));
+
+        let status = try_check_feed_group(&mut conn, &feed, due(feed.settings.interval)).await?;
+        assert_eq!(status, FeedStatus::NewFeed);
+        let status = try_check_feed_group(&mut conn, &feed, due(feed.settings.interval)).await?;
+        assert_eq!(status, FeedStatus::Wait);
+        assert!(get_last_check(&mut conn, feed.urls_hash).await?.is_some());
+        assert!(is_feed_group_waiting(&mut conn, &feed, due(feed.settings.interval)).await?);
+
+        let feed = build_feed_group("b", TimeDelta::hours(1));
+        assert!(!is_feed_group_waiting(&mut conn, &feed, due(feed.settings.interval)).await?);
+        let status = try_check_feed_group(&mut conn, &feed, due(feed.settings.interval)).await?;
+        assert_eq!(status, FeedStatus::NewCriteria);
+
+        let feed = build_feed_group("b", TimeDelta::zero());
+        let status = try_check_feed_group(&mut conn, &feed, due(feed.settings.interval)).await?;
+        assert_eq!(status, FeedStatus::Update);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn items_are_new_until_recorded() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+        let update_hash = blake3::hash(b"item");
+        let item_key = blake3::hash(b"id");
+
+        assert!(check_item_new(&mut conn, feed.urls_hash, update_hash).await?);
+        let recorded =
+            upsert_and_check_item_new(&mut conn, feed.urls_hash, update_hash, item_key, "v1", None)
+                .await?;
+        assert!(recorded.new);
+        assert_eq!(recorded.previous_title, None);
+        assert!(!check_item_new(&mut conn, feed.urls_hash, update_hash).await?);
+
+        // the original first-seen time is kept for existing items
+        assert_eq!(
+            upsert_and_check_item_new(&mut conn, feed.urls_hash, update_hash, item_key, "v1", None)
+                .await?,
+            RecordedItem {
+                previous_title: None,
+                new: false,
+                ..recorded
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_versions_get_the_previous_title() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+        let item_key = blake3::hash(b"id");
+        upsert_and_check_item_new(
+            &mut conn,
+            feed.urls_hash,
+            blake3::hash(b"item"),
+            item_key,
+            "v1",
+            None,
+        )
+        .await?;
+
+        let updated = upsert_and_check_item_new(
+            &mut conn,
+            feed.urls_hash,
+            blake3::hash(b"item v2"),
+            item_key,
+            "v2",
+            None,
+        )
+        .await?;
+        assert!(updated.new);
+        assert_eq!(updated.previous_title.as_deref(), Some("v1"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn last_updated_date_is_recorded() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+        let update_hash = blake3::hash(b"item");
+        let item_key = blake3::hash(b"id");
+        upsert_and_check_item_new(&mut conn, feed.urls_hash, update_hash, item_key, "v1", None)
+            .await?;
+        assert_eq!(
+            last_item_updated(&mut conn, feed.urls_hash, item_key).await?,
+            None
+        );
+
+        let date = DateTime::from_timestamp(1_767_225_600, 0).unwrap();
+        upsert_and_check_item_new(
+            &mut conn,
+            feed.urls_hash,
+            update_hash,
+            item_key,
+            "v1",
+            Some(date),
+        )
+        .await?;
+        assert_eq!(
+            last_item_updated(&mut conn, feed.urls_hash, item_key).await?,
+            Some(date)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stored_items_are_read_by_update_hash() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+        let update_hash = blake3::hash(b"item");
+        let date = DateTime::from_timestamp(1_767_225_600, 0).unwrap();
+        let recorded = upsert_and_check_item_new(
+            &mut conn,
+            feed.urls_hash,
+            update_hash,
+            blake3::hash(b"id"),
+            "v1",
+            Some(date),
+        )
+        .await?;
+
+        let stored = get_item(&mut conn, feed.urls_hash, update_hash)
+            .await?
+            .expect("recorded item not found");
+        assert_eq!(stored.first_seen, recorded.first_seen);
+        assert_eq!(stored.title.as_deref(), Some("v1"));
+        assert_eq!(stored.updated, Some(date));
+        assert!(
+            get_item(&mut conn, feed.urls_hash, blake3::hash(b"unknown"))
+                .await?
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removed_feed_groups_are_deleted_with_their_items() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+        let update_hash = blake3::hash(b"item");
+        upsert_and_check_item_new(
+            &mut conn,
+            feed.urls_hash,
+            update_hash,
+            blake3::hash(b"id"),
+            "v1",
+            None,
+        )
+        .await?;
+
+        // the feed group is kept while it is in the config
+        prune_removed_feeds(&mut conn, &[feed.urls_hash.as_bytes().to_vec()]).await?;
+        assert_ne!(
+            peek_feed_status(&mut conn, &feed).await?,
+            FeedStatus::NewFeed
+        );
+
+        delete_old_groups(&mut conn, TimeDelta::zero(), &[]).await?;
+        assert_eq!(
+            peek_feed_status(&mut conn, &feed).await?,
+            FeedStatus::NewFeed
+        );
+        assert!(check_item_new(&mut conn, feed.urls_hash, update_hash).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn old_and_excess_items_are_deleted() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+
+        let now = Utc::now();
+        for days in 0..6 {
+            sqlx::query(
+                "INSERT INTO feed_items (urls_hash, update_hash, last_seen, first_seen) VALUES (?, ?, ?, ?)",
+            )
+            .bind(feed.urls_hash.as_bytes().as_slice())
+            .bind(days.to_string())
+            .bind(now - TimeDelta::days(days))
+            .bind(now - TimeDelta::days(days))
+            .execute(&mut conn)
+            .await?;
+        }
+        let remaining = async |conn: &mut SqliteConnection| -> Result<Vec<String>> {
+            Ok(
+                sqlx::query_scalar("SELECT update_hash FROM feed_items ORDER BY last_seen DESC")
+                    .fetch_all(conn)
+                    .await?,
+            )
+        };
+
+        delete_old_items(&mut conn, feed.urls_hash, TimeDelta::hours(84)).await?;
+        assert_eq!(remaining(&mut conn).await?, ["0", "1", "2", "3"]);
+
+        // items seen in the current check are kept even beyond the limit
+        delete_excess_items(&mut conn, feed.urls_hash, 1, now - TimeDelta::hours(36)).await?;
+        assert_eq!(remaining(&mut conn).await?, ["0", "1"]);
+
+        delete_excess_items(&mut conn, feed.urls_hash, 1, now + TimeDelta::hours(1)).await?;
+        assert_eq!(remaining(&mut conn).await?, ["0"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn old_cycle_stats_are_deleted() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+
+        let now = Utc::now();
+        for days in [0, 2] {
+            let stats = CycleStats {
+                check_time: now - TimeDelta::days(days),
+                items_seen: 10,
+                new_items: days,
+                mails_sent: 1,
+                fetch_duration: std::time::Duration::from_millis(1500),
+            };
+            record_cycle_stats(&mut conn, feed.urls_hash, &stats).await?;
+        }
+        delete_old_stats(&mut conn, feed.urls_hash, TimeDelta::days(1)).await?;
+
+        let rows: Vec<(i64, i64, i64, i64)> = sqlx::query_as(
+            "SELECT items_seen, new_items, mails_sent, fetch_duration_ms FROM feed_stats",
+        )
+        .fetch_all(&mut conn)
+        .await?;
+        assert_eq!(rows, [(10, 0, 1, 1500)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn advertised_interval_delays_checks() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+        assert_eq!(
+            get_advertised_interval(&mut conn, feed.urls_hash).await?,
+            None
+        );
+
+        set_advertised_interval(&mut conn, feed.urls_hash, Some(TimeDelta::days(1))).await?;
+        assert_eq!(
+            get_advertised_interval(&mut conn, feed.urls_hash).await?,
+            Some(TimeDelta::days(1))
+        );
+
+        // a longer interval keeps the feed group waiting
+        let status = try_check_feed_group(&mut conn, &feed, due(TimeDelta::zero())).await?;
+        assert_eq!(status, FeedStatus::Update);
+        assert!(is_feed_group_waiting(&mut conn, &feed, due(TimeDelta::days(1))).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_from_the_second_one() -> Result<()> {
+        let mut conn = connect().await?;
+        let urls_hash = blake3::hash(b"https://example.com/rss");
+        let first = DateTime::from_timestamp(1_000_000, 0).unwrap();
+        let last = DateTime::from_timestamp(2_000_000, 0).unwrap();
+
+        record_failure(&mut conn, urls_hash, "first", first).await?;
+        // a single failure is not reported
+        assert!(get_failing_feeds(&mut conn).await?.is_empty());
+
+        record_failure(&mut conn, urls_hash, "second", first).await?;
+        record_failure(&mut conn, urls_hash, "third", last).await?;
+        let failures = get_failing_feeds(&mut conn).await?;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].urls_hash, urls_hash);
+        assert_eq!(failures[0].error, "third");
+        assert_eq!(failures[0].fail_count, 3);
+        assert_eq!(failures[0].fail_time, last);
+        assert_eq!(failures[0].first_fail_time, first);
+
+        clear_failure(&mut conn, urls_hash).await?;
+        assert!(get_failing_feeds(&mut conn).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn debounce_state_is_stored() -> Result<()> {
+        let mut conn = connect().await?;
+        // the first run starts without a stored state
+        assert!(get_debounce_state(&mut conn).await?.is_none());
+
+        let state = DebounceState {
+            failing_hash: blake3::hash(b"failing"),
+            debouncing_hash: blake3::hash(b"debouncing"),
+            debounce_count: 2,
+        };
+        set_debounce_state(&mut conn, &state).await?;
+        assert!(get_debounce_state(&mut conn).await? == Some(state));
+
+        let state = DebounceState {
+            debounce_count: 3,
+            ..state
+        };
+        set_debounce_state(&mut conn, &state).await?;
+        assert!(get_debounce_state(&mut conn).await? == Some(state));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_webhooks_are_kept_in_order_until_deleted() -> Result<()> {
+        let (mut conn, feed) = checked_feed_group().await?;
+        add_pending_webhook(&mut conn, feed.urls_hash, "first").await?;
+        add_pending_webhook(&mut conn, feed.urls_hash, "second").await?;
+
+        let pending = get_pending_webhooks(&mut conn, feed.urls_hash).await?;
+        let bodies = pending
+            .iter()
+            .map(|(_, body)| body.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, ["first", "second"]);
+
+        delete_pending_webhook(&mut conn, pending[0].0).await?;
+        let pending = get_pending_webhooks(&mut conn, feed.urls_hash).await?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].1, "second");
+        Ok(())
+    }
+}
@@ -1,62 +1,822 @@
-use color_eyre::{Result, eyre::WrapErr};
-use lettre::message::{Mailbox, SinglePart};
-use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use crate::config::{Settings, SmtpOAuth2, UndisclosedTo};
+use blake3::Hash;
+use color_eyre::Result;
+use color_eyre::eyre::{WrapErr, bail};
+use lettre::address::Envelope;
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use lettre::message::{Attachment, Mailbox, MessageBuilder, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::{
+    AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use serde::Deserialize;
+use std::io::Write;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::sync::Mutex;
+use tokio::time::{Instant, sleep};
 
-const RETRY_COUNT: u32 = 3;
+const SENDMAIL_SCHEME: &str = "sendmail://";
+const FILE_SCHEME: &str = "file://";
+const STDOUT_URL: &str = "stdout://";
+/// Access tokens are refreshed this long before they expire.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_mins(5);
+/// Used if the token endpoint does not say when the access token expires.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_hours(1);
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct Mailer {
     pub from: Mailbox,
-    pub transport: AsyncSmtpTransport<Tokio1Executor>,
+    pub transport: Transport,
+    pub retry: SendRetry,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Paces mails to at most `max` per `period`, allowing bursts of up to `max` mails.
+///
+/// Mails over the budget wait for their turn instead of failing, so a single cycle can send
+/// more than `max` mails.
+pub struct RateLimit {
+    /// Delay between two mails once the burst is used up.
+    interval: Duration,
+    /// How far ahead of the paced schedule a mail may be sent.
+    burst: Duration,
+    /// When the next mail would be sent if there were no bursts.
+    next: std::sync::Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(max: u32, period: Duration) -> Self {
+        let max = max.max(1);
+        let interval = period / max;
+        Self {
+            interval,
+            burst: interval * (max - 1),
+            next: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Waits until the next mail can be sent.
+    async fn acquire(&self) {
+        let wait = self
+            .reserve(Instant::now())
+            .saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            log::debug!("Email rate limit reached, waiting {wait:?}");
+            sleep(wait).await;
+        }
+    }
+
+    /// Reserves the next slot at or after `now`, and returns when it starts.
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let paced = next.map_or(now, |next| next.max(now));
+        *next = Some(paced + self.interval);
+        paced.checked_sub(self.burst).map_or(now, |at| at.max(now))
+    }
+}
+
+/// How sending a mail is retried, with the backoff doubling after each failed attempt.
+#[derive(Clone, Copy)]
+pub struct SendRetry {
+    /// Retries after the first attempt.
+    pub retries: u32,
+    /// Backoff after the first failed attempt.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl SendRetry {
+    fn backoff(&self, failed_attempts: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << (failed_attempts - 1).min(31))
+            .min(self.max_backoff)
+    }
+}
+
+pub enum Transport {
+    /// SMTP with a connection pool, so that consecutive mails reuse the same session instead of
+    /// reconnecting for each mail.
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    /// SMTP with the XOAUTH2 mechanism.
+    SmtpOAuth2(Box<OAuth2Transport>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    /// Writes each mail as an `.eml` file into a directory.
+    File(AsyncFileTransport<Tokio1Executor>),
+    /// Prints each mail to stdout.
+    Stdout,
+}
+
+impl Transport {
+    /// - `sendmail:///path/to/sendmail` (or `sendmail://` for `sendmail` in `PATH`) selects the
+    ///   sendmail transport.
+    /// - `file:///path/to/dir` writes mails into the directory.
+    /// - `stdout://` prints mails to stdout.
+    /// - Other URLs are parsed as SMTP URLs, authenticated with `oauth2` if it is set.
+    pub fn from_url(url: &str, oauth2: Option<SmtpOAuth2>) -> Result<Self> {
+        if let Some(oauth2) = oauth2 {
+            if [STDOUT_URL, FILE_SCHEME, SENDMAIL_SCHEME]
+                .iter()
+                .any(|prefix| url.starts_with(prefix))
+            {
+                bail!("smtp-oauth2 requires an SMTP URL");
+            }
+            return Ok(Self::SmtpOAuth2(Box::new(OAuth2Transport::new(
+                url, oauth2,
+            )?)));
+        }
+        if url == STDOUT_URL {
+            return Ok(Self::Stdout);
+        }
+        if let Some(dir) = url.strip_prefix(FILE_SCHEME) {
+            return Ok(Self::File(AsyncFileTransport::new(dir)));
+        }
+        match url.strip_prefix(SENDMAIL_SCHEME) {
+            Some("") => Ok(Self::Sendmail(AsyncSendmailTransport::new())),
+            Some(command) => Ok(Self::Sendmail(AsyncSendmailTransport::new_with_command(
+                command,
+            ))),
+            None => Ok(Self::Smtp(
+                AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+            )),
+        }
+    }
+
+    async fn send(&self, message: Message) -> Result<()> {
+        match self {
+            Self::Smtp(transport) => {
+                transport.send(message).await?;
+                // the connection is put back into the pool by a spawned task, which is given a
+                // chance to run so that the next mail of a batch usually reuses it instead of
+                // opening another one
+                tokio::task::yield_now().await;
+            }
+            Self::SmtpOAuth2(transport) => {
+                transport.send(message).await?;
+                tokio::task::yield_now().await;
+            }
+            Self::Sendmail(transport) => {
+                transport.send(message).await?;
+            }
+            Self::File(transport) => {
+                transport.send(message).await?;
+            }
+            Self::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&message.formatted())?;
+                stdout.write_all(b"\n")?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An SMTP transport that is rebuilt with a new access token before the previous one expires.
+pub struct OAuth2Transport {
+    url: String,
+    user: String,
+    oauth2: SmtpOAuth2,
+    client: reqwest::Client,
+    /// The transport with the current access token, and when the token expires.
+    current: Mutex<Option<(AsyncSmtpTransport<Tokio1Executor>, Instant)>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl OAuth2Transport {
+    fn new(url: &str, oauth2: SmtpOAuth2) -> Result<Self> {
+        // check the URL upfront instead of when sending the first mail
+        AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?;
+        let parsed = Url::parse(url)?;
+        let user = percent_decode_str(parsed.username())
+            .decode_utf8()
+            .wrap_err("Invalid user name in SMTP_URL")?
+            .into_owned();
+        if user.is_empty() {
+            bail!("SMTP_URL must contain the user name for smtp-oauth2");
+        }
+        let client = reqwest::Client::builder()
+            .timeout(TOKEN_TIMEOUT)
+            .build()
+            .wrap_err("Failed to build HTTP client")?;
+        Ok(Self {
+            url: url.to_owned(),
+            user,
+            oauth2,
+            client,
+            current: Mutex::new(None),
+        })
+    }
+
+    async fn send(&self, message: Message) -> Result<()> {
+        let transport = self.transport().await?;
+        if let Err(e) = transport.send(message).await {
+            // the token may have been revoked, so a new one is requested for the retry
+            *self.current.lock().await = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+        let mut current = self.current.lock().await;
+        if let Some((transport, expires_at)) = &*current
+            && Instant::now() + TOKEN_REFRESH_MARGIN < *expires_at
+        {
+            return Ok(transport.clone());
+        }
+
+        let token = self.fetch_token().await?;
+        let lifetime = token
+            .expires_in
+            .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&self.url)?
+            .credentials(Credentials::new(self.user.clone(), token.access_token))
+            .authentication(vec![Mechanism::Xoauth2])
+            .build();
+        *current = Some((transport.clone(), Instant::now() + lifetime));
+        Ok(transport)
+    }
+
+    async fn fetch_token(&self) -> Result<TokenResponse> {
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("client_id", &self.oauth2.client_id),
+            ("refresh_token", &self.oauth2.refresh_token),
+        ];
+        if let Some(client_secret) = &self.oauth2.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        if let Some(scope) = &self.oauth2.scope {
+            form.push(("scope", scope));
+        }
+        log::debug!(
+            "Requesting SMTP access token from {}",
+            self.oauth2.token_url
+        );
+        self.client
+            .post(&self.oauth2.token_url)
+            .form(&form)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .wrap_err("Failed to request SMTP access token")?
+            .json()
+            .await
+            .wrap_err("Invalid SMTP access token response")
+    }
 }
 
 pub struct Mail {
     pub subject: String,
     pub body: String,
+    pub text_body: Option<String>,
+    pub thread: Option<Thread>,
+    /// Images referenced by `cid:` URLs in the body.
+    pub images: Vec<Arc<InlineImage>>,
+    /// File name, content and content type of each attachment.
+    pub attachments: Vec<(String, Vec<u8>, ContentType)>,
+}
+
+/// An image attached to a mail, so that mail clients show it without loading remote content.
+pub struct InlineImage {
+    pub content_id: String,
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+}
+
+/// Threading info of a mail, which is turned into `Message-ID`, `In-Reply-To` and `References`.
+pub struct Thread {
+    /// Identifies the synthetic root mail of the thread.
+    pub root: Hash,
+    /// Identifies the mail itself. A random `Message-ID` is used if it's `None`.
+    pub id: Option<Hash>,
+}
+
+/// Headers shared by a batch of mails.
+#[derive(Default)]
+pub struct MailHeaders<'a> {
+    /// Overrides the mailer's `From`.
+    pub from: Option<&'a Mailbox>,
+    pub reply_to: Option<&'a Mailbox>,
+    pub to: &'a [Mailbox],
+    pub cc: &'a [Mailbox],
+    pub bcc: &'a [Mailbox],
+    pub undisclosed_to: UndisclosedTo,
+    pub list_id: Option<&'a str>,
+    pub list_unsubscribe: &'a [String],
+}
+
+impl<'a> From<&'a Settings> for MailHeaders<'a> {
+    fn from(settings: &'a Settings) -> Self {
+        Self {
+            from: settings.from.as_ref(),
+            reply_to: settings.reply_to.as_ref(),
+            to: &settings.to,
+            cc: &settings.cc,
+            bcc: &settings.bcc,
+            undisclosed_to: settings.undisclosed_to,
+            list_id: settings.list_id.as_deref(),
+            list_unsubscribe: &settings.list_unsubscribe,
+        }
+    }
+}
+
+impl<'a> MailHeaders<'a> {
+    /// Headers of personalized mails, which are sent to a single recipient without `Cc` and `Bcc`.
+    pub fn personalized(
+        settings: &'a Settings,
+        recipient: &'a Mailbox,
+        list_unsubscribe: &'a [String],
+    ) -> Self {
+        Self {
+            to: std::slice::from_ref(recipient),
+            cc: &[],
+            bcc: &[],
+            list_unsubscribe,
+            ..Self::from(settings)
+        }
+    }
 }
 
 pub async fn send_email_with_backoff(
     sender: &Mailer,
-    to: &[Mailbox],
-    cc: &[Mailbox],
-    bcc: &[Mailbox],
+    headers: &MailHeaders<'_>,
     mails: Vec<Mail>,
 ) -> Result<()> {
-    let mut message = Message::builder().from(sender.from.clone());
+    let from = headers.from.unwrap_or(&sender.from);
+    let message = message_builder(from, headers)?;
+
+    for mail in mails {
+        let message = build_message(message.clone(), from, mail)?;
+        send_with_retry(sender.retry, || async {
+            if let Some(rate_limit) = &sender.rate_limit {
+                rate_limit.acquire().await;
+            }
+            sender.transport.send(message.clone()).await
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// The message with the headers shared by a batch of mails.
+fn message_builder(from: &Mailbox, headers: &MailHeaders<'_>) -> Result<MessageBuilder> {
+    let mut message = Message::builder().from(from.clone());
 
-    for addr in to.iter() {
+    if let Some(reply_to) = headers.reply_to {
+        message = message.reply_to(reply_to.clone());
+    }
+
+    for addr in headers.to.iter() {
         message = message.to(addr.clone());
     }
 
-    for addr in cc.iter() {
+    for addr in headers.cc.iter() {
         message = message.cc(addr.clone());
     }
 
-    for addr in bcc.iter() {
+    for addr in headers.bcc.iter() {
         message = message.bcc(addr.clone());
     }
 
-    for mail in mails {
-        let message = message
-            .clone()
-            .subject(mail.subject)
-            .singlepart(SinglePart::html(mail.body))
-            .wrap_err("Failed to build message")?;
-
-        for attempt in 1..=RETRY_COUNT {
-            match sender.transport.send(message.clone()).await {
-                Ok(_) => break,
-                Err(e) if attempt < RETRY_COUNT => {
-                    log::warn!("Failed to send email (attempt {attempt}): {e}");
-                    sleep(Duration::from_secs(1 << attempt)).await;
+    if headers.to.is_empty() && headers.cc.is_empty() && !headers.bcc.is_empty() {
+        let to = match headers.undisclosed_to {
+            UndisclosedTo::None => None,
+            UndisclosedTo::UndisclosedRecipients => Some("undisclosed-recipients:;".into()),
+            UndisclosedTo::From => Some(from.to_string()),
+        };
+        if let Some(to) = to {
+            // the placeholder is only a header, the mail is still sent to the `bcc` addresses only
+            let recipients = headers.bcc.iter().map(|addr| addr.email.clone()).collect();
+            let envelope =
+                Envelope::new(Some(from.email.clone()), recipients).wrap_err("Invalid envelope")?;
+            message = message
+                .envelope(envelope)
+                .raw_header(HeaderValue::new(HeaderName::new_from_ascii_str("To"), to));
+        }
+    }
+
+    if let Some(list_id) = headers.list_id {
+        message = message.raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str("List-Id"),
+            format!("<{list_id}>"),
+        ));
+    }
+
+    let list_unsubscribe = headers.list_unsubscribe;
+    if !list_unsubscribe.is_empty() {
+        let value = list_unsubscribe
+            .iter()
+            .map(|uri| format!("<{uri}>"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        message = message.raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str("List-Unsubscribe"),
+            value,
+        ));
+        // one-click unsubscribe (RFC 8058) requires an HTTPS URI
+        if list_unsubscribe.iter().any(|uri| uri.starts_with("https:")) {
+            message = message.raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                "List-Unsubscribe=One-Click".into(),
+            ));
+        }
+    }
+
+    Ok(message)
+}
+
+fn build_message(mut message: MessageBuilder, from: &Mailbox, mail: Mail) -> Result<Message> {
+    message = message.subject(mail.subject);
+    if let Some(thread) = mail.thread {
+        let domain = from.email.domain();
+        let root_id = format!("<{}@{domain}>", thread.root);
+        message = message
+            .message_id(
+                thread
+                    .id
+                    .map(|id| format!("<{id}.{}@{domain}>", thread.root)),
+            )
+            .in_reply_to(root_id.clone())
+            .references(root_id);
+    }
+    let body = MailBody::new(mail.body, mail.text_body, &mail.images);
+    if mail.attachments.is_empty() {
+        body.build(message)
+    } else {
+        let mixed = mail.attachments.into_iter().fold(
+            body.into_mixed(),
+            |mixed, (filename, data, content_type)| {
+                mixed.singlepart(Attachment::new(filename).body(data, content_type))
+            },
+        );
+        message.multipart(mixed)
+    }
+    .wrap_err("Failed to build message")
+}
+
+/// The body of a mail, without attachments.
+enum MailBody {
+    Html(SinglePart),
+    Multipart(MultiPart),
+}
+
+impl MailBody {
+    fn new(body: String, text_body: Option<String>, images: &[Arc<InlineImage>]) -> Self {
+        if images.is_empty() {
+            return match text_body {
+                Some(text_body) => {
+                    Self::Multipart(MultiPart::alternative_plain_html(text_body, body))
                 }
-                Err(e) => {
-                    return Err(e).wrap_err("Failed to send email after all retries");
+                None => Self::Html(SinglePart::html(body)),
+            };
+        }
+        let related = images.iter().fold(
+            MultiPart::related().singlepart(SinglePart::html(body)),
+            |related, image| {
+                related.singlepart(
+                    Attachment::new_inline(image.content_id.clone())
+                        .body(image.data.clone(), image.content_type.clone()),
+                )
+            },
+        );
+        match text_body {
+            Some(text_body) => Self::Multipart(
+                MultiPart::alternative()
+                    .singlepart(SinglePart::plain(text_body))
+                    .multipart(related),
+            ),
+            None => Self::Multipart(related),
+        }
+    }
+
+    fn build(self, message: MessageBuilder) -> Result<Message, lettre::error::Error> {
+        match self {
+            Self::Html(part) => message.singlepart(part),
+            Self::Multipart(part) => message.multipart(part),
+        }
+    }
+
+    fn into_mixed(self) -> MultiPart {
+        match self {
+            Self::Html(part) => MultiPart::mixed().singlepart(part),
+            Self::Multipart(part) => MultiPart::mixed().multipart(part),
+        }
+    }
+}
+
+async fn send_with_retry<F, Fut>(retry: SendRetry, mut send: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut attempt = 1;
+    loop {
+        match send().await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt <= retry.retries => {
+                log::warn!("Failed to send email (attempt {attempt}): {e}");
+                sleep(retry.backoff(attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e).wrap_err("Failed to send email after all retries");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::eyre;
+    use std::cell::Cell;
+
+    const RETRY: SendRetry = SendRetry {
+        retries: 2,
+        backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(2),
+    };
+
+    async fn send_failing_twice(retry: SendRetry) -> (Result<()>, u32) {
+        let attempts = Cell::new(0);
+        let result = send_with_retry(retry, || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                if attempt <= 2 {
+                    Err(eyre!("transient failure {attempt}"))
+                } else {
+                    Ok(())
                 }
             }
+        })
+        .await;
+        (result, attempts.get())
+    }
+
+    #[tokio::test]
+    async fn retries_until_sent() {
+        let (result, attempts) = send_failing_twice(RETRY).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let retry = SendRetry {
+            retries: 1,
+            ..RETRY
+        };
+        let (result, attempts) = send_failing_twice(retry).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let retry = SendRetry {
+            retries: 40,
+            backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_mins(1),
+        };
+        assert_eq!(retry.backoff(1), Duration::from_secs(2));
+        assert_eq!(retry.backoff(2), Duration::from_secs(4));
+        assert_eq!(retry.backoff(5), Duration::from_secs(32));
+        assert_eq!(retry.backoff(6), Duration::from_mins(1));
+        assert_eq!(retry.backoff(40), Duration::from_mins(1));
+    }
+
+    #[test]
+    fn rate_limit_paces_mails_after_burst() {
+        let rate_limit = RateLimit::new(3, Duration::from_mins(1));
+        let start = Instant::now();
+        let slots = (0..5)
+            .map(|_| rate_limit.reserve(start) - start)
+            .collect::<Vec<_>>();
+        assert_eq!(slots, [0, 0, 0, 20, 40].map(Duration::from_secs));
+
+        // the budget is refilled after an idle period
+        let later = start + Duration::from_mins(5);
+        assert_eq!(rate_limit.reserve(later), later);
+        assert_eq!(rate_limit.reserve(later), later);
+    }
+
+    #[test]
+    fn text_body_is_an_alternative_to_html() -> Result<()> {
+        let from = "a@example.com".parse::<Mailbox>()?;
+        let mail = Mail {
+            subject: "Subject".into(),
+            body: "<p>html body</p>".into(),
+            text_body: Some("text body".into()),
+            thread: None,
+            images: Vec::new(),
+            attachments: Vec::new(),
+        };
+        let message = build_message(
+            Message::builder().from(from.clone()).to(from.clone()),
+            &from,
+            mail,
+        )?;
+        let formatted = String::from_utf8(message.formatted())?;
+        let alternative = formatted.find("multipart/alternative").unwrap();
+        let plain = formatted.find("Content-Type: text/plain").unwrap();
+        let html = formatted.find("Content-Type: text/html").unwrap();
+        // the last part is preferred by mail clients
+        assert!(alternative < plain && plain < html);
+        assert!(formatted.contains("text body"));
+        assert!(formatted.contains("<p>html body</p>"));
+        Ok(())
+    }
+
+    #[test]
+    fn attachments_wrap_alternative_body() -> Result<()> {
+        let from = "a@example.com".parse::<Mailbox>()?;
+        let mail = Mail {
+            subject: "Subject".into(),
+            body: "<p>body</p>".into(),
+            text_body: Some("body".into()),
+            thread: None,
+            images: Vec::new(),
+            attachments: vec![(
+                "item.html".into(),
+                b"<p>source</p>".to_vec(),
+                ContentType::TEXT_HTML,
+            )],
+        };
+        let message = build_message(
+            Message::builder().from(from.clone()).to(from.clone()),
+            &from,
+            mail,
+        )?;
+        let formatted = String::from_utf8(message.formatted())?;
+        let mixed = formatted.find("multipart/mixed").unwrap();
+        let alternative = formatted.find("multipart/alternative").unwrap();
+        let attachment = formatted
+            .find("Content-Disposition: attachment; filename=\"item.html\"")
+            .unwrap();
+        assert!(mixed < alternative && alternative < attachment);
+        Ok(())
+    }
+
+    #[test]
+    fn undisclosed_to_does_not_add_recipients() -> Result<()> {
+        let from = "feeds@example.com".parse::<Mailbox>()?;
+        let bcc = ["a@example.com".parse()?, "b@example.com".parse()?];
+        let build = |undisclosed_to| {
+            let headers = MailHeaders {
+                bcc: &bcc,
+                undisclosed_to,
+                ..Default::default()
+            };
+            let mail = Mail {
+                subject: "Subject".into(),
+                body: "<p>body</p>".into(),
+                text_body: None,
+                thread: None,
+                images: Vec::new(),
+                attachments: Vec::new(),
+            };
+            build_message(message_builder(&from, &headers)?, &from, mail)
+        };
+
+        for undisclosed_to in [
+            UndisclosedTo::None,
+            UndisclosedTo::UndisclosedRecipients,
+            UndisclosedTo::From,
+        ] {
+            let message = build(undisclosed_to)?;
+            let recipients = bcc.iter().map(|addr| &addr.email).collect::<Vec<_>>();
+            assert_eq!(
+                message.envelope().to().iter().collect::<Vec<_>>(),
+                recipients
+            );
+            let formatted = String::from_utf8(message.formatted())?;
+            assert!(!formatted.contains("Bcc:"));
+            let to = formatted.lines().find_map(|line| line.strip_prefix("To: "));
+            let expected = match undisclosed_to {
+                UndisclosedTo::None => None,
+                UndisclosedTo::UndisclosedRecipients => Some("undisclosed-recipients:;"),
+                UndisclosedTo::From => Some("feeds@example.com"),
+            };
+            assert_eq!(to, expected);
         }
+        Ok(())
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn oauth2_token_is_cached_until_expiry() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let token_url = format!("http://{}/token", listener.local_addr()?);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]);
+                assert!(request.contains("grant_type=refresh_token"));
+                assert!(request.contains("refresh_token=refresh"));
+                counter.fetch_add(1, Ordering::Relaxed);
+                let body = r#"{"access_token":"token","expires_in":3600}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let oauth2 = SmtpOAuth2 {
+            token_url,
+            client_id: "client".into(),
+            client_secret: None,
+            refresh_token: "refresh".into(),
+            scope: None,
+        };
+        let transport = OAuth2Transport::new("smtp://user%40example.com@127.0.0.1:2525", oauth2)?;
+        assert_eq!(transport.user, "user@example.com");
+
+        transport.transport().await?;
+        transport.transport().await?;
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn smtp_mails_share_one_connection() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("smtp://{}", listener.local_addr()?);
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 localhost\r\n").await?;
+                    let mut in_data = false;
+                    while let Some(line) = lines.next_line().await? {
+                        let reply: &[u8] = match line.as_str() {
+                            "." if in_data => {
+                                in_data = false;
+                                b"250 queued\r\n"
+                            }
+                            _ if in_data => continue,
+                            "DATA" => {
+                                in_data = true;
+                                b"354 go ahead\r\n"
+                            }
+                            "QUIT" => b"221 bye\r\n",
+                            _ => b"250 ok\r\n",
+                        };
+                        writer.write_all(reply).await?;
+                    }
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+
+        let mailer = Mailer {
+            from: "from@example.com".parse()?,
+            transport: Transport::from_url(&url, None)?,
+            retry: RETRY,
+            rate_limit: None,
+        };
+        let to = ["to@example.com".parse::<Mailbox>()?];
+        let headers = MailHeaders {
+            to: &to,
+            ..Default::default()
+        };
+        let mails = (0..3)
+            .map(|i| Mail {
+                subject: format!("Item {i}"),
+                body: "<p>body</p>".into(),
+                text_body: None,
+                thread: None,
+                images: Vec::new(),
+                attachments: Vec::new(),
+            })
+            .collect();
+        send_email_with_backoff(&mailer, &headers, mails).await?;
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
 }
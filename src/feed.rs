@@ -1,56 +1,327 @@
-use crate::config::Settings;
+use crate::config::{Sanitize, Settings, TruncationCheck};
 use ammonia::{Url, UrlRelative, clean_text};
-use color_eyre::{Result, eyre::WrapErr};
-use feed_rs::model::{Content, Entry, Feed, Text};
+use blake3::Hash;
+use bytesize::ByteSize;
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::{Report, Result, eyre::WrapErr, eyre::bail, eyre::eyre};
+use dom_smoothie::Readability;
+use encoding_rs::{Encoding, UTF_8};
+use feed_rs::model::{Content, Entry, Feed, FeedType, Link, MediaContent, MediaObject, Text};
+use lol_html::{RewriteStrSettings, element, rewrite_str};
 use ouroboros::self_referencing;
-use reqwest_middleware::ClientBuilder;
+use regex::{Regex, bytes};
+use reqwest::StatusCode;
+use reqwest::header::{
+    CONTENT_TYPE, ETAG, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION,
+};
+use reqwest::redirect::Policy;
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
-use serde::Serialize;
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Copy, Serialize)]
+/// Attributes holding URLs that are rewritten when only URLs are sanitized.
+const URL_ATTRIBUTES: &[&str] = &["href", "src", "poster", "cite"];
+
+static INDEX_URL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"https?://[^\s"'<>]+"#).expect("invalid index URL regex"));
+
+/// The name of the root element, after the XML declaration, comments, and DOCTYPE.
+static ROOT_TAG_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\u{feff}?(?:\s+|<\?[\s\S]*?\?>|<!--[\s\S]*?-->|<!DOCTYPE[^>]*>)*<([^\s/>]+)")
+        .expect("invalid root tag regex")
+});
+
+/// The encoding in the XML declaration, which feed-rs decodes by itself.
+static XML_ENCODING_REGEX: LazyLock<bytes::Regex> = LazyLock::new(|| {
+    bytes::Regex::new(r#"^(?:\xef\xbb\xbf)?\s*<\?xml[^>]*?\sencoding\s*="#)
+        .expect("invalid XML encoding regex")
+});
+
+static UPDATE_PERIOD_REGEX: LazyLock<bytes::Regex> = LazyLock::new(|| {
+    bytes::Regex::new(r"<(?:\w+:)?updatePeriod>\s*(\w+)\s*</").expect("invalid update period regex")
+});
+
+static UPDATE_FREQUENCY_REGEX: LazyLock<bytes::Regex> = LazyLock::new(|| {
+    bytes::Regex::new(r"<(?:\w+:)?updateFrequency>\s*(\d+)\s*</")
+        .expect("invalid update frequency regex")
+});
+
+/// A feed without items is suspicious when its body is larger than this.
+const EMPTY_FEED_WARNING_SIZE: usize = 1024;
+
+/// How much of the body is logged for a suspiciously empty feed.
+const EMPTY_FEED_SNIPPET_CHARS: usize = 200;
+
+static CLOSING_TAG_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"</([^\s>]+)\s*>\s*$").expect("invalid closing tag regex"));
+
+#[derive(Debug, Clone, Copy)]
 pub struct FeedItemContext<'a> {
     pub feed: &'a Feed,
     pub item: &'a Entry,
+    pub source: &'a FeedSource,
+    /// When the item was first recorded in the database, set for new items.
+    pub item_first_seen: Option<DateTime<Utc>>,
+    /// The last recorded version of the item, set for updates of items seen before.
+    pub previous: Option<PreviousItem<'a>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PreviousItem<'a> {
+    pub title: &'a str,
+}
+
+/// A media file of an item, flattened from `media` and `enclosure` links for templates.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Enclosure<'a> {
+    url: &'a str,
+    mime_type: Option<String>,
+    length: Option<u64>,
+}
+
+/// The media of the item with a URL, followed by `enclosure` links that are not in the media.
+fn enclosures(item: &Entry) -> Vec<Enclosure<'_>> {
+    let mut enclosures = item
+        .media
+        .iter()
+        .flat_map(|media| &media.content)
+        .filter_map(|content| {
+            Some(Enclosure {
+                url: content.url.as_ref()?.as_str(),
+                mime_type: content.content_type.as_ref().map(ToString::to_string),
+                length: content.size,
+            })
+        })
+        .collect::<Vec<_>>();
+    for link in &item.links {
+        if link.rel.as_deref() == Some("enclosure")
+            && !enclosures
+                .iter()
+                .any(|enclosure| enclosure.url == link.href)
+        {
+            enclosures.push(Enclosure {
+                url: &link.href,
+                mime_type: link.media_type.clone(),
+                length: link.length,
+            });
+        }
+    }
+    enclosures
+}
+
+impl FeedItemContext<'_> {
+    /// The title of the feed that the item comes from, or its host if the feed has no title.
+    pub fn source_title(&self) -> &str {
+        self.feed
+            .title
+            .as_ref()
+            .map(|title| title.content.trim())
+            .filter(|title| !title.is_empty())
+            .or(self.source.host.as_deref())
+            .unwrap_or(&self.source.url)
+    }
+
+    /// The home page of the feed that the item comes from, or the root of its site.
+    pub fn source_link(&self) -> Option<Cow<'_, str>> {
+        let home = self.feed.links.iter().find(|link| {
+            link.rel
+                .as_deref()
+                .is_none_or(|rel| rel.eq_ignore_ascii_case("alternate"))
+        });
+        if let Some(home) = home {
+            return Some(Cow::Borrowed(&home.href));
+        }
+        let mut url = Url::parse(&self.source.final_url).ok()?;
+        url.host()?;
+        url.set_path("/");
+        url.set_query(None);
+        url.set_fragment(None);
+        Some(Cow::Owned(url.into()))
+    }
+}
+
+impl Serialize for FeedItemContext<'_> {
+    /// Serializes `item` with the additional `enclosures`, `source_title` and `source_link`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Item<'a> {
+            #[serde(flatten)]
+            entry: &'a Entry,
+            enclosures: Vec<Enclosure<'a>>,
+            source_title: &'a str,
+            source_link: Option<Cow<'a, str>>,
+        }
+        #[derive(Serialize)]
+        struct Context<'a> {
+            feed: &'a Feed,
+            item: Item<'a>,
+            source: &'a FeedSource,
+            item_first_seen: Option<DateTime<Utc>>,
+            previous: Option<PreviousItem<'a>>,
+        }
+        Context {
+            feed: self.feed,
+            item: Item {
+                entry: self.item,
+                enclosures: enclosures(self.item),
+                source_title: self.source_title(),
+                source_link: self.source_link(),
+            },
+            source: self.source,
+            item_first_seen: self.item_first_seen,
+            previous: self.previous,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Where and when a feed was fetched.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedSource {
+    pub url: String,
+    /// The host of the URL, e.g. for telling apart items from different feeds in a group.
+    pub host: Option<String>,
+    /// The HTTP status code, absent for `file:` URLs.
+    pub status: Option<u16>,
+    /// The URL after following redirects, the same as `url` if not redirected.
+    pub final_url: String,
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl FeedSource {
+    pub fn new(url: &str, status: Option<u16>) -> Self {
+        Self {
+            url: url.to_string(),
+            host: Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned)),
+            status,
+            final_url: url.to_string(),
+            fetched_at: Utc::now(),
+        }
+    }
 }
 
 #[self_referencing]
 #[derive(Debug)]
 pub struct FetchedFeed {
     pub feed: Feed,
-    #[borrows(feed)]
+    pub source: FeedSource,
+    /// How often the feed says it is updated.
+    pub advertised_interval: Option<TimeDelta>,
+    #[borrows(feed, source)]
     #[covariant]
     pub items: Vec<FeedItemContext<'this>>,
 }
 
-pub async fn fetch_feed(url: &str, settings: &Settings) -> Result<FetchedFeed> {
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-    let retry = RetryTransientMiddleware::new_with_policy(retry_policy)
-        .with_retry_log_level(tracing::Level::INFO);
-    let client = ClientBuilder::new(reqwest::Client::new())
-        .with(retry)
-        .build();
+/// Validators of the last processed response of a feed URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedCache {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_hash: Hash,
+}
+
+pub enum FetchOutcome {
+    /// The server responded with 304 Not Modified.
+    NotModified,
+    Fetched {
+        feed: FetchedFeed,
+        cache: FeedCache,
+        /// The content is identical to the cached one, even though the server ignored the
+        /// conditional request.
+        unchanged: bool,
+    },
+}
 
-    let response = client
-        .get(url)
-        .timeout(settings.timeout)
-        .headers(settings.http_headers.as_ref().clone())
-        .send()
-        .await
-        .wrap_err("Failed to fetch feed")?;
+pub async fn fetch_feed(
+    url: &str,
+    settings: &Settings,
+    cache: Option<&FeedCache>,
+) -> Result<FetchOutcome> {
+    let (content, validators, source) = if url.starts_with("file:") {
+        let path = Url::parse(url)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| eyre!("Invalid file URL"))?;
+        let content = read_file(&path, settings.max_body_size)
+            .await
+            .wrap_err_with(|| format!("Failed to read feed file at {}", path.display()))?;
+        check_truncation(&content, None, settings.truncation_check)?;
+        (content, Validators::default(), FeedSource::new(url, None))
+    } else {
+        match fetch_remote_feed(url, settings, cache).await? {
+            Some(fetched) => fetched,
+            None => return Ok(FetchOutcome::NotModified),
+        }
+    };
 
-    let content = response
-        .bytes()
-        .await
-        .wrap_err("Failed to read response body")?;
+    let new_cache = FeedCache {
+        etag: validators.etag,
+        last_modified: validators.last_modified,
+        content_hash: blake3::hash(&content),
+    };
+    let unchanged = cache.is_some_and(|cache| cache.content_hash == new_cache.content_hash);
+
+    let feed = parse_feed(&content, settings, source)?;
+    if feed.borrow_feed().entries.is_empty() {
+        check_empty_feed(url, &content, settings.strict_parse)?;
+    }
+
+    Ok(FetchOutcome::Fetched {
+        feed,
+        cache: new_cache,
+        unchanged,
+    })
+}
+
+/// Warns about a feed that parsed without any items although its body is not trivially small,
+/// since the parser silently skips malformed entries. It is an error with `strict-parse`.
+fn check_empty_feed(url: &str, content: &[u8], strict: bool) -> Result<()> {
+    if content.len() <= EMPTY_FEED_WARNING_SIZE {
+        return Ok(());
+    }
+    let snippet = String::from_utf8_lossy(content)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(EMPTY_FEED_SNIPPET_CHARS)
+        .collect::<String>();
+    if strict {
+        bail!(
+            "Feed has no items although its body is {}: {snippet}",
+            ByteSize(content.len() as u64)
+        );
+    }
+    log::warn!(
+        url,
+        size = content.len();
+        "Feed has no items, malformed entries may have been skipped: {snippet}"
+    );
+    Ok(())
+}
 
+/// Parses and sanitizes the feed content.
+pub fn parse_feed(content: &[u8], settings: &Settings, source: FeedSource) -> Result<FetchedFeed> {
     let mut feed = feed_rs::parser::Builder::new()
         .build()
-        .parse(&content[..])
+        .parse(content)
         .wrap_err("Failed to parse feed")?;
 
-    if settings.sanitize {
-        let mut sanitizer = Sanitizer::new();
+    if feed.feed_type == FeedType::JSON {
+        for entry in &mut feed.entries {
+            add_attachments_to_media(entry);
+        }
+    }
 
+    if let Some(mut sanitizer) = Sanitizer::from_settings(settings) {
+        sanitizer.document_url(&source.final_url);
         let base = feed.links.first().map_or(&feed.id, |link| &link.href);
         sanitizer.sanitize_text(&mut feed.title, base, false);
         sanitizer.sanitize_text(&mut feed.description, base, true);
@@ -65,26 +336,727 @@ pub async fn fetch_feed(url: &str, settings: &Settings) -> Result<FetchedFeed> {
         }
     }
 
-    Ok(FetchedFeedBuilder {
-        feed,
-        items_builder: |feed: &Feed| {
-            feed.entries
-                .iter()
-                .map(|item| FeedItemContext { feed, item })
-                .collect()
-        },
+    let advertised_interval = advertised_interval(content, &feed);
+
+    Ok(FetchedFeed::from_parts(feed, source, advertised_interval))
+}
+
+/// The fields of an item set by `item-transform`, `None` for unchanged ones.
+#[derive(Debug, Default)]
+pub struct ItemChanges {
+    pub title: Option<String>,
+    pub summary: Option<String>,
+    pub link: Option<String>,
+}
+
+impl FetchedFeed {
+    fn from_parts(feed: Feed, source: FeedSource, advertised_interval: Option<TimeDelta>) -> Self {
+        FetchedFeedBuilder {
+            feed,
+            source,
+            advertised_interval,
+            items_builder: |feed: &Feed, source: &FeedSource| {
+                feed.entries
+                    .iter()
+                    .map(|item| FeedItemContext {
+                        feed,
+                        item,
+                        source,
+                        item_first_seen: None,
+                        previous: None,
+                    })
+                    .collect()
+            },
+        }
+        .build()
+    }
+
+    /// Applies the changes returned by `transform` for each item. The new title and summary are
+    /// sanitized like the parsed ones.
+    pub fn transform_items(
+        self,
+        settings: &Settings,
+        transform: impl Fn(&FeedItemContext) -> Result<ItemChanges>,
+    ) -> Result<Self> {
+        let changes = self
+            .borrow_items()
+            .iter()
+            .map(transform)
+            .collect::<Result<Vec<_>>>()?;
+        let heads = self.into_heads();
+        let mut feed = heads.feed;
+        let mut sanitizer = Sanitizer::from_settings(settings);
+        if let Some(sanitizer) = &mut sanitizer {
+            sanitizer.document_url(&heads.source.final_url);
+        }
+
+        for (entry, changes) in feed.entries.iter_mut().zip(changes) {
+            if let Some(link) = changes.link {
+                match entry.links.first_mut() {
+                    Some(first) => first.href = link,
+                    None => entry.links.push(Link {
+                        href: link,
+                        rel: None,
+                        media_type: None,
+                        href_lang: None,
+                        title: None,
+                        length: None,
+                    }),
+                }
+            }
+            let base = entry
+                .links
+                .first()
+                .map_or(entry.id.clone(), |link| link.href.clone());
+            if let Some(title) = changes.title {
+                set_text(&mut entry.title, title);
+                if let Some(sanitizer) = &mut sanitizer {
+                    sanitizer.sanitize_text(&mut entry.title, &base, false);
+                }
+            }
+            if let Some(summary) = changes.summary {
+                set_text(&mut entry.summary, summary);
+                if let Some(sanitizer) = &mut sanitizer {
+                    sanitizer.sanitize_text(&mut entry.summary, &base, true);
+                }
+            }
+        }
+
+        Ok(Self::from_parts(
+            feed,
+            heads.source,
+            heads.advertised_interval,
+        ))
+    }
+}
+
+/// Replaces the content of the text, keeping its content type, or adds a plain text.
+fn set_text(text: &mut Option<Text>, content: String) {
+    match text {
+        Some(text) => text.content = content,
+        None => {
+            *text = Some(Text {
+                content_type: "text/plain".parse().expect("invalid media type"),
+                src: None,
+                content,
+            });
+        }
     }
-    .build())
+}
+
+/// The update interval from `<ttl>` of RSS, or from `updatePeriod` and `updateFrequency` of the
+/// syndication module, whichever is longer.
+fn advertised_interval(content: &[u8], feed: &Feed) -> Option<TimeDelta> {
+    let ttl = feed.ttl.map(|minutes| TimeDelta::minutes(minutes.into()));
+
+    let period = UPDATE_PERIOD_REGEX
+        .captures(content)
+        .and_then(|caps| match &caps[1] {
+            b"hourly" => Some(TimeDelta::hours(1)),
+            b"daily" => Some(TimeDelta::days(1)),
+            b"weekly" => Some(TimeDelta::weeks(1)),
+            b"monthly" => Some(TimeDelta::days(30)),
+            b"yearly" => Some(TimeDelta::days(365)),
+            _ => None,
+        });
+    let frequency = UPDATE_FREQUENCY_REGEX
+        .captures(content)
+        .and_then(|caps| std::str::from_utf8(&caps[1]).ok()?.parse::<i32>().ok())
+        .filter(|&frequency| frequency > 0)
+        .unwrap_or(1);
+    let syndication = period.map(|period| period / frequency);
+
+    ttl.max(syndication)
 }
 
 #[derive(Default)]
-struct Sanitizer(ammonia::Builder<'static>);
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Whether the query parameter `name` matches one of `params`, where a trailing `*` matches by
+/// prefix.
+pub fn param_matches(params: &[String], name: &str) -> bool {
+    params.iter().any(|param| match param.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == param,
+    })
+}
+
+/// Removes `params` from the query of the URL, keeping the URL as is if none of them is present.
+fn strip_params(url: &str, params: &[String]) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    let pairs = url
+        .query_pairs()
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    let kept = pairs
+        .iter()
+        .filter(|(name, _)| !param_matches(params, name))
+        .collect::<Vec<_>>();
+    if kept.len() == pairs.len() {
+        return None;
+    }
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    Some(url.into())
+}
+
+/// Whether the image is a tracking pixel, i.e. its `width` and `height` are at most 1px.
+fn is_tracking_pixel(el: &lol_html::html_content::Element) -> bool {
+    ["width", "height"].iter().all(|&attribute| {
+        el.get_attribute(attribute).is_some_and(|size| {
+            size.trim()
+                .trim_end_matches("px")
+                .parse::<f64>()
+                .is_ok_and(|size| size <= 1.0)
+        })
+    })
+}
+
+/// Removes tracking pixels and the tracking query parameters `params` from the URLs in the HTML.
+fn strip_tracking(html: &str, params: &[String]) -> String {
+    let mut handlers = vec![element!("img", |el| {
+        if is_tracking_pixel(el) {
+            el.remove();
+        }
+        Ok(())
+    })];
+    handlers.extend(URL_ATTRIBUTES.iter().map(|&attribute| {
+        element!(format!("[{attribute}]"), move |el| {
+            // the raw value has `&` escaped, while only quotes are escaped when setting it
+            if let Some(url) = el.get_attribute(attribute)
+                && let Some(stripped) = strip_params(&url.trim().replace("&amp;", "&"), params)
+            {
+                el.set_attribute(attribute, &stripped.replace('&', "&amp;"))?;
+            }
+            Ok(())
+        })
+    }));
+    let settings = RewriteStrSettings {
+        element_content_handlers: handlers,
+        ..RewriteStrSettings::new()
+    };
+    rewrite_str(html, settings).unwrap_or_else(|e| {
+        log::debug!("Failed to strip tracking: {e}");
+        html.to_owned()
+    })
+}
+
+/// feed-rs only maps JSON Feed attachments to links, which are distinguished from other links by
+/// their MIME type.
+fn add_attachments_to_media(entry: &mut Entry) {
+    let media = entry
+        .links
+        .iter()
+        .filter_map(|link| {
+            Some(MediaObject {
+                content: vec![MediaContent {
+                    url: Some(Url::parse(&link.href).ok()?),
+                    content_type: Some(link.media_type.as_deref()?.parse().ok()?),
+                    height: None,
+                    width: None,
+                    duration: None,
+                    size: link.length,
+                    rating: None,
+                }],
+                ..Default::default()
+            })
+        })
+        .collect::<Vec<_>>();
+    entry.media.extend(media);
+}
+
+/// Fetches the page linked from the item and returns the item with the content replaced by the
+/// article extracted from the page, for feeds that only publish summaries.
+///
+/// The `http-headers` are not sent, as the link may point to any site.
+pub async fn fetch_full_text(item: &Entry, settings: &Settings) -> Result<Entry> {
+    let url = &item
+        .links
+        .first()
+        .ok_or_else(|| eyre!("Item has no link"))?
+        .href;
+
+    let response = http_client(settings)?
+        .get(url)
+        .timeout(settings.timeout)
+        .send()
+        .await
+        .wrap_err("Failed to fetch article")?
+        .error_for_status()
+        .wrap_err("Failed to fetch article")?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    // the pages are on arbitrary sites, so they are capped like the feeds
+    let page = read_body(response, settings.max_body_size)
+        .await
+        .wrap_err("Failed to read article")?;
+    let page = decode_with_charset(page, content_type.as_deref());
+    let page = String::from_utf8_lossy(&page).into_owned();
+
+    let mut body = extract_article(page, url)?;
+    if let Some(mut sanitizer) = Sanitizer::from_settings(settings) {
+        body = sanitizer.register_base(url).clean(&body);
+    }
+
+    let mut item = item.clone();
+    item.content = Some(Content {
+        body: Some(body),
+        content_type: "text/html".parse().expect("invalid media type"),
+        ..Default::default()
+    });
+    Ok(item)
+}
+
+fn extract_article(page: String, url: &str) -> Result<String> {
+    let article = Readability::new(page, Some(url), None)
+        .and_then(|mut readability| readability.parse())
+        .wrap_err("Failed to extract article")?;
+    Ok(article.content.to_string())
+}
+
+/// The settings a client is built from. Other settings are applied per request.
+#[derive(PartialEq, Eq, Hash)]
+struct ClientProfile {
+    user_agent: Arc<str>,
+    accept_compression: bool,
+    max_redirects: usize,
+    proxy: Option<String>,
+    fetch_retries: u32,
+    retry_base_backoff: Duration,
+    retry_max_backoff: Duration,
+}
 
-impl Sanitizer {
-    fn new() -> Self {
+/// Clients are reused across fetches to keep their connection pools, one per distinct profile.
+static HTTP_CLIENTS: LazyLock<Mutex<HashMap<ClientProfile, ClientWithMiddleware>>> =
+    LazyLock::new(Default::default);
+
+fn http_client(settings: &Settings) -> Result<ClientWithMiddleware> {
+    let profile = ClientProfile {
+        user_agent: Arc::clone(&settings.user_agent),
+        accept_compression: settings.accept_compression,
+        max_redirects: settings.max_redirects,
+        proxy: settings
+            .proxy
+            .as_ref()
+            .map(|proxy| proxy.expose().to_owned()),
+        fetch_retries: settings.fetch_retries,
+        retry_base_backoff: settings.retry_base_backoff,
+        retry_max_backoff: settings.retry_max_backoff,
+    };
+    let mut clients = HTTP_CLIENTS.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(client) = clients.get(&profile) {
+        return Ok(client.clone());
+    }
+    let client = build_http_client(settings)?;
+    clients.insert(profile, client.clone());
+    Ok(client)
+}
+
+fn build_http_client(settings: &Settings) -> Result<ClientWithMiddleware> {
+    // a `User-Agent` in `http-headers` overrides this default header of the client
+    let client = reqwest::Client::builder()
+        .user_agent(&*settings.user_agent)
+        .gzip(settings.accept_compression)
+        .deflate(settings.accept_compression)
+        .brotli(settings.accept_compression)
+        .redirect(match settings.max_redirects {
+            0 => Policy::none(),
+            max => Policy::limited(max),
+        });
+    let client = match &settings.proxy {
+        // credentials in the URL are used for the proxy
+        Some(proxy) => client
+            .proxy(reqwest::Proxy::all(proxy.expose()).map_err(|_| eyre!("Invalid proxy URL"))?),
+        None => client,
+    };
+    let client = client.build().wrap_err("Failed to build HTTP client")?;
+    let mut builder = ClientBuilder::new(client);
+    // without retries, the middleware is not added at all
+    if settings.fetch_retries > 0 {
+        let retry_policy = ExponentialBackoff::builder()
+            .retry_bounds(settings.retry_base_backoff, settings.retry_max_backoff)
+            .build_with_max_retries(settings.fetch_retries);
+        let retry = RetryTransientMiddleware::new_with_policy(retry_policy)
+            .with_retry_log_level(tracing::Level::INFO);
+        builder = builder.with(retry);
+    }
+    Ok(builder.build())
+}
+
+/// Returns the body, the validators and the source, or `None` if the server responded with 304 Not
+/// Modified.
+async fn fetch_remote_feed(
+    url: &str,
+    settings: &Settings,
+    cache: Option<&FeedCache>,
+) -> Result<Option<(Vec<u8>, Validators, FeedSource)>> {
+    let mut request = http_client(settings)?
+        .get(url)
+        .timeout(settings.timeout)
+        .headers(settings.http_headers.as_ref().clone());
+
+    if let Some(auth) = &settings.basic_auth {
+        request = request.basic_auth(auth.username.expose(), Some(auth.password.expose()));
+    }
+    if let Some(token) = &settings.bearer_token {
+        request = request.bearer_auth(token.expose());
+    }
+
+    // malformed validators are skipped, resulting in a full fetch
+    if let Some(etag) = cache
+        .and_then(|cache| cache.etag.as_deref())
+        .and_then(|etag| HeaderValue::from_str(etag).ok())
+    {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = cache
+        .and_then(|cache| cache.last_modified.as_deref())
+        .filter(|last_modified| DateTime::parse_from_rfc2822(last_modified).is_ok())
+        .and_then(|last_modified| HeaderValue::from_str(last_modified).ok())
+    {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let response = request.send().await.wrap_err("Failed to fetch feed")?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    // redirects that are left are not followed because of `max-redirects`
+    if response.status().is_redirection() {
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("an unknown location");
+        bail!(
+            "Redirected to {location} with {}, which is not followed with max-redirects = {}",
+            response.status(),
+            settings.max_redirects
+        );
+    }
+
+    let mut source = FeedSource::new(url, Some(response.status().as_u16()));
+    source.final_url = response.url().to_string();
+    let content_length = response.content_length();
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+
+    let content_type = header(CONTENT_TYPE);
+    let content = read_body(response, settings.max_body_size).await?;
+
+    check_truncation(&content, content_length, settings.truncation_check)?;
+
+    let content = decode_with_charset(content, content_type.as_deref());
+
+    Ok(Some((content, validators, source)))
+}
+
+/// Transcodes the content to UTF-8 with the charset in `Content-Type`, for feeds that declare no
+/// encoding in an XML declaration and are not valid UTF-8. Servers often send a default charset
+/// that is wrong, so valid UTF-8 is kept as is.
+fn decode_with_charset(content: Vec<u8>, content_type: Option<&str>) -> Vec<u8> {
+    let Some(encoding) = content_type
+        .and_then(|content_type| {
+            content_type.split(';').skip(1).find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("charset")
+                    .then(|| value.trim().trim_matches('"'))
+            })
+        })
+        .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+    else {
+        return content;
+    };
+    if encoding == UTF_8
+        || XML_ENCODING_REGEX.is_match(&content)
+        || std::str::from_utf8(&content).is_ok()
+    {
+        return content;
+    }
+    encoding.decode(&content).0.into_owned().into_bytes()
+}
+
+/// Fetches an image to be inlined into mails, returning its content type and its content.
+///
+/// The `http-headers` are not sent, as images are often hosted by third parties.
+pub async fn fetch_image(
+    url: &str,
+    settings: &Settings,
+    max_size: ByteSize,
+) -> Result<(String, Vec<u8>)> {
+    let response = http_client(settings)?
+        .get(url)
+        .timeout(settings.timeout)
+        .send()
+        .await
+        .wrap_err("Failed to fetch image")?
+        .error_for_status()
+        .wrap_err("Failed to fetch image")?;
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|content_type| content_type.starts_with("image/"))
+        .ok_or_else(|| eyre!("Not an image"))?
+        .to_owned();
+
+    let content = read_limited(response, max_size)
+        .await?
+        .ok_or_else(|| eyre!("Image is larger than {max_size}"))?;
+    Ok((content_type, content))
+}
+
+/// POSTs the JSON `body`, retrying transient errors like feed fetches do.
+///
+/// The `http-headers` are not sent, as they are meant for the feed servers.
+pub async fn post_webhook(url: &str, settings: &Settings, body: &str) -> Result<()> {
+    http_client(settings)?
+        .post(url)
+        .timeout(settings.timeout)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_owned())
+        .send()
+        .await
+        .wrap_err("Failed to post to webhook")?
+        .error_for_status()
+        .wrap_err("Failed to post to webhook")?;
+    Ok(())
+}
+
+async fn read_body(response: reqwest::Response, max_size: ByteSize) -> Result<Vec<u8>> {
+    read_limited(response, max_size)
+        .await?
+        .ok_or_else(|| body_too_large(max_size))
+}
+
+/// Reads the response body in chunks, returning `None` as soon as it exceeds `max_size` instead
+/// of buffering a huge body.
+async fn read_limited(
+    mut response: reqwest::Response,
+    max_size: ByteSize,
+) -> Result<Option<Vec<u8>>> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_size.as_u64())
+    {
+        return Ok(None);
+    }
+
+    let mut content = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .wrap_err("Failed to read response body")?
+    {
+        if (content.len() + chunk.len()) as u64 > max_size.as_u64() {
+            return Ok(None);
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(Some(content))
+}
+
+async fn read_file(path: &Path, max_size: ByteSize) -> Result<Vec<u8>> {
+    if tokio::fs::metadata(path).await?.len() > max_size.as_u64() {
+        return Err(body_too_large(max_size));
+    }
+    Ok(tokio::fs::read(path).await?)
+}
+
+fn body_too_large(max_size: ByteSize) -> Report {
+    eyre!("Feed is larger than max-body-size ({max_size})")
+}
+
+/// Fails if the response body seems truncated, so that the feed is retried later instead of
+/// being parsed as a partial feed with missing items.
+fn check_truncation(
+    content: &[u8],
+    content_length: Option<u64>,
+    check: TruncationCheck,
+) -> Result<()> {
+    if check == TruncationCheck::Off {
+        return Ok(());
+    }
+
+    if let Some(expected) = content_length
+        && expected != content.len() as u64
+    {
+        bail!(
+            "Feed response seems truncated: expected {expected} bytes, got {}",
+            content.len()
+        );
+    }
+
+    if check == TruncationCheck::Strict {
+        let content = String::from_utf8_lossy(content);
+        let content = content.trim_end();
+        if content.trim_start().starts_with('{') {
+            // JSON Feed
+            if !content.ends_with('}') {
+                bail!("Feed response seems truncated: JSON does not end with '}}'");
+            }
+        } else if let Some(root) = ROOT_TAG_REGEX.captures(content) {
+            let root = &root[1];
+            let closing = CLOSING_TAG_REGEX.captures(content);
+            if closing.is_none_or(|closing| &closing[1] != root) {
+                bail!("Feed response seems truncated: missing closing tag of <{root}>");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Extracts all URLs matching `pattern` from an index page, e.g. a sitemap or an OPML file.
+pub async fn fetch_index_urls(
+    index_url: &str,
+    pattern: &Regex,
+    settings: &Settings,
+) -> Result<Vec<String>> {
+    let response = http_client(settings)?
+        .get(index_url)
+        .timeout(settings.timeout)
+        .headers(settings.http_headers.as_ref().clone())
+        .send()
+        .await
+        .wrap_err("Failed to fetch index")?
+        .error_for_status()
+        .wrap_err("Failed to fetch index")?;
+    let content = read_body(response, settings.max_body_size)
+        .await
+        .wrap_err("Failed to read index")?;
+    let content = String::from_utf8_lossy(&content);
+
+    let mut urls = INDEX_URL_REGEX
+        .find_iter(&content)
+        .map(|m| m.as_str().replace("&amp;", "&"))
+        .filter(|url| pattern.is_match(url))
+        .collect::<Vec<_>>();
+    // keep the URL order stable so that the feed group is not considered new
+    urls.sort_unstable();
+    urls.dedup();
+    Ok(urls)
+}
+
+#[derive(Default)]
+pub struct Sanitizer<'a> {
+    builder: ammonia::Builder<'a>,
+    /// Only rewrite relative URLs, keeping the HTML and plain text as they are.
+    urls_only: bool,
+    base: Option<Url>,
+    document_url: Option<Url>,
+    /// Set to remove tracking pixels and these query parameters from links.
+    tracking_params: Option<&'a [String]>,
+}
+
+impl<'a> Sanitizer<'a> {
+    /// The allowed tags and attributes are added to the defaults of ammonia.
+    pub fn new(
+        url_schemes: &'a [String],
+        allowed_tags: &'a [String],
+        allowed_attributes: &'a BTreeMap<String, Vec<String>>,
+    ) -> Self {
         let mut sanitizer = ammonia::Builder::new();
-        sanitizer.add_generic_attributes(["style"]);
-        Self(sanitizer)
+        sanitizer
+            .add_generic_attributes(["style"])
+            .add_tags(allowed_tags.iter().map(String::as_str))
+            .url_schemes(url_schemes.iter().map(String::as_str).collect());
+        for (tag, attributes) in allowed_attributes {
+            let attributes = attributes.iter().map(String::as_str);
+            if tag == "*" {
+                sanitizer.add_generic_attributes(attributes);
+            } else {
+                sanitizer.add_tag_attributes(tag, attributes);
+            }
+        }
+        Self {
+            builder: sanitizer,
+            urls_only: false,
+            base: None,
+            document_url: None,
+            tracking_params: None,
+        }
+    }
+
+    /// Returns `None` if the HTML is not sanitized.
+    fn from_settings(settings: &'a Settings) -> Option<Self> {
+        if settings.sanitize == Sanitize::None {
+            return None;
+        }
+        let mut sanitizer = Self::new(
+            &settings.url_schemes,
+            &settings.sanitize_allowed_tags,
+            &settings.sanitize_allowed_attributes,
+        );
+        sanitizer.urls_only = settings.sanitize == Sanitize::UrlsOnly;
+        if settings.strip_images {
+            sanitizer.builder.rm_tags(["img", "picture"]);
+        }
+        sanitizer.tracking_params = settings
+            .strip_tracking
+            .then_some(&*settings.tracking_params);
+        Some(sanitizer)
+    }
+
+    pub fn clean(&self, html: &str) -> String {
+        let cleaned = if self.urls_only {
+            self.rewrite_relative_urls(html)
+        } else {
+            self.builder.clean(html).to_string()
+        };
+        match self.tracking_params {
+            Some(params) => strip_tracking(&cleaned, params),
+            None => cleaned,
+        }
+    }
+
+    fn rewrite_relative_urls(&self, html: &str) -> String {
+        let Some(base) = &self.base else {
+            return html.to_owned();
+        };
+        let handlers = URL_ATTRIBUTES
+            .iter()
+            .map(|&attribute| {
+                element!(format!("[{attribute}]"), move |el| {
+                    if let Some(url) = el.get_attribute(attribute)
+                        && let Ok(absolute) = base.join(url.trim())
+                    {
+                        el.set_attribute(attribute, absolute.as_str())?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        let settings = RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::new()
+        };
+        rewrite_str(html, settings).unwrap_or_else(|e| {
+            log::debug!("Failed to rewrite relative URLs: {e}");
+            html.to_owned()
+        })
     }
 
     fn sanitize_text(&mut self, text: &mut Option<Text>, base: &str, sanitize_plain_text: bool) {
@@ -95,8 +1067,8 @@ impl Sanitizer {
                 } else {
                     self.register_base(base);
                 }
-                text.content = self.0.clean(&text.content).to_string();
-            } else if sanitize_plain_text {
+                text.content = self.clean(&text.content);
+            } else if sanitize_plain_text && !self.urls_only {
                 text.content = clean_text(&text.content);
             }
         }
@@ -112,20 +1084,667 @@ impl Sanitizer {
                 } else {
                     self.register_base(base);
                 }
-                *body = self.0.clean(body).to_string();
-            } else {
+                *body = self.clean(body);
+            } else if !self.urls_only {
                 *body = clean_text(body);
             }
         }
     }
 
+    /// Relative bases are resolved against the URL of the feed document.
+    fn document_url(&mut self, url: &str) -> &mut Self {
+        self.document_url = Url::parse(url).ok();
+        self
+    }
+
     fn register_base(&mut self, url: &str) -> &mut Self {
-        let policy = if let Ok(url) = Url::parse(url) {
-            UrlRelative::RewriteWithBase(url)
+        self.base = Url::parse(url)
+            .ok()
+            .or_else(|| self.document_url.as_ref()?.join(url).ok());
+        let policy = if let Some(url) = &self.base {
+            UrlRelative::RewriteWithBase(url.clone())
         } else {
             UrlRelative::PassThrough
         };
-        self.0.url_relative(policy);
+        self.builder.url_relative(policy);
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_truncated_responses() {
+        let full =
+            "<?xml version=\"1.0\"?>\n<!-- c --><rss version=\"2.0\"><channel></channel></rss>\n";
+        let truncated = &full[..full.len() - 8];
+
+        for check in [TruncationCheck::Lenient, TruncationCheck::Strict] {
+            assert!(check_truncation(full.as_bytes(), Some(full.len() as u64), check).is_ok());
+            assert!(
+                check_truncation(truncated.as_bytes(), Some(full.len() as u64), check).is_err()
+            );
+        }
+
+        assert!(check_truncation(truncated.as_bytes(), None, TruncationCheck::Lenient).is_ok());
+        assert!(check_truncation(truncated.as_bytes(), None, TruncationCheck::Strict).is_err());
+        assert!(check_truncation(b"{\"items\": [", None, TruncationCheck::Strict).is_err());
+        assert!(check_truncation(b"{\"items\": []}", None, TruncationCheck::Strict).is_ok());
+        assert!(check_truncation(truncated.as_bytes(), Some(1), TruncationCheck::Off).is_ok());
+    }
+
+    #[test]
+    fn parses_json_feed_with_attachments() -> Result<()> {
+        let content = r#"{
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": "JSON Feed",
+            "items": [{
+                "id": "1",
+                "title": "Episode 1",
+                "url": "https://example.com/1",
+                "content_html": "<p>Hello</p>",
+                "date_published": "2025-01-02T03:04:05Z",
+                "attachments": [{
+                    "url": "https://example.com/1.mp3",
+                    "mime_type": "audio/mpeg",
+                    "size_in_bytes": 1234
+                }]
+            }]
+        }"#;
+
+        let source = FeedSource::new("https://example.com/feed.json", Some(200));
+        let feed = parse_feed(content.as_bytes(), &Settings::default(), source)?;
+        let item = feed.borrow_items()[0].item;
+
+        assert_eq!(item.id, "1");
+        assert_eq!(
+            feed.borrow_items()[0].source.host.as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(item.title.as_ref().unwrap().content, "Episode 1");
+        assert_eq!(item.links[0].href, "https://example.com/1");
+        assert_eq!(
+            item.content.as_ref().unwrap().body.as_deref(),
+            Some("<p>Hello</p>")
+        );
+        assert!(item.published.is_some());
+        assert_eq!(item.media.len(), 1);
+        let media = &item.media[0].content[0];
+        assert_eq!(
+            media.url.as_ref().map(Url::as_str),
+            Some("https://example.com/1.mp3")
+        );
+        assert_eq!(media.size, Some(1234));
+        Ok(())
+    }
+
+    #[test]
+    fn enclosures_are_flattened_for_templates() -> Result<()> {
+        let content = r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>T</title>
+            <entry><id>1</id><title>Episode 1</title>
+            <link rel="alternate" href="https://example.com/1"/>
+            <link rel="enclosure" type="audio/mpeg" length="1234" href="https://example.com/1.mp3"/>
+            </entry></feed>"#;
+        let source = FeedSource::new("https://example.com/feed.xml", Some(200));
+        let feed = parse_feed(content.as_bytes(), &Settings::default(), source)?;
+        let ctx = &feed.borrow_items()[0];
+        let rendered = minijinja::Environment::new().render_str(
+            "{% for e in item.enclosures %}{{ e.url }} {{ e.mime_type }} {{ e.length }}{% endfor %} {{ item.title.content }}",
+            ctx,
+        )?;
+        assert_eq!(
+            rendered,
+            "https://example.com/1.mp3 audio/mpeg 1234 Episode 1"
+        );
+
+        let rss = r#"<rss version="2.0"><channel><title>T</title><item><guid>1</guid>
+            <enclosure url="https://example.com/1.mp3" type="audio/mpeg" length="1234"/>
+            </item></channel></rss>"#;
+        let source = FeedSource::new("https://example.com/rss.xml", Some(200));
+        let feed = parse_feed(rss.as_bytes(), &Settings::default(), source)?;
+        let item = feed.borrow_items()[0].item;
+        assert_eq!(
+            enclosures(item),
+            [Enclosure {
+                url: "https://example.com/1.mp3",
+                mime_type: Some("audio/mpeg".into()),
+                length: Some(1234),
+            }]
+        );
+        // the raw media are still there
+        assert_eq!(item.media.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn items_are_attributed_to_their_source_feed() -> Result<()> {
+        let template = "{{ item.source_title }} {{ item.source_link }}";
+        let titled = r#"<rss version="2.0"><channel><title> Blog </title>
+            <link>https://example.com/blog/</link><item><guid>1</guid></item></channel></rss>"#;
+        let untitled = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <link rel="self" href="https://example.org/atom.xml"/><entry><id>1</id></entry></feed>"#;
+        let rendered = [
+            (titled, "https://example.com/feed.xml"),
+            (untitled, "https://example.org/atom.xml?page=1"),
+        ]
+        .into_iter()
+        .map(|(content, url)| {
+            let feed = parse_feed(
+                content.as_bytes(),
+                &Settings::default(),
+                FeedSource::new(url, Some(200)),
+            )?;
+            Ok(minijinja::Environment::new().render_str(template, feed.borrow_items()[0])?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            rendered,
+            [
+                "Blog https://example.com/blog/",
+                "example.org https://example.org/"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sanitizer_extends_default_allowlist() {
+        let url_schemes = ["https".to_string()];
+        let html = r#"<p style="color: red" dir="rtl">Hi</p><video controls onplay="x()"><source src="https://example.com/v.mp4"></video><script>x()</script>"#;
+
+        let default = Sanitizer::new(&url_schemes, &[], &BTreeMap::new()).clean(html);
+        assert_eq!(default, r#"<p style="color: red">Hi</p>"#);
+
+        let tags = ["video".to_string(), "source".to_string()];
+        let attributes = BTreeMap::from([
+            ("*".to_string(), vec!["dir".to_string()]),
+            ("video".to_string(), vec!["controls".to_string()]),
+            ("source".to_string(), vec!["src".to_string()]),
+        ]);
+        let extended = Sanitizer::new(&url_schemes, &tags, &attributes).clean(html);
+        assert_eq!(
+            extended,
+            r#"<p style="color: red" dir="rtl">Hi</p><video controls=""><source src="https://example.com/v.mp4"></video>"#
+        );
+    }
+
+    #[test]
+    fn strip_images_removes_images_only() {
+        let settings = Settings {
+            strip_images: true,
+            sanitize_allowed_tags: vec!["picture".to_string(), "source".to_string()].into(),
+            sanitize_allowed_attributes: BTreeMap::from([(
+                "img".to_string(),
+                vec!["loading".to_string()],
+            )])
+            .into(),
+            ..Default::default()
+        };
+        let sanitizer = Sanitizer::from_settings(&settings).unwrap();
+        let html = r#"<p style="color: red">Hi <img src="https://example.com/a.png" style="width: 1px" loading="lazy"></p><picture><img src="https://example.com/b.png"></picture>"#;
+        assert_eq!(sanitizer.clean(html), r#"<p style="color: red">Hi </p>"#);
+        assert_eq!(sanitizer.clean("<p>No images</p>"), "<p>No images</p>");
+    }
+
+    #[test]
+    fn strip_tracking_removes_pixels_and_tracking_params() {
+        let settings = Settings {
+            strip_tracking: true,
+            tracking_params: vec!["utm_*".to_string(), "fbclid".to_string()].into(),
+            ..Default::default()
+        };
+        let sanitizer = Sanitizer::from_settings(&settings).unwrap();
+        let html = r#"<p style="color: red"><a href="https://example.com/a?id=1&amp;utm_source=rss&amp;fbclid=x">A</a> <a href="https://example.com/search?q=a+b&amp;page=2">B</a></p><img src="https://t.example.com/p.gif?utm_medium=x" width="1" height="1px"><img src="https://example.com/c.png" width="1" height="100">"#;
+        assert_eq!(
+            sanitizer.clean(html),
+            r#"<p style="color: red"><a href="https://example.com/a?id=1" rel="noopener noreferrer">A</a> <a href="https://example.com/search?q=a+b&amp;page=2" rel="noopener noreferrer">B</a></p><img src="https://example.com/c.png" width="1" height="100">"#
+        );
+
+        let settings = Settings {
+            tracking_params: settings.tracking_params.clone(),
+            ..Default::default()
+        };
+        let sanitizer = Sanitizer::from_settings(&settings).unwrap();
+        assert!(sanitizer.clean(html).contains("utm_source=rss"));
+    }
+
+    #[test]
+    fn urls_only_keeps_html_and_rewrites_relative_urls() -> Result<()> {
+        let content = r#"<?xml version="1.0"?>
+            <rss version="2.0"><channel><title>T</title><link>https://example.com/blog/</link>
+            <item><guid>1</guid><link>https://example.com/blog/1</link>
+            <description><![CDATA[<iframe src="/embed"></iframe><img src="a.png" onload="x()"> <a href="https://other.example/">x</a>]]></description>
+            </item></channel></rss>"#;
+
+        let settings = Settings {
+            sanitize: Sanitize::UrlsOnly,
+            ..Default::default()
+        };
+        let source = FeedSource::new("https://example.com/feed.xml", None);
+        let feed = parse_feed(content.as_bytes(), &settings, source)?;
+        let summary = &feed.borrow_items()[0]
+            .item
+            .summary
+            .as_ref()
+            .unwrap()
+            .content;
+
+        assert_eq!(
+            summary,
+            r#"<iframe src="https://example.com/embed"></iframe><img src="https://example.com/blog/a.png" onload="x()"> <a href="https://other.example/">x</a>"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn extracts_article_from_page() -> Result<()> {
+        let paragraph = "<p>This is the full text of the article, which is long enough to be \
+            considered the main content of the page by the readability algorithm.</p>";
+        let page = format!(
+            "<html><head><title>Article</title></head><body>\
+            <nav><a href=\"/\">Home</a><a href=\"/about\">About</a></nav>\
+            <article><h1>Article</h1>{}</article>\
+            <footer>Copyright</footer></body></html>",
+            paragraph.repeat(5)
+        );
+
+        let article = extract_article(page, "https://example.com/article")?;
+        assert!(article.contains("full text of the article"));
+        assert!(!article.contains("Copyright"));
+        assert!(!article.contains("About"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_feeds_larger_than_max_body_size() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("yaf2m-large-{}.xml", std::process::id()));
+        let content = format!(
+            "<rss version=\"2.0\"><channel><title>{}</title></channel></rss>",
+            "x".repeat(2048)
+        );
+        tokio::fs::write(&path, &content).await?;
+        let url = Url::from_file_path(&path).unwrap().to_string();
+
+        let small = Settings {
+            max_body_size: ByteSize::kib(1),
+            ..Default::default()
+        };
+        let err = fetch_feed(&url, &small, None).await.err().unwrap();
+        assert!(format!("{err:#}").contains("max-body-size"));
+        assert!(fetch_feed(&url, &Settings::default(), None).await.is_ok());
+
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetches_through_proxy_with_credentials() -> Result<()> {
+        use serde::Deserialize;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = format!("http://user:pass@{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = r#"<rss version="2.0"><channel><title>Proxied</title></channel></rss>"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await?;
+            std::io::Result::Ok(String::from_utf8_lossy(&request).into_owned())
+        });
+
+        let settings = Settings {
+            proxy: Some(crate::config::Secret::deserialize(
+                serde_json::Value::from(proxy),
+            )?),
+            fetch_retries: 0,
+            ..Default::default()
+        };
+        let outcome = fetch_feed("http://feed.invalid/feed.xml", &settings, None).await?;
+        let FetchOutcome::Fetched { feed, .. } = outcome else {
+            panic!("expected a fetched feed");
+        };
+        assert_eq!(
+            feed.borrow_feed()
+                .title
+                .as_ref()
+                .map(|t| t.content.as_str()),
+            Some("Proxied")
+        );
+
+        let request = server.await??.to_lowercase();
+        assert!(request.starts_with("get http://feed.invalid/feed.xml http/1.1\r\n"));
+        // base64 of "user:pass"
+        assert!(request.contains("proxy-authorization: basic dxnlcjpwyxnz\r\n"));
+        Ok(())
+    }
+
+    /// Serves HTTP on a local port with `respond` mapping request paths to responses, returning the
+    /// address and the number of accepted connections.
+    async fn serve(
+        respond: fn(&str) -> Vec<u8>,
+    ) -> Result<(std::net::SocketAddr, Arc<std::sync::atomic::AtomicUsize>)> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    // one request per read, which is enough for these small requests
+                    while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                        let request = String::from_utf8_lossy(&buf[..n]);
+                        let path = request.split(' ').nth(1).unwrap_or_default();
+                        if stream.write_all(&respond(path)).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Ok((addr, connections))
+    }
+
+    fn ok_response(body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn reuses_connections_across_fetches() -> Result<()> {
+        let (addr, connections) = serve(|_| {
+            ok_response(r#"<rss version="2.0"><channel><title>T</title></channel></rss>"#)
+        })
+        .await?;
+
+        let settings = Settings {
+            user_agent: "yaf2m-keep-alive-test".into(),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            fetch_feed(&format!("http://{addr}/feed.xml"), &settings, None).await?;
+        }
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_up_to_max_redirects() -> Result<()> {
+        let (addr, _) = serve(|path| match path {
+            "/old.xml" => "HTTP/1.1 301 Moved Permanently\r\nLocation: /blog/feed.xml\r\nContent-Length: 0\r\n\r\n".into(),
+            _ => ok_response(
+                r#"<rss version="2.0"><channel><title>T</title>
+                <item><guid>1</guid><link>posts/1</link><description>&lt;img src="a.png"&gt;</description></item>
+                </channel></rss>"#,
+            ),
+        })
+        .await?;
+        let url = format!("http://{addr}/old.xml");
+
+        let FetchOutcome::Fetched { feed, .. } =
+            fetch_feed(&url, &Settings::default(), None).await?
+        else {
+            panic!("expected a fetched feed");
+        };
+        assert_eq!(feed.borrow_source().url, url);
+        assert_eq!(
+            feed.borrow_source().final_url,
+            format!("http://{addr}/blog/feed.xml")
+        );
+        // the relative item link is resolved against the URL after redirects
+        let summary = feed.borrow_feed().entries[0].summary.as_ref().unwrap();
+        assert_eq!(
+            summary.content,
+            format!(r#"<img src="http://{addr}/blog/posts/a.png">"#)
+        );
+
+        let no_redirects = Settings {
+            max_redirects: 0,
+            ..Default::default()
+        };
+        let err = fetch_feed(&url, &no_redirects, None).await.err().unwrap();
+        assert!(err.to_string().contains("Redirected to /blog/feed.xml"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transcodes_charset_from_content_type() -> Result<()> {
+        let (addr, _) = serve(|path| {
+            let body: &[u8] = match path {
+                "/latin1.xml" => b"<rss version=\"2.0\"><channel><title>Caf\xe9</title><item><guid>1</guid><title>Cr\xe8me br\xfbl\xe9e</title></item></channel></rss>",
+                // a wrong default charset for a UTF-8 body
+                _ => "<rss version=\"2.0\"><channel><title>Café</title><item><guid>1</guid><title>Crème brûlée</title></item></channel></rss>".as_bytes(),
+            };
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/xml; charset=ISO-8859-1\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(body);
+            response
+        })
+        .await?;
+
+        for path in ["latin1.xml", "utf8.xml"] {
+            let FetchOutcome::Fetched { feed, .. } =
+                fetch_feed(&format!("http://{addr}/{path}"), &Settings::default(), None).await?
+            else {
+                panic!("expected a fetched feed");
+            };
+            let feed = feed.borrow_feed();
+            assert_eq!(feed.title.as_ref().unwrap().content, "Café");
+            let title = feed.entries[0].title.as_ref().unwrap();
+            assert_eq!(title.content, "Crème brûlée");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_text_pages_are_capped_by_max_body_size() -> Result<()> {
+        let (addr, _) = serve(|_| {
+            ok_response(&format!(
+                "<html><body><article><h1>Title</h1><p>{}</p></article></body></html>",
+                "The full text of the article. ".repeat(100)
+            ))
+        })
+        .await?;
+        let item = Entry {
+            links: vec![Link {
+                href: format!("http://{addr}/post"),
+                rel: None,
+                media_type: None,
+                href_lang: None,
+                title: None,
+                length: None,
+            }],
+            ..Default::default()
+        };
+
+        let full_text = fetch_full_text(&item, &Settings::default()).await?;
+        let body = full_text.content.unwrap().body.unwrap();
+        assert!(body.contains("The full text of the article."));
+
+        let small = Settings {
+            max_body_size: ByteSize::kib(1),
+            ..Default::default()
+        };
+        let err = fetch_full_text(&item, &small).await.err().unwrap();
+        assert!(format!("{err:#}").contains("max-body-size"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_text_is_fetched_without_http_headers() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/post", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).await?;
+            let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+            let page = format!(
+                "<html><body><article><h1>Title</h1><p>{}</p></article></body></html>",
+                "The full text of the article. ".repeat(20)
+            );
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{page}",
+                        page.len()
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            std::io::Result::Ok(request)
+        });
+
+        let item = Entry {
+            links: vec![Link {
+                href: url,
+                rel: None,
+                media_type: None,
+                href_lang: None,
+                title: None,
+                length: None,
+            }],
+            ..Default::default()
+        };
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        let settings = Settings {
+            http_headers: Arc::new(headers),
+            ..Default::default()
+        };
+        let full_text = fetch_full_text(&item, &settings).await?;
+        assert!(
+            full_text
+                .content
+                .and_then(|content| content.body)
+                .is_some_and(|body| body.contains("The full text of the article."))
+        );
+        assert!(!server.await??.contains("secret"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn images_are_fetched_without_http_headers() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/a.png", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).await?;
+            let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 3\r\n\r\nPNG",
+                )
+                .await?;
+            std::io::Result::Ok(request)
+        });
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        let settings = Settings {
+            http_headers: Arc::new(headers),
+            ..Default::default()
+        };
+        let (content_type, content) = fetch_image(&url, &settings, ByteSize::kib(1)).await?;
+        assert_eq!(content_type, "image/png");
+        assert_eq!(content, b"PNG");
+        assert!(!server.await??.contains("secret"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn index_is_fetched_with_feed_settings() -> Result<()> {
+        let (addr, _) = serve(|_| {
+            ok_response(&format!(
+                r#"<urlset><url><loc>https://example.com/a/feed.xml</loc></url>
+                <url><loc>https://example.com/about</loc></url>{}</urlset>"#,
+                " ".repeat(2048)
+            ))
+        })
+        .await?;
+        let url = format!("http://{addr}/sitemap.xml");
+        let pattern = Regex::new(r"/feed\.xml$")?;
+
+        let urls = fetch_index_urls(&url, &pattern, &Settings::default()).await?;
+        assert_eq!(urls, ["https://example.com/a/feed.xml"]);
+
+        let small = Settings {
+            max_body_size: ByteSize::kib(1),
+            ..Default::default()
+        };
+        let err = fetch_index_urls(&url, &pattern, &small)
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("max-body-size"));
+        Ok(())
+    }
+
+    #[test]
+    fn empty_feed_with_large_body_fails_only_when_strict() {
+        let mut content = String::from(r#"<rss version="2.0"><channel><title>t</title>"#);
+        content.push_str(&"<item><title>broken".repeat(100));
+        assert!(check_empty_feed("https://example.com/feed.xml", b"<rss/>", true).is_ok());
+        assert!(
+            check_empty_feed("https://example.com/feed.xml", content.as_bytes(), false).is_ok()
+        );
+        let err = check_empty_feed("https://example.com/feed.xml", content.as_bytes(), true)
+            .expect_err("empty feed accepted in strict mode");
+        assert!(err.to_string().contains("<rss version=\"2.0\"><channel>"));
+    }
+
+    #[test]
+    fn parses_advertised_interval() -> Result<()> {
+        let parse = |channel: &str| -> Result<Option<TimeDelta>> {
+            let content = format!(
+                r#"<rss version="2.0" xmlns:sy="http://purl.org/rss/1.0/modules/syndication/"><channel><title>T</title>{channel}</channel></rss>"#
+            );
+            let source = FeedSource::new("https://example.com/feed.xml", None);
+            let feed = parse_feed(content.as_bytes(), &Settings::default(), source)?;
+            Ok(*feed.borrow_advertised_interval())
+        };
+
+        assert_eq!(parse("")?, None);
+        assert_eq!(parse("<ttl>90</ttl>")?, Some(TimeDelta::minutes(90)));
+        assert_eq!(
+            parse(
+                "<sy:updatePeriod>daily</sy:updatePeriod><sy:updateFrequency>2</sy:updateFrequency>"
+            )?,
+            Some(TimeDelta::hours(12))
+        );
+        assert_eq!(
+            parse("<ttl>60</ttl><sy:updatePeriod> weekly </sy:updatePeriod>")?,
+            Some(TimeDelta::weeks(1))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use blake3::Hash;
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use color_eyre::eyre::WrapErr;
+use serde::Serialize;
+use serde_with::{DisplayFromStr, serde_as};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Worker state reported by the health check endpoint.
+pub struct Health {
+    started: DateTime<Utc>,
+    /// Unix timestamp of the last completed cycle, or 0 before the first cycle completes.
+    last_cycle: AtomicI64,
+    failing_feeds: AtomicUsize,
+    stale_after_secs: AtomicU64,
+    last_reload: Mutex<Option<Reload>>,
+}
+
+/// The last time the config was (re)loaded, whether it succeeded, and the hash of the config files.
+#[serde_as]
+#[derive(Serialize)]
+struct Reload {
+    time: DateTime<Utc>,
+    result: ReloadResult,
+    #[serde_as(as = "DisplayFromStr")]
+    config_hash: Hash,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ReloadResult {
+    Ok,
+    /// The previous config is still running.
+    Failed,
+}
+
+/// The JSON body of the health check response.
+#[derive(Serialize)]
+struct Report<'a> {
+    status: &'static str,
+    last_cycle: Option<DateTime<Utc>>,
+    failing_feeds: usize,
+    last_reload: Option<&'a Reload>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started: Utc::now(),
+            last_cycle: AtomicI64::new(0),
+            failing_feeds: AtomicUsize::new(0),
+            stale_after_secs: AtomicU64::new(0),
+            last_reload: Mutex::new(None),
+        }
+    }
+
+    pub fn record_cycle(&self) {
+        self.last_cycle
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn record_reload(&self, ok: bool, config_hash: Hash) {
+        *self.last_reload.lock().unwrap_or_else(|e| e.into_inner()) = Some(Reload {
+            time: Utc::now(),
+            result: if ok {
+                ReloadResult::Ok
+            } else {
+                ReloadResult::Failed
+            },
+            config_hash,
+        });
+    }
+
+    pub fn set_failing_feeds(&self, count: usize) {
+        self.failing_feeds.store(count, Ordering::Relaxed);
+    }
+
+    pub fn set_stale_after(&self, stale_after: Duration) {
+        self.stale_after_secs
+            .store(stale_after.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Returns the HTTP status line and the JSON body.
+    fn report(&self) -> serde_json::Result<(&'static str, String)> {
+        let last_cycle = DateTime::from_timestamp(self.last_cycle.load(Ordering::Relaxed), 0)
+            .filter(|time| time.timestamp() > 0);
+        let stale_after = self.stale_after_secs.load(Ordering::Relaxed);
+        let since = last_cycle.unwrap_or(self.started);
+        let healthy = (Utc::now() - since).num_seconds() <= stale_after as i64;
+
+        let (status_line, status) = if healthy {
+            ("200 OK", "ok")
+        } else {
+            ("503 Service Unavailable", "stale")
+        };
+        let last_reload = self.last_reload.lock().unwrap_or_else(|e| e.into_inner());
+        let body = serde_json::to_string(&Report {
+            status,
+            last_cycle,
+            failing_feeds: self.failing_feeds.load(Ordering::Relaxed),
+            last_reload: last_reload.as_ref(),
+        })?;
+        Ok((status_line, body))
+    }
+}
+
+pub async fn serve_health(addr: SocketAddr, health: Arc<Health>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .wrap_err_with(|| format!("Failed to listen on {addr}"))?;
+    log::info!("Health check endpoint listening on {addr}");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let health = Arc::clone(&health);
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream, &health).await {
+                            log::debug!("Failed to respond to health check: {e}");
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Failed to accept health check connection: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream, health: &Health) -> std::io::Result<()> {
+    // the request is not inspected, every path reports the health
+    let mut buf = [0; 1024];
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+
+    let (status_line, body) = health.report()?;
+    let response = format!(
+        "HTTP/1.1 {status_line}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_includes_last_reload() -> Result<()> {
+        let health = Health::new();
+        health.set_stale_after(Duration::from_mins(10));
+        let (_, body) = health.report()?;
+        let report: serde_json::Value = serde_json::from_str(&body)?;
+        assert_eq!(report["last_reload"], serde_json::Value::Null);
+
+        let config_hash = blake3::hash(b"[[feeds]]");
+        health.record_reload(false, config_hash);
+        let (status_line, body) = health.report()?;
+        let report: serde_json::Value = serde_json::from_str(&body)?;
+        assert_eq!(status_line, "200 OK");
+        assert_eq!(report["last_reload"]["result"], "failed");
+        assert_eq!(
+            report["last_reload"]["config_hash"],
+            config_hash.to_hex().as_str()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use crate::config::Settings;
+use crate::email::{InlineImage, Mail};
+use crate::feed::fetch_image;
+use bytesize::ByteSize;
+use futures::{StreamExt, stream};
+use lettre::message::header::ContentType;
+use lol_html::{RewriteStrSettings, element, rewrite_str};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const MAX_INLINE_IMAGES: usize = 10;
+const MAX_INLINE_IMAGE_SIZE: ByteSize = ByteSize::mib(1);
+const MAX_CONCURRENT_IMAGES: usize = 4;
+
+/// Downloads the images in mail bodies and attaches them to the mails.
+///
+/// Images are cached, so that mails sharing images (e.g. personalized mails) download them only
+/// once. Images that fail to download are left as remote URLs.
+pub struct ImageInliner<'a> {
+    settings: &'a Settings,
+    images: HashMap<String, Option<Arc<InlineImage>>>,
+}
+
+impl<'a> ImageInliner<'a> {
+    pub fn new(settings: &'a Settings) -> Self {
+        Self {
+            settings,
+            images: HashMap::new(),
+        }
+    }
+
+    pub async fn inline(&mut self, mail: &mut Mail) {
+        let mut urls = image_urls(&mail.body);
+        urls.truncate(MAX_INLINE_IMAGES);
+
+        let settings = self.settings;
+        let futures = urls
+            .iter()
+            .filter(|url| !self.images.contains_key(*url))
+            .map(|url| async move { (url, download_image(url, settings).await) })
+            .collect::<Vec<_>>();
+        let downloaded = stream::iter(futures)
+            .buffered(MAX_CONCURRENT_IMAGES)
+            .collect::<Vec<_>>()
+            .await;
+        for (url, image) in downloaded {
+            self.images.insert(url.clone(), image.map(Arc::new));
+        }
+
+        let images = urls
+            .iter()
+            .filter_map(|url| Some((url.as_str(), self.images.get(url)?.as_ref()?)))
+            .collect::<HashMap<_, _>>();
+        if images.is_empty() {
+            return;
+        }
+        mail.body = rewrite_image_urls(&mail.body, &images);
+        mail.images = images.into_values().map(Arc::clone).collect();
+    }
+}
+
+async fn download_image(url: &str, settings: &Settings) -> Option<InlineImage> {
+    let result = fetch_image(url, settings, MAX_INLINE_IMAGE_SIZE)
+        .await
+        .and_then(|(content_type, data)| Ok((ContentType::parse(&content_type)?, data)));
+    match result {
+        Ok((content_type, data)) => Some(InlineImage {
+            content_id: format!("{}@yaf2m", blake3::hash(url.as_bytes())),
+            content_type,
+            data,
+        }),
+        Err(e) => {
+            log::info!("Failed to inline image {url}: {e:#}");
+            None
+        }
+    }
+}
+
+/// The distinct HTTP(S) URLs of `<img>` elements, in the order they appear.
+fn image_urls(html: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let settings = RewriteStrSettings {
+        element_content_handlers: vec![element!("img[src]", |el| {
+            if let Some(src) = el.get_attribute("src")
+                && (src.starts_with("http://") || src.starts_with("https://"))
+                && !urls.contains(&src)
+            {
+                urls.push(src);
+            }
+            Ok(())
+        })],
+        ..RewriteStrSettings::new()
+    };
+    if let Err(e) = rewrite_str(html, settings) {
+        log::debug!("Failed to scan images: {e}");
+    }
+    urls
+}
+
+fn rewrite_image_urls(html: &str, images: &HashMap<&str, &Arc<InlineImage>>) -> String {
+    let settings = RewriteStrSettings {
+        element_content_handlers: vec![element!("img[src]", |el| {
+            if let Some(src) = el.get_attribute("src")
+                && let Some(image) = images.get(src.as_str())
+            {
+                el.set_attribute("src", &format!("cid:{}", image.content_id))?;
+            }
+            Ok(())
+        })],
+        ..RewriteStrSettings::new()
+    };
+    rewrite_str(html, settings).unwrap_or_else(|e| {
+        log::debug!("Failed to rewrite image URLs: {e}");
+        html.to_owned()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_and_rewrites_remote_images() {
+        let html = r#"<p><img src="https://example.com/a.png"><img src="data:image/png;base64,AA=="></p><img src="http://example.com/b.jpg" alt="b"><img src="https://example.com/a.png">"#;
+        assert_eq!(
+            image_urls(html),
+            ["https://example.com/a.png", "http://example.com/b.jpg"]
+        );
+
+        let image = Arc::new(InlineImage {
+            content_id: "a@yaf2m".into(),
+            content_type: ContentType::parse("image/png").unwrap(),
+            data: Vec::new(),
+        });
+        let images = HashMap::from([("https://example.com/a.png", &image)]);
+        assert_eq!(
+            rewrite_image_urls(html, &images),
+            r#"<p><img src="cid:a@yaf2m"><img src="data:image/png;base64,AA=="></p><img src="http://example.com/b.jpg" alt="b"><img src="cid:a@yaf2m">"#
+        );
+    }
+}
@@ -2,42 +2,346 @@ mod config;
 mod db;
 mod email;
 mod feed;
+mod health;
+mod images;
+mod logging;
 mod render;
 mod worker;
 
-use crate::email::Mailer;
+use crate::email::{Mailer, RateLimit, SendRetry, Transport};
+use blake3::Hash;
+use clap::builder::BoolishValueParser;
+use clap::{Parser, Subcommand};
 use color_eyre::Result;
-use color_eyre::eyre::WrapErr;
-use db::init_db;
+use color_eyre::eyre::{WrapErr, bail, eyre};
+use config::{DiscoveryCache, FeedGroup, load_config, load_single_feed_config};
+use db::{FeedStatus, Pool, get_item, init_db};
+use feed::{FeedSource, FetchOutcome, fetch_feed, parse_feed};
 use lettre::message::Mailbox;
-use lettre::{AsyncSmtpTransport, Tokio1Executor};
-use sqlx::postgres::PgPoolOptions;
+pub use logging::init_logger;
+use render::{Renderer, TemplateName, update_hash_from_values};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use worker::Worker;
+use worker::{
+    Worker, compose_mails, filter_item, item_context, preview_recipient, print_mail,
+    transform_items,
+};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path to the config file, or a directory of `*.toml` files to merge
+    #[arg(long, env = "YAF2M_CONFIG_PATH", default_value = "config/config.toml")]
+    config: PathBuf,
+    /// Run a single worker cycle and exit, e.g. when driven by cron or a systemd timer
+    #[arg(long, env = "YAF2M_ONCE", value_parser = BoolishValueParser::new())]
+    once: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the effective config after resolving per-feed settings, with secrets redacted
+    PrintConfig {
+        /// Only print the feed group of this URL
+        #[arg(long)]
+        feed: Option<String>,
+    },
+    /// Check the config file, including templates, filters and update keys, without network or database access
+    ValidateConfig,
+    /// Report how many items would be sent in the next check, without sending mails or writing to the database
+    DryRun {
+        /// Also print the rendered mails
+        #[arg(long)]
+        render: bool,
+    },
+    /// Render the mails for a feed from a saved sample, using the filter and templates of its feed group
+    RenderSample {
+        /// URL of the feed in the config
+        #[arg(long)]
+        feed: String,
+        /// Path to the sample feed content
+        #[arg(long)]
+        input: PathBuf,
+    },
+    /// Fetch a feed that may not be in the config, and print its items with the filter result, update hash and subject, without database or SMTP access
+    TestFeed {
+        /// URL of the feed
+        url: String,
+        /// Filter as a MiniJinja expression
+        #[arg(long)]
+        filter: Option<String>,
+        /// Template of the item subject
+        #[arg(long)]
+        item_subject: Option<String>,
+        /// Template of the item body, which is also printed when set
+        #[arg(long)]
+        item_body: Option<String>,
+    },
+    /// Show whether an item of a feed is recorded in the database, which is only read
+    Preview {
+        /// URL of the feed in the config
+        #[arg(long)]
+        feed: String,
+        /// Value of each update key of the item, in order
+        #[arg(long = "key", required_unless_present = "hash")]
+        keys: Vec<String>,
+        /// Update hash of the item, e.g. as printed by `test-feed`
+        #[arg(long, conflicts_with = "keys")]
+        hash: Option<Hash>,
+    },
+}
 
 pub async fn run() -> Result<()> {
-    let config_path =
-        std::env::var("YAF2M_CONFIG_PATH").unwrap_or_else(|_| "config/config.toml".to_string());
+    let cli = Cli::parse();
 
-    let database_url =
-        std::env::var("POSTGRES_URL").wrap_err("POSTGRES_URL environment variable not set")?;
+    match cli.command {
+        None => run_worker(cli.config, cli.once).await,
+        Some(Command::PrintConfig { feed }) => print_config(cli.config, feed.as_deref()).await,
+        Some(Command::ValidateConfig) => validate_config(cli.config).await,
+        Some(Command::DryRun { render }) => dry_run(cli.config, render).await,
+        Some(Command::RenderSample { feed, input }) => {
+            render_sample(cli.config, &feed, &input).await
+        }
+        Some(Command::TestFeed {
+            url,
+            filter,
+            item_subject,
+            item_body,
+        }) => test_feed(cli.config, url, filter, item_subject, item_body).await,
+        Some(Command::Preview { feed, keys, hash }) => {
+            preview(cli.config, &feed, &keys, hash).await
+        }
+    }
+}
 
-    let pool = PgPoolOptions::new()
-        .max_connections(20)
-        .acquire_slow_threshold(Duration::from_secs(10))
-        .acquire_timeout(Duration::from_mins(2))
-        .connect(&database_url)
-        .await?;
+async fn connect_db() -> Result<Pool> {
+    // `POSTGRES_URL` is kept for existing deployments
+    let database_url = std::env::var("DATABASE_URL")
+        .or_else(|_| std::env::var("POSTGRES_URL"))
+        .wrap_err("DATABASE_URL environment variable not set")?;
+
+    Pool::connect(&database_url).await
+}
+
+async fn run_worker(config_path: PathBuf, once: bool) -> Result<()> {
+    let pool = connect_db().await?;
 
     init_db(&pool).await?;
 
     let from_str = std::env::var("SMTP_FROM").wrap_err("SMTP_FROM environment variable not set")?;
     let from = from_str.parse::<Mailbox>().wrap_err("Invalid SMTP_FROM")?;
 
+    // SMTP-wide settings are read once, changing them requires a restart
+    let config = load_config(&config_path, &mut DiscoveryCache::offline()).await?;
+
     let smtp_url = std::env::var("SMTP_URL").wrap_err("SMTP_URL environment variable not set")?;
-    let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&smtp_url)?.build();
+    let transport =
+        Transport::from_url(&smtp_url, config.smtp_oauth2).wrap_err("Invalid SMTP_URL")?;
+    let retry = SendRetry {
+        retries: config.send_retries,
+        backoff: config.send_retry_backoff,
+        max_backoff: config.send_retry_max_backoff,
+    };
+
+    let rate_limit = config
+        .max_emails_per_minute
+        .map(|max| RateLimit::new(max, Duration::from_mins(1)));
+
+    let mailer = Mailer {
+        from,
+        transport,
+        retry,
+        rate_limit,
+    };
+
+    Worker::new(pool, config_path, mailer).run(once).await
+}
+
+async fn print_config(config_path: PathBuf, feed_url: Option<&str>) -> Result<()> {
+    let config = load_config(&config_path, &mut DiscoveryCache::default()).await?;
+    let output = match feed_url {
+        Some(feed_url) => {
+            let feed_group = find_feed_group(&config.feeds, feed_url)?;
+            toml::to_string_pretty(feed_group)
+        }
+        None => toml::to_string_pretty(&config),
+    }
+    .wrap_err("Failed to serialize config")?;
+    print!("{output}");
+    Ok(())
+}
+
+async fn validate_config(config_path: PathBuf) -> Result<()> {
+    let config = load_config(&config_path, &mut DiscoveryCache::offline()).await?;
+
+    let mut errors = 0;
+    for feed_group in &config.feeds {
+        if let Err(e) =
+            Renderer::from_feed(feed_group).and_then(|renderer| renderer.check_templates())
+        {
+            println!("Feed group {:?}: {e:#}", feed_group.urls);
+            errors += 1;
+        }
+    }
+
+    if errors > 0 {
+        return Err(eyre!(
+            "{errors} of {} feed groups have errors",
+            config.feeds.len()
+        ));
+    }
+    println!("Config is valid: {} feed groups", config.feeds.len());
+    Ok(())
+}
+
+async fn dry_run(config_path: PathBuf, render: bool) -> Result<()> {
+    let config = load_config(&config_path, &mut DiscoveryCache::default()).await?;
+    let pool = connect_db().await?;
+    worker::dry_run(pool, config.feeds, render).await
+}
+
+fn find_feed_group<'a>(feeds: &'a [FeedGroup], feed_url: &str) -> Result<&'a FeedGroup> {
+    feeds
+        .iter()
+        .find(|feed| feed.urls.iter().any(|url| url == feed_url))
+        .ok_or_else(|| eyre!("Feed {feed_url} not found in config"))
+}
+
+async fn render_sample(config_path: PathBuf, feed_url: &str, input: &Path) -> Result<()> {
+    let config = load_config(&config_path, &mut DiscoveryCache::default()).await?;
+    let feed_group = find_feed_group(&config.feeds, feed_url)?;
+
+    let content = tokio::fs::read(input)
+        .await
+        .wrap_err_with(|| format!("Failed to read sample at {}", input.display()))?;
+    let feed = parse_feed(
+        &content,
+        &feed_group.settings,
+        FeedSource::new(feed_url, None),
+    )?;
+
+    let renderer = Renderer::from_feed(feed_group)?;
+    let feed = transform_items(feed_group, &renderer, feed)?;
+    let items = feed
+        .borrow_items()
+        .iter()
+        .filter_map(|item| match filter_item(feed_group, &renderer, item) {
+            Ok(true) => Some(Ok(item)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if items.is_empty() {
+        eprintln!("No items passed the filter");
+        return Ok(());
+    }
+
+    let mails = compose_mails(
+        feed_group,
+        &renderer,
+        FeedStatus::Update,
+        std::slice::from_ref(&feed),
+        items,
+        preview_recipient(&feed_group.settings),
+    )?;
+    for mail in &mails {
+        print_mail(mail);
+    }
+    Ok(())
+}
+
+async fn test_feed(
+    config_path: PathBuf,
+    url: String,
+    filter: Option<String>,
+    item_subject: Option<String>,
+    item_body: Option<String>,
+) -> Result<()> {
+    let inline = |template: String| toml::Table::from_iter([("inline".into(), template.into())]);
+    let mut feed = toml::Table::new();
+    feed.insert("url".into(), url.clone().into());
+    if let Some(filter) = filter {
+        let filter = toml::Table::from_iter([("jinja-expr".into(), filter.into())]);
+        feed.insert("filter".into(), filter.into());
+    }
+    if let Some(item_subject) = item_subject {
+        feed.insert("item-subject".into(), inline(item_subject).into());
+    }
+    let print_body = item_body.is_some();
+    if let Some(item_body) = item_body {
+        feed.insert("item-body".into(), inline(item_body).into());
+    }
+    let config = load_single_feed_config(&config_path, feed).await?;
+    let feed_group = &config.feeds[0];
+
+    let renderer = Renderer::from_feed(feed_group)?;
+    renderer.check_templates()?;
+    let FetchOutcome::Fetched { feed, .. } = fetch_feed(&url, &feed_group.settings, None).await?
+    else {
+        bail!("unexpected 304 Not Modified from {url} without validators")
+    };
+    let feed = transform_items(feed_group, &renderer, feed)?;
+
+    let recipient = preview_recipient(&feed_group.settings);
+    let mut passed = 0;
+    for item in feed.borrow_items() {
+        let pass = filter_item(feed_group, &renderer, item)?;
+        passed += usize::from(pass);
+        let ctx = item_context(item, recipient);
+        println!("ID: {}", item.item.id);
+        if let Some(link) = item.item.links.first() {
+            println!("Link: {}", link.href);
+        }
+        println!("Filter: {}", if pass { "passed" } else { "filtered out" });
+        println!("Update hash: {}", renderer.update_hash(item)?);
+        println!(
+            "Subject: {}",
+            renderer.render(TemplateName::ItemSubject, &ctx)?
+        );
+        if print_body {
+            println!("\n{}", renderer.render(TemplateName::ItemBody, &ctx)?);
+        }
+        println!();
+    }
+    println!(
+        "{passed} of {} items passed the filter",
+        feed.borrow_items().len()
+    );
+    Ok(())
+}
 
-    let mailer = Mailer { from, transport };
+async fn preview(
+    config_path: PathBuf,
+    feed_url: &str,
+    keys: &[String],
+    hash: Option<Hash>,
+) -> Result<()> {
+    let config = load_config(&config_path, &mut DiscoveryCache::default()).await?;
+    let feed_group = find_feed_group(&config.feeds, feed_url)?;
+    let update_hash = match hash {
+        Some(hash) => hash,
+        None => update_hash_from_values(&feed_group.settings, keys)
+            .wrap_err("Failed to compute the update hash, use --hash instead")?,
+    };
 
-    Worker::new(pool, config_path, mailer).run().await
+    let pool = connect_db().await?;
+    println!("Update hash: {update_hash}");
+    let Some(item) = get_item(&pool, feed_group.urls_hash, update_hash).await? else {
+        println!(
+            "Not recorded: the item has not been seen, did not pass the filter, or was deleted after keep-old"
+        );
+        return Ok(());
+    };
+    println!("First seen: {}", item.first_seen.to_rfc3339());
+    println!("Last seen: {}", item.last_seen.to_rfc3339());
+    if let Some(title) = item.title {
+        println!("Title: {title}");
+    }
+    if let Some(updated) = item.updated {
+        println!("Updated: {}", updated.to_rfc3339());
+    }
+    Ok(())
 }
@@ -0,0 +1,79 @@
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use env_logger::fmt::Formatter;
+use log::Record;
+use log::kv::{Error, Key, Value, VisitSource};
+use serde_json::{Map, Value as Json};
+use std::io::Write;
+
+/// Initializes the logger, in the format chosen by `YAF2M_LOG_FORMAT`.
+///
+/// The level is configured by `RUST_LOG` in both formats.
+pub fn init_logger() -> Result<()> {
+    let mut builder = env_logger::Builder::from_default_env();
+    match std::env::var("YAF2M_LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => {}
+        Ok("json") => {
+            builder.format(format_json);
+        }
+        Ok(format) => return Err(eyre!("Invalid YAF2M_LOG_FORMAT: {format:?}")),
+    }
+    builder.init();
+    Ok(())
+}
+
+/// Formats a record as a single-line JSON object, with its key-values as additional fields.
+fn format_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let mut fields = Map::new();
+    fields.insert(
+        "timestamp".into(),
+        buf.timestamp_millis().to_string().into(),
+    );
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    fields.insert("message".into(), record.args().to_string().into());
+    // the visitor never fails
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+    writeln!(buf, "{}", Json::Object(fields))
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_key_values_as_fields() {
+        let kvs = [
+            ("items", Value::from(3u64)),
+            ("url", Value::from("https://example.com/rss")),
+        ];
+        let record = Record::builder().key_values(&kvs).build();
+        let mut fields = Map::new();
+        record
+            .key_values()
+            .visit(&mut JsonFields(&mut fields))
+            .unwrap();
+        assert_eq!(
+            Json::Object(fields).to_string(),
+            r#"{"items":3,"url":"https://example.com/rss"}"#
+        );
+    }
+}
@@ -2,7 +2,7 @@ use color_eyre::Result;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    yaf2m::init_logger()?;
     color_eyre::install()?;
     yaf2m::run().await
 }
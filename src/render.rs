@@ -1,24 +1,45 @@
-use crate::config::{FeedGroup, Filter, TemplateSource};
-use crate::feed::FeedItemContext;
+use crate::config::{FeedGroup, Filter, ItemTransform, Settings, TemplateSource};
+use crate::feed::{FeedItemContext, ItemChanges, Sanitizer};
 use blake3::{Hash, Hasher};
-use color_eyre::{Result, eyre::WrapErr};
-use minijinja::{Environment, Expression, Value};
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::Result;
+use color_eyre::eyre::{WrapErr, bail};
+use feed_rs::model::MediaContent;
+use minijinja::value::{Kwargs, ValueKind};
+use minijinja::{Environment, ErrorKind, Expression, Value, context};
 use minijinja_contrib::add_to_environment;
 use ouroboros::self_referencing;
+use pulldown_cmark::{Options, Parser};
 use regex::Regex;
+use reqwest::Url;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
+/// Template args in the form of `{ jinja-expr = "..." }` are evaluated at render time.
+const TEMPLATE_ARG_EXPR_KEY: &str = "jinja-expr";
+
+/// The global used by the date filters of `minijinja-contrib` as the default timezone.
+pub const TIMEZONE_GLOBAL: &str = "TIMEZONE";
+
 #[self_referencing]
 pub struct Renderer<'a> {
     env: Environment<'a>,
+    template_args: Arc<Value>,
+    update_on_content_change: bool,
     #[borrows(env)]
     #[covariant]
-    update_key_exprs: Vec<Expression<'this, 'a>>,
+    update_keys: CompiledUpdateKeys<'this, 'a>,
     #[borrows(env)]
     #[covariant]
     filter: Option<CompiledFilter<'this>>,
+    #[borrows(env)]
+    #[covariant]
+    item_transform: Option<CompiledTransform<'this, 'a>>,
+    #[borrows(env)]
+    #[covariant]
+    template_arg_exprs: Vec<(String, Expression<'this, 'a>)>,
 }
 
 pub enum TemplateName {
@@ -26,6 +47,19 @@ pub enum TemplateName {
     DigestSubject,
     ItemBody,
     DigestBody,
+    ItemTextBody,
+    DigestTextBody,
+}
+
+impl TemplateName {
+    pub const ALL: [Self; 6] = [
+        Self::ItemSubject,
+        Self::DigestSubject,
+        Self::ItemBody,
+        Self::DigestBody,
+        Self::ItemTextBody,
+        Self::DigestTextBody,
+    ];
 }
 
 impl AsRef<str> for TemplateName {
@@ -35,6 +69,8 @@ impl AsRef<str> for TemplateName {
             Self::DigestSubject => "digest-subject.txt",
             Self::ItemBody => "item-body.html",
             Self::DigestBody => "digest-body.html",
+            Self::ItemTextBody => "item-body.txt",
+            Self::DigestTextBody => "digest-body.txt",
         }
     }
 }
@@ -55,71 +91,169 @@ impl<'a> Renderer<'a> {
         env.add_test("matches", regex_is_match);
         env.add_filter("capture", regex_capture);
         env.add_filter("regex_replace", regex_replace);
+        env.add_filter("regex_split", regex_split);
+        env.add_filter("normalize_url", normalize_url);
+        let url_schemes = Arc::clone(&feed.settings.url_schemes);
+        let allowed_tags = Arc::clone(&feed.settings.sanitize_allowed_tags);
+        let allowed_attributes = Arc::clone(&feed.settings.sanitize_allowed_attributes);
+        env.add_filter("markdown", move |value: &str| {
+            let sanitizer = Sanitizer::new(&url_schemes, &allowed_tags, &allowed_attributes);
+            markdown(value, &sanitizer)
+        });
 
         env.add_global(
             "template_args",
             Value::from_serialize(&feed.settings.template_args),
         );
+        if let Some(timezone) = &feed.timezone {
+            env.add_global(TIMEZONE_GLOBAL, timezone.as_str());
+        }
 
         let templates = Templates {
             item_subject: Arc::clone(&feed.settings.item_subject),
             digest_subject: Arc::clone(&feed.settings.digest_subject),
             item_body: Arc::clone(&feed.settings.item_body),
             digest_body: Arc::clone(&feed.settings.digest_body),
+            item_text_body: feed.settings.item_text_body.clone(),
+            digest_text_body: feed.settings.digest_text_body.clone(),
         };
+        let shared_templates = Arc::clone(&feed.shared_templates);
 
         env.set_loader(move |name| match name {
             "item-subject.txt" => templates.item_subject.load(),
             "digest-subject.txt" => templates.digest_subject.load(),
             "item-body.html" => templates.item_body.load(),
             "digest-body.html" => templates.digest_body.load(),
-            _ => Ok(None),
+            "item-body.txt" => templates
+                .item_text_body
+                .as_ref()
+                .map_or(Ok(None), |t| t.load()),
+            "digest-body.txt" => templates
+                .digest_text_body
+                .as_ref()
+                .map_or(Ok(None), |t| t.load()),
+            _ => shared_templates
+                .get(name)
+                .map_or(Ok(None), TemplateSource::load),
         });
 
         Renderer::try_new(
             env,
-            |env| {
-                feed.settings
-                    .update_keys
-                    .iter()
-                    .map(|key| {
-                        env.compile_expression(key)
-                            .wrap_err("Failed to compile update key expression")
-                    })
-                    .collect()
-            },
+            Arc::clone(&feed.settings.template_args),
+            feed.settings.update_on_content_change,
+            |env| CompiledUpdateKeys::compile(&feed.settings, env),
             |env| {
                 feed.filter
                     .as_ref()
                     .map(|f| CompiledFilter::compile(f, env))
                     .transpose()
             },
+            |env| {
+                feed.item_transform
+                    .as_ref()
+                    .map(|t| CompiledTransform::compile(t, env))
+                    .transpose()
+            },
+            |env| {
+                let args = &feed.settings.template_args;
+                args.try_iter()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|name| {
+                        let expr = template_arg_expr(&args.get_item(&name).ok()?)?;
+                        Some((name.to_string(), expr))
+                    })
+                    .map(|(name, expr)| {
+                        let expr = env.compile_expression_owned(expr).wrap_err_with(|| {
+                            format!("Failed to compile template arg {name} expression")
+                        })?;
+                        Ok((name, expr))
+                    })
+                    .collect()
+            },
         )
     }
 
+    /// Compiles all templates, which are otherwise only loaded when first rendered.
+    pub fn check_templates(&self) -> Result<()> {
+        for name in TemplateName::ALL {
+            let optional = matches!(
+                name,
+                TemplateName::ItemTextBody | TemplateName::DigestTextBody
+            );
+            match self.borrow_env().get_template(name.as_ref()) {
+                Ok(_) => {}
+                Err(e) if optional && e.kind() == ErrorKind::TemplateNotFound => {}
+                Err(e) => return Err(e).wrap_err_with(|| format!("Invalid {name} template")),
+            }
+        }
+        Ok(())
+    }
+
     pub fn render<S: Serialize>(&self, name: TemplateName, ctx: S) -> Result<String> {
-        self.borrow_env()
+        let template = self
+            .borrow_env()
             .get_template(name.as_ref())
-            .wrap_err_with(|| format!("Failed to get {name} template"))?
-            .render(ctx)
-            .wrap_err_with(|| format!("Failed to render {name} template"))
+            .wrap_err_with(|| format!("Failed to get {name} template"))?;
+        let rendered = if self.borrow_template_arg_exprs().is_empty() {
+            template.render(ctx)
+        } else {
+            let ctx = Value::from_serialize(ctx);
+            let template_args = self.eval_template_args(&ctx)?;
+            template.render(context! { template_args, ..ctx })
+        };
+        rendered.wrap_err_with(|| format!("Failed to render {name} template"))
     }
 
-    pub fn update_hash(&self, ctx: &FeedItemContext) -> Result<Hash> {
-        let mut hasher = Hasher::new();
-        for key in self.borrow_update_key_exprs() {
-            let value = key
+    /// Renders a template string from the config, such as a personalized `list-unsubscribe` URI.
+    pub fn render_str<S: Serialize>(&self, source: &str, ctx: S) -> Result<String> {
+        self.borrow_env()
+            .render_str(source, ctx)
+            .wrap_err_with(|| format!("Failed to render {source:?}"))
+    }
+
+    fn eval_template_args(&self, ctx: &Value) -> Result<Value> {
+        let static_args = self.borrow_template_args();
+        let mut args = static_args
+            .try_iter()
+            .into_iter()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), static_args.get_item(&name).ok()?)))
+            .collect::<BTreeMap<_, _>>();
+        for (name, expr) in self.borrow_template_arg_exprs() {
+            let value = expr
                 .eval(ctx)
-                .wrap_err("Failed to evaluate update key expression")?;
-            let hash = match value.as_bytes() {
-                Some(bytes) => blake3::hash(bytes),
-                None => blake3::hash(value.to_string().as_bytes()),
-            };
-            hasher.update(hash.as_bytes());
+                .wrap_err_with(|| format!("Failed to evaluate template arg {name}"))?;
+            args.insert(name.clone(), value);
+        }
+        Ok(Value::from(args))
+    }
+
+    pub fn update_hash(&self, ctx: &FeedItemContext) -> Result<Hash> {
+        let update_keys = self.borrow_update_keys();
+        let mut values = eval_update_keys(&update_keys.keys, ctx)?;
+        if !update_keys.fallback.is_empty() && all_empty(&values) {
+            values = eval_update_keys(&update_keys.fallback, ctx)?;
+        }
+        let mut hasher = hash_update_key_values(&values);
+        if *self.borrow_update_on_content_change() {
+            hasher.update(content_hash(ctx).as_bytes());
         }
         Ok(hasher.finalize())
     }
 
+    /// Whether all update keys evaluate to empty values for the item, ignoring the fallback keys.
+    pub fn update_keys_empty(&self, ctx: &FeedItemContext) -> Result<bool> {
+        eval_update_keys(&self.borrow_update_keys().keys, ctx).map(|values| all_empty(&values))
+    }
+
+    /// The fields of the item changed by `item-transform`.
+    pub fn transform_item(&self, ctx: &FeedItemContext) -> Result<ItemChanges> {
+        self.borrow_item_transform()
+            .as_ref()
+            .map_or(Ok(ItemChanges::default()), |t| t.evaluate(ctx))
+    }
+
     pub fn filter(&self, ctx: &FeedItemContext) -> Result<bool> {
         self.borrow_filter()
             .as_ref()
@@ -127,6 +261,113 @@ impl<'a> Renderer<'a> {
     }
 }
 
+struct CompiledUpdateKeys<'env, 'source> {
+    keys: Vec<Expression<'env, 'source>>,
+    /// Used when all `keys` are empty.
+    fallback: Vec<Expression<'env, 'source>>,
+}
+
+impl<'env, 'source> CompiledUpdateKeys<'env, 'source> {
+    fn compile(settings: &'source Settings, env: &'env Environment<'source>) -> Result<Self> {
+        let compile = |keys: &'source [String]| {
+            keys.iter()
+                .map(|key| {
+                    env.compile_expression(key)
+                        .wrap_err("Failed to compile update key expression")
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            keys: compile(&settings.update_keys)?,
+            fallback: compile(&settings.fallback_update_keys)?,
+        })
+    }
+}
+
+fn eval_update_keys(keys: &[Expression], ctx: &FeedItemContext) -> Result<Vec<Value>> {
+    keys.iter()
+        .map(|key| {
+            key.eval(ctx)
+                .wrap_err("Failed to evaluate update key expression")
+        })
+        .collect()
+}
+
+fn hash_update_key_values(values: &[Value]) -> Hasher {
+    let mut hasher = Hasher::new();
+    for value in values {
+        let hash = match value.as_bytes() {
+            Some(bytes) => blake3::hash(bytes),
+            None => blake3::hash(value.to_string().as_bytes()),
+        };
+        hasher.update(hash.as_bytes());
+    }
+    hasher
+}
+
+/// The update hash of an item whose update keys evaluate to `values`, which are taken as strings.
+pub fn update_hash_from_values(settings: &Settings, values: &[String]) -> Result<Hash> {
+    if settings.update_on_content_change {
+        bail!("the update hash includes the item content with update-on-content-change");
+    }
+    if ![
+        settings.update_keys.len(),
+        settings.fallback_update_keys.len(),
+    ]
+    .contains(&values.len())
+    {
+        bail!(
+            "expected a value for each of the update keys {:?}",
+            settings.update_keys
+        );
+    }
+    let values = values.iter().map(Value::from).collect::<Vec<_>>();
+    Ok(hash_update_key_values(&values).finalize())
+}
+
+/// Whether all values are undefined, none or empty, e.g. `item.id` of feeds without IDs.
+fn all_empty(values: &[Value]) -> bool {
+    values
+        .iter()
+        .all(|value| value.is_undefined() || value.is_none() || value.len() == Some(0))
+}
+
+/// Hash of the item content and summary, which are already sanitized when the feed is parsed.
+fn content_hash(ctx: &FeedItemContext) -> Hash {
+    let body = ctx
+        .item
+        .content
+        .as_ref()
+        .and_then(|content| content.body.as_deref());
+    let summary = ctx.item.summary.as_ref().map(|summary| &summary.content);
+    let mut hasher = Hasher::new();
+    for part in [body, summary.map(String::as_str)] {
+        hasher.update(blake3::hash(part.unwrap_or_default().as_bytes()).as_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Checks that the timezone is known to the date filters.
+pub fn check_timezone(timezone: &str) -> Result<()> {
+    let mut env = Environment::new();
+    add_to_environment(&mut env);
+    env.add_global(TIMEZONE_GLOBAL, timezone);
+    env.render_str("{{ 0 | datetimeformat }}", ())
+        .wrap_err_with(|| format!("Invalid timezone {timezone:?}"))?;
+    Ok(())
+}
+
+fn template_arg_expr(value: &Value) -> Option<String> {
+    if value.kind() != ValueKind::Map || value.len() != Some(1) {
+        return None;
+    }
+    value
+        .get_attr(TEMPLATE_ARG_EXPR_KEY)
+        .ok()?
+        .as_str()
+        .map(str::to_owned)
+}
+
 fn minijinja_regex(pattern: &str) -> Result<Regex, minijinja::Error> {
     Regex::new(pattern).map_err(|e| {
         minijinja::Error::new(
@@ -156,11 +397,103 @@ fn regex_replace(value: &str, pattern: &str, replacement: &str) -> Result<Value,
     minijinja_regex(pattern).map(|re| re.replace_all(value, replacement).into())
 }
 
+fn regex_split(value: &str, pattern: &str) -> Result<Value, minijinja::Error> {
+    minijinja_regex(pattern).map(|re| re.split(value).map(Value::from).collect())
+}
+
+/// Normalizes an HTTP(S) URL so that it is stable across site migrations: the scheme becomes
+/// `https`, and trailing slashes of the path are removed. The host is removed if `host=false`.
+/// Other values are returned as is.
+fn normalize_url(value: &str, kwargs: Kwargs) -> Result<String, minijinja::Error> {
+    let keep_host = kwargs.get::<Option<bool>>("host")?.unwrap_or(true);
+    kwargs.assert_all_used()?;
+
+    let url = match Url::parse(value.trim()) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => return Ok(value.to_owned()),
+    };
+
+    let mut normalized = String::new();
+    if keep_host {
+        normalized.push_str("https://");
+        normalized.push_str(url.host_str().unwrap_or_default());
+        // the parser has dropped the default port of the original scheme
+        if let Some(port) = url.port() {
+            normalized.push_str(&format!(":{port}"));
+        }
+    }
+    normalized.push_str(url.path().trim_end_matches('/'));
+    if let Some(query) = url.query() {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    if let Some(fragment) = url.fragment() {
+        normalized.push('#');
+        normalized.push_str(fragment);
+    }
+    Ok(normalized)
+}
+
+/// Converts Markdown to HTML, sanitized in the same way as the HTML in feeds.
+fn markdown(value: &str, sanitizer: &Sanitizer) -> Value {
+    let parser = Parser::new_ext(
+        value,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+    );
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser);
+    Value::from_safe_string(sanitizer.clean(&html))
+}
+
 struct Templates {
     item_subject: Arc<TemplateSource>,
     digest_subject: Arc<TemplateSource>,
     item_body: Arc<TemplateSource>,
     digest_body: Arc<TemplateSource>,
+    item_text_body: Option<Arc<TemplateSource>>,
+    digest_text_body: Option<Arc<TemplateSource>>,
+}
+
+struct CompiledTransform<'env, 'source> {
+    title: Option<Expression<'env, 'source>>,
+    summary: Option<Expression<'env, 'source>>,
+    link: Option<Expression<'env, 'source>>,
+}
+
+impl<'env, 'source> CompiledTransform<'env, 'source> {
+    fn compile(transform: &'source ItemTransform, env: &'env Environment<'source>) -> Result<Self> {
+        let compile = |field: &str, expr: &'source Option<String>| {
+            expr.as_deref()
+                .map(|expr| {
+                    env.compile_expression(expr).wrap_err_with(|| {
+                        format!("Failed to compile item-transform.{field} expression")
+                    })
+                })
+                .transpose()
+        };
+        Ok(Self {
+            title: compile("title", &transform.title)?,
+            summary: compile("summary", &transform.summary)?,
+            link: compile("link", &transform.link)?,
+        })
+    }
+
+    fn evaluate(&self, ctx: &FeedItemContext) -> Result<ItemChanges> {
+        let evaluate = |field: &str, expr: &Option<Expression>| -> Result<Option<String>> {
+            let Some(expr) = expr else {
+                return Ok(None);
+            };
+            let value = expr.eval(ctx).wrap_err_with(|| {
+                format!("Failed to evaluate item-transform.{field} expression")
+            })?;
+            Ok((!value.is_none() && !value.is_undefined()).then(|| value.to_string()))
+        };
+        Ok(ItemChanges {
+            title: evaluate("title", &self.title)?,
+            summary: evaluate("summary", &self.summary)?,
+            link: evaluate("link", &self.link)?,
+        })
+    }
 }
 
 enum CompiledFilter<'a> {
@@ -171,6 +504,40 @@ enum CompiledFilter<'a> {
     BodyRegex(Regex),
     Regex(Regex),
     JinjaExpr(Expression<'a, 'a>),
+    Category(Regex),
+    Author(Regex),
+    LinkRegex(Regex),
+    MediaType(Regex),
+    PublishedAfter {
+        since: DateBound,
+        drop_undated: bool,
+    },
+}
+
+enum DateBound {
+    Absolute(DateTime<Utc>),
+    /// Relative to the time of evaluation.
+    Relative(TimeDelta),
+}
+
+impl DateBound {
+    fn parse(s: &str) -> Result<Self> {
+        if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Self::Absolute(datetime.to_utc()));
+        }
+        let duration = humantime::parse_duration(s)
+            .wrap_err_with(|| format!("Expected an RFC 3339 datetime or a duration, got {s:?}"))?;
+        Ok(Self::Relative(TimeDelta::from_std(duration)?))
+    }
+
+    fn resolve(&self) -> DateTime<Utc> {
+        match self {
+            Self::Absolute(datetime) => *datetime,
+            Self::Relative(delta) => Utc::now()
+                .checked_sub_signed(*delta)
+                .unwrap_or(DateTime::<Utc>::MIN_UTC),
+        }
+    }
 }
 
 impl<'a> CompiledFilter<'a> {
@@ -190,15 +557,21 @@ impl<'a> CompiledFilter<'a> {
             )),
             Filter::Not(clause) => Ok(Self::Not(Box::new(Self::compile(clause, env)?))),
             Filter::TitleRegex(pattern) => {
-                let re = Regex::new(pattern).wrap_err("Failed to complile filter title regex")?;
+                let re = pattern
+                    .to_regex()
+                    .wrap_err("Failed to complile filter title regex")?;
                 Ok(Self::TitleRegex(re))
             }
             Filter::BodyRegex(pattern) => {
-                let re = Regex::new(pattern).wrap_err("Failed to complile filter body regex")?;
+                let re = pattern
+                    .to_regex()
+                    .wrap_err("Failed to complile filter body regex")?;
                 Ok(Self::BodyRegex(re))
             }
             Filter::Regex(pattern) => {
-                let re = Regex::new(pattern).wrap_err("Failed to complile filter regex")?;
+                let re = pattern
+                    .to_regex()
+                    .wrap_err("Failed to complile filter regex")?;
                 Ok(Self::Regex(re))
             }
             Filter::JinjaExpr(expr_str) => {
@@ -207,6 +580,37 @@ impl<'a> CompiledFilter<'a> {
                     .wrap_err("Failed to compile filter Jinja expression")?;
                 Ok(Self::JinjaExpr(expr))
             }
+            Filter::Category(pattern) => {
+                let re = pattern
+                    .to_regex()
+                    .wrap_err("Failed to compile filter category regex")?;
+                Ok(Self::Category(re))
+            }
+            Filter::Author(pattern) => {
+                let re = pattern
+                    .to_regex()
+                    .wrap_err("Failed to compile filter author regex")?;
+                Ok(Self::Author(re))
+            }
+            Filter::LinkRegex(pattern) => {
+                let re = pattern
+                    .to_regex()
+                    .wrap_err("Failed to compile filter link regex")?;
+                Ok(Self::LinkRegex(re))
+            }
+            Filter::MediaType(pattern) => {
+                let re = pattern
+                    .to_regex()
+                    .wrap_err("Failed to compile filter media type regex")?;
+                Ok(Self::MediaType(re))
+            }
+            Filter::PublishedAfter {
+                since,
+                drop_undated,
+            } => Ok(Self::PublishedAfter {
+                since: DateBound::parse(since).wrap_err("Invalid filter published-after")?,
+                drop_undated: *drop_undated,
+            }),
         }
     }
 
@@ -243,19 +647,64 @@ impl<'a> CompiledFilter<'a> {
                 .eval(ctx)
                 .map(|v| v.is_true())
                 .wrap_err("Failed to evaluate filter Jinja expression"),
+            Self::Category(re) => Ok(ctx.item.categories.iter().any(|category| {
+                re.is_match(&category.term)
+                    || category
+                        .label
+                        .as_ref()
+                        .is_some_and(|label| re.is_match(label))
+            })),
+            Self::Author(re) => {
+                let authors = if ctx.item.authors.is_empty() {
+                    &ctx.feed.authors
+                } else {
+                    &ctx.item.authors
+                };
+                Ok(authors.iter().any(|author| re.is_match(&author.name)))
+            }
+            // hrefs are matched as written in the feed, which may be relative
+            Self::LinkRegex(re) => Ok((ctx.item.links.iter().map(|link| link.href.as_str()))
+                .chain(
+                    media_contents(ctx).filter_map(|content| Some(content.url.as_ref()?.as_str())),
+                )
+                .any(|url| re.is_match(url))),
+            Self::MediaType(re) => Ok((ctx.item.links.iter())
+                .filter_map(|link| link.media_type.as_deref())
+                .chain(
+                    media_contents(ctx)
+                        .filter_map(|content| Some(content.content_type.as_ref()?.as_str())),
+                )
+                .any(|media_type| re.is_match(media_type))),
+            Self::PublishedAfter {
+                since,
+                drop_undated,
+            } => Ok(match ctx.item.updated.or(ctx.item.published) {
+                Some(date) => date >= since.resolve(),
+                None => !drop_undated,
+            }),
         }
     }
 }
 
+fn media_contents<'a>(ctx: &FeedItemContext<'a>) -> impl Iterator<Item = &'a MediaContent> {
+    ctx.item.media.iter().flat_map(|media| &media.content)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{FeedGroup, Settings, TemplateSource};
-    use crate::feed::FeedItemContext;
+    use crate::config::{
+        DedupeBy, Delivery, FeedGroup, NoRecipients, Sanitize, Settings, TemplateSource, ThreadBy,
+        TruncationCheck, UndisclosedTo,
+    };
+    use crate::feed::{FeedItemContext, FeedSource};
     use blake3::hash;
+    use bytesize::ByteSize;
     use chrono::TimeDelta;
     use color_eyre::Result;
-    use feed_rs::model::{Content, Entry, Feed, FeedType, Text};
+    use feed_rs::model::{
+        Category, Content, Entry, Feed, FeedType, Link, MediaObject, Person, Text,
+    };
     use std::collections::BTreeMap;
     use std::sync::Arc;
     use std::time::Duration;
@@ -279,28 +728,83 @@ mod tests {
             criteria_hash: Hash::from_bytes([0; _]),
             urls,
             filter,
+            item_transform: None,
+            pin: Vec::new(),
+            priority: 0,
             settings: Settings {
+                from: None,
+                reply_to: None,
                 to: Vec::new().into(),
                 cc: Vec::new().into(),
                 bcc: Vec::new().into(),
+                undisclosed_to: UndisclosedTo::None,
+                personalize: false,
+                no_recipients: NoRecipients::MarkSeen,
                 digest: false,
+                digest_even_if_empty: false,
                 item_subject: Arc::new(item_subject),
                 digest_subject: Arc::new(TemplateSource::Inline("digest-subject".into())),
                 item_body: Arc::new(TemplateSource::Inline("item-body".into())),
                 digest_body: Arc::new(TemplateSource::Inline("digest-body".into())),
+                item_text_body: None,
+                digest_text_body: None,
+                text_body: false,
+                list_id: None,
+                list_unsubscribe: Vec::new().into(),
+                thread_by: ThreadBy::None,
+                delivery: Delivery::Email,
+                webhook_url: None,
                 template_args: Arc::new(Value::from_serialize(&template_args)),
                 update_keys: update_keys.into(),
+                fallback_update_keys: Vec::new().into(),
+                update_on_content_change: false,
+                update_on_updated_date: false,
+                dedupe_by: DedupeBy::UpdateKey,
+                dedupe_ignore_params: Vec::new().into(),
                 interval: TimeDelta::hours(1),
+                schedule: None,
+                respect_ttl: false,
                 keep_old: TimeDelta::weeks(1),
+                keep_max_items: None,
                 timeout: Duration::from_secs(30),
+                fetch_retries: 3,
+                retry_base_backoff: Duration::from_secs(1),
+                retry_max_backoff: Duration::from_mins(30),
+                max_body_size: ByteSize::mib(25),
                 max_mails_per_check: 5,
-                sanitize: true,
+                max_new_items_per_check: None,
+                seed_silently: false,
+                seed_silently_on_new_criteria: false,
+                full_text: false,
+                inline_images: false,
+                attach_source: false,
+                sanitize: Sanitize::All,
+                url_schemes: Vec::new().into(),
+                sanitize_allowed_tags: Vec::new().into(),
+                sanitize_allowed_attributes: Default::default(),
+                strip_images: false,
+                strip_tracking: false,
+                tracking_params: Vec::new().into(),
                 sort_by_last_modified: false,
+                truncation_check: TruncationCheck::Lenient,
+                strict_parse: false,
+                accept_compression: true,
+                max_redirects: 10,
+                user_agent: "yaf2m".into(),
                 http_headers: Default::default(),
+                basic_auth: None,
+                bearer_token: None,
+                proxy: None,
             },
+            shared_templates: Default::default(),
+            timezone: None,
         }
     }
 
+    fn sample_source() -> FeedSource {
+        FeedSource::new("https://example.com/rss", Some(200))
+    }
+
     fn sample_feed_and_item(id: &str, title: &str, summary: Option<&str>) -> (Feed, Entry) {
         let feed = Feed {
             feed_type: FeedType::RSS2,
@@ -355,6 +859,32 @@ mod tests {
         (feed, item)
     }
 
+    fn link(href: &str, media_type: Option<&str>) -> Link {
+        Link {
+            href: href.into(),
+            rel: None,
+            media_type: media_type.map(Into::into),
+            href_lang: None,
+            title: None,
+            length: None,
+        }
+    }
+
+    fn media(url: &str, content_type: &str) -> MediaObject {
+        MediaObject {
+            content: vec![MediaContent {
+                url: Some(url.parse().unwrap()),
+                content_type: Some(content_type.parse().unwrap()),
+                height: None,
+                width: None,
+                duration: None,
+                size: None,
+                rating: None,
+            }],
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn renders_item_template_with_globals_and_custom_test() -> Result<()> {
         let template = TemplateSource::Inline(
@@ -364,9 +894,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-1", "Rust", Some("Summary"));
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -375,6 +909,64 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn renders_item_source() -> Result<()> {
+        let template =
+            TemplateSource::Inline("{{ source.host }} {{ source.status }} {{ source.url }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("id", "Rust", None);
+        let source = FeedSource::new("https://blog.example.com/feed.xml", Some(200));
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(
+            rendered,
+            "blog.example.com 200 https://blog.example.com/feed.xml"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn evaluates_template_arg_expressions_at_render_time() -> Result<()> {
+        let template = TemplateSource::Inline(
+            "{{ template_args.greeting }} {{ template_args.display_name }}".into(),
+        );
+        let mut feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let mut template_args = BTreeMap::new();
+        template_args.insert("greeting", Value::from("Hello"));
+        template_args.insert(
+            "display_name",
+            Value::from_serialize(BTreeMap::from([(
+                "jinja-expr",
+                "item.title.content | upper",
+            )])),
+        );
+        feed_group.settings.template_args = Arc::new(Value::from_serialize(&template_args));
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("id", "Rust", None);
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(rendered, "Hello RUST");
+        Ok(())
+    }
+
     #[test]
     fn update_hash_uses_compiled_expressions_in_order() -> Result<()> {
         let feed_group = build_feed_group(
@@ -385,9 +977,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-42", "Title", Some("Body"));
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let expected = {
@@ -419,15 +1015,22 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, matching_item) = sample_feed_and_item("matchme", "Rustacean", Some("Body text"));
+        let source = sample_source();
         let matching_ctx = FeedItemContext {
             feed: &feed,
             item: &matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let (_, non_matching_item) = sample_feed_and_item("other", "Python", Some("Body text"));
         let non_matching_ctx = FeedItemContext {
             feed: &feed,
             item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(renderer.filter(&matching_ctx)?);
@@ -445,9 +1048,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("test-id", "Test Title", Some("Summary"));
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         // Test all template types to cover loader branches
@@ -467,6 +1074,79 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn item_body_extends_shared_template() -> Result<()> {
+        let mut feed_group = build_feed_group(
+            TemplateSource::Inline("item-subject".into()),
+            vec!["item.id".into()],
+            None,
+        );
+        feed_group.settings.item_body = Arc::new(TemplateSource::Inline(
+            r#"{% extends "base.html" %}{% block content %}{{ item.title.content }}{% endblock %}"#
+                .into(),
+        ));
+        feed_group.shared_templates = Arc::new(BTreeMap::from([
+            (
+                "base.html".to_string(),
+                TemplateSource::Inline(
+                    r#"<header>{% include "footer.html" %}</header>{% block content %}{% endblock %}"#
+                        .into(),
+                ),
+            ),
+            (
+                "footer.html".to_string(),
+                TemplateSource::Inline("{{ template_args.greeting }}".into()),
+            ),
+        ]));
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("test-id", "Test Title", Some("Summary"));
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+        assert_eq!(
+            renderer.render(TemplateName::ItemBody, ctx)?,
+            "<header>Hello</header>Test Title"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn formats_dates_in_configured_timezone() -> Result<()> {
+        let mut feed_group = build_feed_group(
+            TemplateSource::Inline(
+                r#"{{ 0 | datetimeformat(format="iso") }} {{ 0 | datetimeformat(format="iso", tz="UTC") }}"#.into(),
+            ),
+            vec!["item.id".into()],
+            None,
+        );
+        feed_group.timezone = Some("Asia/Shanghai".into());
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("test-id", "Test Title", Some("Summary"));
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+        assert_eq!(
+            renderer.render(TemplateName::ItemSubject, ctx)?,
+            "1970-01-01T08:00:00+08:00 1970-01-01T00:00:00+00:00"
+        );
+
+        assert!(check_timezone("Asia/Shanghai").is_ok());
+        assert!(check_timezone("Mars/Olympus_Mons").is_err());
+        Ok(())
+    }
+
     #[test]
     fn filter_or_returns_true_on_first_match() -> Result<()> {
         let filter = Filter::Or(vec![
@@ -482,9 +1162,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("id", "Match This", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(renderer.filter(&ctx)?);
@@ -506,9 +1190,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("id", "Different Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(!renderer.filter(&ctx)?);
@@ -527,15 +1215,22 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, matching_item) = sample_feed_and_item("id", "Normal Title", None);
+        let source = sample_source();
         let matching_ctx = FeedItemContext {
             feed: &feed,
             item: &matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let (_, skipped_item) = sample_feed_and_item("id2", "Skip This", None);
         let skipped_ctx = FeedItemContext {
             feed: &feed,
             item: &skipped_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(renderer.filter(&matching_ctx)?);
@@ -556,9 +1251,13 @@ mod tests {
 
         // Item with content body but no summary
         let (feed, item) = sample_feed_and_item("id", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         // Should match the content body which contains "<p>Body</p>"
@@ -578,15 +1277,136 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("id", "Title", Some("SummaryText here"));
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(renderer.filter(&ctx)?);
         Ok(())
     }
 
+    #[test]
+    fn custom_filters_work_in_update_keys_and_filter_expressions() -> Result<()> {
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.summary.content | capture('id=(\\\\d+)', 1)".into()],
+            Some(Filter::JinjaExpr(
+                "item.id | regex_replace('-\\\\d+$', '') == 'post'".into(),
+            )),
+        );
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("post-1", "Title", Some("Post id=7"));
+        let (_, repost) = sample_feed_and_item("post-2", "Title", Some("Repost id=7"));
+        let (_, other) = sample_feed_and_item("other-3", "Title", Some("Post id=8"));
+        let source = sample_source();
+        let ctx = |item| FeedItemContext {
+            feed: &feed,
+            item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let hash = renderer.update_hash(&ctx(&item))?;
+        assert_eq!(hash, renderer.update_hash(&ctx(&repost))?);
+        assert_ne!(hash, renderer.update_hash(&ctx(&other))?);
+
+        assert!(renderer.filter(&ctx(&item))?);
+        assert!(renderer.filter(&ctx(&repost))?);
+        assert!(!renderer.filter(&ctx(&other))?);
+        Ok(())
+    }
+
+    #[test]
+    fn update_hash_includes_content_only_when_enabled() -> Result<()> {
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            None,
+        );
+        let mut content_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            None,
+        );
+        content_group.settings.update_on_content_change = true;
+        let (feed, item) = sample_feed_and_item("item-42", "Title", Some("Summary"));
+        let (_, edited) = sample_feed_and_item("item-42", "Title", Some("Edited summary"));
+        let source = sample_source();
+        let ctx = |item| FeedItemContext {
+            feed: &feed,
+            item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // existing hashes are kept without the flag
+        let expected = {
+            let mut hasher = Hasher::new();
+            hasher.update(hash(b"item-42").as_bytes());
+            hasher.finalize()
+        };
+        let renderer = Renderer::from_feed(&feed_group)?;
+        assert_eq!(renderer.update_hash(&ctx(&item))?, expected);
+        assert_eq!(renderer.update_hash(&ctx(&edited))?, expected);
+
+        let renderer = Renderer::from_feed(&content_group)?;
+        let content_hash = renderer.update_hash(&ctx(&item))?;
+        assert_ne!(content_hash, expected);
+        assert_ne!(content_hash, renderer.update_hash(&ctx(&edited))?);
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_when_update_keys_are_empty() -> Result<()> {
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            None,
+        );
+        let mut fallback_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            None,
+        );
+        fallback_group.settings.fallback_update_keys = vec!["item.title.content".into()].into();
+        let (feed, first) = sample_feed_and_item("", "First", None);
+        let (_, second) = sample_feed_and_item("", "Second", None);
+        let (_, with_id) = sample_feed_and_item("item-42", "First", None);
+        let source = sample_source();
+        let ctx = |item| FeedItemContext {
+            feed: &feed,
+            item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // items without IDs collapse into one hash without fallback keys
+        let renderer = Renderer::from_feed(&feed_group)?;
+        assert!(renderer.update_keys_empty(&ctx(&first))?);
+        assert!(!renderer.update_keys_empty(&ctx(&with_id))?);
+        let hash = renderer.update_hash(&ctx(&first))?;
+        assert_eq!(hash, renderer.update_hash(&ctx(&second))?);
+        let id_hash = renderer.update_hash(&ctx(&with_id))?;
+
+        let renderer = Renderer::from_feed(&fallback_group)?;
+        assert_ne!(
+            renderer.update_hash(&ctx(&first))?,
+            renderer.update_hash(&ctx(&second))?
+        );
+        // items with IDs keep their hashes
+        assert_eq!(renderer.update_hash(&ctx(&with_id))?, id_hash);
+        Ok(())
+    }
+
     #[test]
     fn update_hash_handles_non_string_values() -> Result<()> {
         // Using numeric expressions to trigger the None branch (non-bytes conversion)
@@ -598,9 +1418,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("test", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let hash1 = renderer.update_hash(&ctx)?;
@@ -618,9 +1442,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-123", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -636,9 +1464,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-456", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -653,9 +1485,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-789", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -672,9 +1508,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-999", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -691,9 +1531,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-555", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -709,9 +1553,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("banana", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -719,6 +1567,172 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn markdown_renders_formatting() -> Result<()> {
+        let template = TemplateSource::Inline("{{ item.title.content | markdown }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("item-1", "**bold** and *em*", None);
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(
+            rendered.trim(),
+            "<p><strong>bold</strong> and <em>em</em></p>"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn markdown_strips_script() -> Result<()> {
+        let template = TemplateSource::Inline("{{ item.title.content | markdown }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item(
+            "item-1",
+            "Hello <script>alert(1)</script>\n\n<script>alert(2)</script>",
+            None,
+        );
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert!(rendered.contains("Hello"));
+        assert!(!rendered.contains("script"));
+        assert!(!rendered.contains("alert"));
+        Ok(())
+    }
+
+    #[test]
+    fn striptags_decodes_entities() -> Result<()> {
+        let template = TemplateSource::Inline("{{ item.title.content | striptags }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item(
+            "item-1",
+            "Tom &amp; Jerry&nbsp;&mdash; &#8220;Part&#x20;2&#8221;&hellip;",
+            None,
+        );
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(rendered, "Tom & Jerry — “Part 2”…");
+        Ok(())
+    }
+
+    #[test]
+    fn striptags_removes_nested_tags() -> Result<()> {
+        let template = TemplateSource::Inline("{{ item.title.content | striptags }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item(
+            "item-1",
+            "<p>New <b>release <i>v1.0</i></b><!-- comment --> is <a href=\"#\">out</a></p>",
+            None,
+        );
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(rendered, "New release v1.0 is out");
+        Ok(())
+    }
+
+    #[test]
+    fn regex_split_returns_segments() -> Result<()> {
+        let template =
+            TemplateSource::Inline("Last: {{ item.id | regex_split('/') | last }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("https://example.com/posts/42", "Title", None);
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(rendered, "Last: 42");
+        Ok(())
+    }
+
+    #[test]
+    fn regex_split_with_multiple_delimiters() -> Result<()> {
+        let template =
+            TemplateSource::Inline("{{ item.id | regex_split('[-_/]+') | join(',') }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("a-b__c/-d", "Title", None);
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(rendered, "a,b,c,d");
+        Ok(())
+    }
+
+    #[test]
+    fn regex_split_on_empty_match() -> Result<()> {
+        let template = TemplateSource::Inline("{{ item.id | regex_split('') | join(',') }}".into());
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, item) = sample_feed_and_item("abc", "Title", None);
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(rendered, ",a,b,c,");
+        Ok(())
+    }
+
     #[test]
     fn regex_is_match_test() -> Result<()> {
         let template = TemplateSource::Inline(
@@ -728,9 +1742,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-123", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -747,9 +1765,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("notanumber", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
@@ -766,9 +1788,13 @@ mod tests {
         let renderer = Renderer::from_feed(&feed_group)?;
 
         let (feed, item) = sample_feed_and_item("item-1", "Title", None);
+        let source = sample_source();
         let ctx = FeedItemContext {
             feed: &feed,
             item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let result = renderer.render(TemplateName::ItemSubject, ctx);
@@ -789,9 +1815,13 @@ mod tests {
 
         let (feed, matching_item) =
             sample_feed_and_item("id", "Rust Programming", Some("Rust in summary"));
+        let source = sample_source();
         let matching_ctx = FeedItemContext {
             feed: &feed,
             item: &matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         let (_, non_matching_item) =
@@ -799,6 +1829,9 @@ mod tests {
         let non_matching_ctx = FeedItemContext {
             feed: &feed,
             item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(renderer.filter(&matching_ctx)?);
@@ -819,9 +1852,13 @@ mod tests {
 
         // Test matching summary
         let (feed, summary_item) = sample_feed_and_item("id1", "Title", Some("important info"));
+        let source = sample_source();
         let summary_ctx = FeedItemContext {
             feed: &feed,
             item: &summary_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         // Test non-matching title (should not match)
@@ -830,6 +1867,9 @@ mod tests {
         let title_ctx = FeedItemContext {
             feed: &feed,
             item: &title_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         // Test non-matching item
@@ -837,6 +1877,9 @@ mod tests {
         let non_matching_ctx = FeedItemContext {
             feed: &feed,
             item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(renderer.filter(&summary_ctx)?);
@@ -858,9 +1901,13 @@ mod tests {
 
         // Test matching title
         let (feed, title_item) = sample_feed_and_item("id1", "search term", Some("summary"));
+        let source = sample_source();
         let title_ctx = FeedItemContext {
             feed: &feed,
             item: &title_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         // Test matching summary
@@ -868,6 +1915,9 @@ mod tests {
         let summary_ctx = FeedItemContext {
             feed: &feed,
             item: &summary_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         // Test non-matching item
@@ -875,6 +1925,9 @@ mod tests {
         let non_matching_ctx = FeedItemContext {
             feed: &feed,
             item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
         };
 
         assert!(renderer.filter(&title_ctx)?);
@@ -882,4 +1935,390 @@ mod tests {
         assert!(!renderer.filter(&non_matching_ctx)?);
         Ok(())
     }
+
+    #[test]
+    fn filter_category_matches_term_and_label() -> Result<()> {
+        let filter = Filter::Category("^(rust|release)$".into());
+
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            Some(filter),
+        );
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let category = |term: &str, label: Option<&str>| Category {
+            term: term.into(),
+            scheme: None,
+            label: label.map(Into::into),
+            subcategories: Vec::new(),
+        };
+
+        // Test matching term
+        let (feed, mut term_item) = sample_feed_and_item("id1", "Title", None);
+        let source = sample_source();
+        term_item.categories = vec![category("go", None), category("rust", None)];
+        let term_ctx = FeedItemContext {
+            feed: &feed,
+            item: &term_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test matching label
+        let (_, mut label_item) = sample_feed_and_item("id2", "Title", None);
+        label_item.categories = vec![category("tag-42", Some("release"))];
+        let label_ctx = FeedItemContext {
+            feed: &feed,
+            item: &label_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test non-matching categories
+        let (_, mut non_matching_item) = sample_feed_and_item("id3", "rust", None);
+        non_matching_item.categories = vec![category("rustacean", Some("Rust news"))];
+        let non_matching_ctx = FeedItemContext {
+            feed: &feed,
+            item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test item without categories
+        let (_, uncategorized_item) = sample_feed_and_item("id4", "rust", Some("rust"));
+        let uncategorized_ctx = FeedItemContext {
+            feed: &feed,
+            item: &uncategorized_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        assert!(renderer.filter(&term_ctx)?);
+        assert!(renderer.filter(&label_ctx)?);
+        assert!(!renderer.filter(&non_matching_ctx)?);
+        assert!(!renderer.filter(&uncategorized_ctx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_author_matches_item_or_feed_authors() -> Result<()> {
+        let filter = Filter::Author("^Alice".into());
+
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            Some(filter),
+        );
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let person = |name: &str| Person {
+            name: name.into(),
+            uri: None,
+            email: None,
+        };
+
+        // Test matching item author
+        let (feed, mut matching_item) = sample_feed_and_item("id1", "Title", None);
+        let source = sample_source();
+        matching_item.authors = vec![person("Bob"), person("Alice Smith")];
+        let matching_ctx = FeedItemContext {
+            feed: &feed,
+            item: &matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test non-matching item author
+        let (_, mut non_matching_item) = sample_feed_and_item("id2", "Alice", None);
+        non_matching_item.authors = vec![person("Bob Alice")];
+        let non_matching_ctx = FeedItemContext {
+            feed: &feed,
+            item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test item without authors, in a feed without authors
+        let (_, anonymous_item) = sample_feed_and_item("id3", "Title", None);
+        let anonymous_ctx = FeedItemContext {
+            feed: &feed,
+            item: &anonymous_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        assert!(renderer.filter(&matching_ctx)?);
+        assert!(!renderer.filter(&non_matching_ctx)?);
+        assert!(!renderer.filter(&anonymous_ctx)?);
+
+        // Test falling back to feed authors
+        let (mut feed, anonymous_item) = sample_feed_and_item("id3", "Title", None);
+        let source = sample_source();
+        feed.authors = vec![person("Alice")];
+        let fallback_ctx = FeedItemContext {
+            feed: &feed,
+            item: &anonymous_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+        assert!(renderer.filter(&fallback_ctx)?);
+
+        // Test item authors taking precedence over feed authors
+        let (_, mut other_item) = sample_feed_and_item("id4", "Title", None);
+        other_item.authors = vec![person("Bob")];
+        let other_ctx = FeedItemContext {
+            feed: &feed,
+            item: &other_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+        assert!(!renderer.filter(&other_ctx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_link_regex_matches_links_and_media() -> Result<()> {
+        let filter = Filter::LinkRegex(r"^https://(www\.)?youtube\.com/|\.pdf$".into());
+
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            Some(filter),
+        );
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        // Test matching link, and relative links
+        let (feed, mut link_item) = sample_feed_and_item("id1", "Title", None);
+        let source = sample_source();
+        link_item.links = vec![
+            link("../posts/1", None),
+            link("https://www.youtube.com/watch?v=1", None),
+        ];
+        let link_ctx = FeedItemContext {
+            feed: &feed,
+            item: &link_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test matching media
+        let (_, mut media_item) = sample_feed_and_item("id2", "Title", None);
+        media_item.media = vec![media("https://example.com/paper.pdf", "application/pdf")];
+        let media_ctx = FeedItemContext {
+            feed: &feed,
+            item: &media_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test non-matching links
+        let (_, mut non_matching_item) = sample_feed_and_item("id3", "Title", None);
+        non_matching_item.links = vec![link("/paper.pdf.html", None)];
+        let non_matching_ctx = FeedItemContext {
+            feed: &feed,
+            item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        // Test item without links
+        let (_, no_link_item) = sample_feed_and_item("id4", "youtube.com", None);
+        let no_link_ctx = FeedItemContext {
+            feed: &feed,
+            item: &no_link_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        assert!(renderer.filter(&link_ctx)?);
+        assert!(renderer.filter(&media_ctx)?);
+        assert!(!renderer.filter(&non_matching_ctx)?);
+        assert!(!renderer.filter(&no_link_ctx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_media_type_matches_links_and_media() -> Result<()> {
+        let filter = Filter::MediaType("^video/".into());
+
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            Some(filter),
+        );
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, mut link_item) = sample_feed_and_item("id1", "Title", None);
+        let source = sample_source();
+        link_item.links = vec![link("https://example.com/1.mp4", Some("video/mp4"))];
+        let link_ctx = FeedItemContext {
+            feed: &feed,
+            item: &link_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let (_, mut media_item) = sample_feed_and_item("id2", "Title", None);
+        media_item.media = vec![media("https://example.com/1.webm", "video/webm")];
+        let media_ctx = FeedItemContext {
+            feed: &feed,
+            item: &media_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        let (_, mut non_matching_item) = sample_feed_and_item("id3", "Title", None);
+        non_matching_item.links = vec![link("https://example.com/1.mp4", None)];
+        non_matching_item.media = vec![media("https://example.com/1.mp3", "audio/mpeg")];
+        let non_matching_ctx = FeedItemContext {
+            feed: &feed,
+            item: &non_matching_item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+
+        assert!(renderer.filter(&link_ctx)?);
+        assert!(renderer.filter(&media_ctx)?);
+        assert!(!renderer.filter(&non_matching_ctx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_published_after_compares_item_date() -> Result<()> {
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            Some(Filter::PublishedAfter {
+                since: "30days".into(),
+                drop_undated: false,
+            }),
+        );
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, mut recent_item) = sample_feed_and_item("id1", "Title", None);
+        let source = sample_source();
+        recent_item.published = Some(Utc::now() - TimeDelta::days(1));
+        let (_, mut old_item) = sample_feed_and_item("id2", "Title", None);
+        old_item.published = Some(Utc::now() - TimeDelta::days(60));
+        // the update time takes precedence over the publish time
+        let (_, mut updated_item) = sample_feed_and_item("id3", "Title", None);
+        updated_item.published = Some(Utc::now() - TimeDelta::days(60));
+        updated_item.updated = Some(Utc::now() - TimeDelta::days(1));
+        let (_, undated_item) = sample_feed_and_item("id4", "Title", None);
+
+        let ctx = |item| FeedItemContext {
+            feed: &feed,
+            item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+        assert!(renderer.filter(&ctx(&recent_item))?);
+        assert!(!renderer.filter(&ctx(&old_item))?);
+        assert!(renderer.filter(&ctx(&updated_item))?);
+        assert!(renderer.filter(&ctx(&undated_item))?);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_published_after_accepts_datetime_and_drops_undated() -> Result<()> {
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            Some(Filter::PublishedAfter {
+                since: "2025-01-01T00:00:00+08:00".into(),
+                drop_undated: true,
+            }),
+        );
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        let (feed, mut new_item) = sample_feed_and_item("id1", "Title", None);
+        let source = sample_source();
+        new_item.published = Some("2024-12-31T17:00:00Z".parse()?);
+        let (_, mut old_item) = sample_feed_and_item("id2", "Title", None);
+        old_item.published = Some("2024-12-31T15:00:00Z".parse()?);
+        let (_, undated_item) = sample_feed_and_item("id3", "Title", None);
+
+        let ctx = |item| FeedItemContext {
+            feed: &feed,
+            item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+        assert!(renderer.filter(&ctx(&new_item))?);
+        assert!(!renderer.filter(&ctx(&old_item))?);
+        assert!(!renderer.filter(&ctx(&undated_item))?);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_published_after_rejects_invalid_bound() {
+        let feed_group = build_feed_group(
+            TemplateSource::Inline("unused".into()),
+            vec!["item.id".into()],
+            Some(Filter::PublishedAfter {
+                since: "last week".into(),
+                drop_undated: false,
+            }),
+        );
+        assert!(Renderer::from_feed(&feed_group).is_err());
+    }
+
+    #[test]
+    fn normalize_url_is_stable_across_migrations() -> Result<()> {
+        let template = TemplateSource::Inline(
+            "{{ item.id | normalize_url }} {{ item.id | normalize_url(host=false) }}".into(),
+        );
+        let feed_group = build_feed_group(template, vec!["item.id".into()], None);
+        let renderer = Renderer::from_feed(&feed_group)?;
+
+        for id in [
+            "http://Example.com:80/posts/1/?a=b",
+            "https://example.com/posts/1?a=b",
+            "https://EXAMPLE.com:443/posts/1//?a=b",
+        ] {
+            let (feed, item) = sample_feed_and_item(id, "Title", None);
+            let source = sample_source();
+            let ctx = FeedItemContext {
+                feed: &feed,
+                item: &item,
+                source: &source,
+                item_first_seen: None,
+                previous: None,
+            };
+            let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+            assert_eq!(rendered, "https://example.com/posts/1?a=b /posts/1?a=b");
+        }
+
+        let (feed, item) = sample_feed_and_item("tag:example.com,2025:1/", "Title", None);
+        let source = sample_source();
+        let ctx = FeedItemContext {
+            feed: &feed,
+            item: &item,
+            source: &source,
+            item_first_seen: None,
+            previous: None,
+        };
+        let rendered = renderer.render(TemplateName::ItemSubject, ctx)?;
+        assert_eq!(rendered, "tag:example.com,2025:1/ tag:example.com,2025:1/");
+        Ok(())
+    }
 }
@@ -1,76 +1,277 @@
-use crate::config::{FeedGroup, load_config};
-use crate::db::{self, FeedStatus};
-use crate::email::{Mail, Mailer, send_email_with_backoff};
-use crate::feed::fetch_feed;
-use crate::render::{Renderer, TemplateName};
+use crate::config::{
+    Config, DedupeBy, DiscoveryCache, DiscoveryFailed, ErrorReportTo, FeedGroup, NoRecipients,
+    Settings, ThreadBy, config_files, load_config,
+};
+use crate::db::{self, Failure, FeedStatus, Pool};
+use crate::email::{Mail, MailHeaders, Mailer, Thread, send_email_with_backoff};
+use crate::feed::{
+    FeedItemContext, FetchOutcome, FetchedFeed, PreviousItem, fetch_feed, fetch_full_text,
+    param_matches, post_webhook,
+};
+use crate::health::{Health, serve_health};
+use crate::images::ImageInliner;
+use crate::render::{Renderer, TIMEZONE_GLOBAL, TemplateName};
 use blake3::{Hash, Hasher};
-use chrono::{TimeDelta, Utc};
-use color_eyre::Result;
-use color_eyre::eyre::WrapErr;
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use color_eyre::eyre::{WrapErr, bail, eyre};
+use color_eyre::{Report, Result};
+use cron::Schedule;
+use feed_rs::model::Entry;
+use futures::{StreamExt, stream};
 use lettre::message::Mailbox;
-use minijinja::{Environment, render};
+use lettre::message::header::ContentType;
+use minijinja::{Environment, Value, render};
 use minijinja_contrib::add_to_environment;
+use reqwest::Url;
 use serde::Serialize;
-use sqlx::PgPool;
 use std::cmp::Reverse;
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{Semaphore, watch};
 use tokio::task::JoinSet;
 
+const TEXT_BODY_WIDTH: usize = 80;
+const MAX_CONCURRENT_FULL_TEXT: usize = 4;
+
 pub struct Worker {
-    pool: PgPool,
+    pool: Pool,
     config_path: PathBuf,
     mailer: Mailer,
+    /// Feed groups warned about holding their items since the config was loaded.
+    held_feeds: Mutex<HashSet<Hash>>,
 }
 
 impl Worker {
-    pub fn new<P: Into<PathBuf>>(pool: PgPool, config_path: P, mailer: Mailer) -> Self {
+    pub fn new<P: Into<PathBuf>>(pool: Pool, config_path: P, mailer: Mailer) -> Self {
         Self {
             pool,
             config_path: config_path.into(),
             mailer,
+            held_feeds: Mutex::default(),
         }
     }
 
-    pub async fn run(self) -> Result<()> {
+    /// Runs the worker until a shutdown signal, or for a single cycle if `once` is set.
+    pub async fn run(self, once: bool) -> Result<()> {
         let this = Arc::new(self);
         let mut feeds = Vec::new();
         let mut feed_map = HashMap::new();
         let mut feed_hashes = Vec::new();
         let mut keep_old = TimeDelta::default();
+        let mut poll_interval = Duration::default();
+        let mut poll_jitter = Duration::ZERO;
+        let mut max_concurrent_feeds = 0;
+        let mut semaphore = Arc::new(Semaphore::new(0));
         let mut last_modified = SystemTime::UNIX_EPOCH;
+        let mut last_config_hash = None;
+        let mut retry_discovery = false;
         let mut failure_tracker = FailureTracker::new();
+        failure_tracker.restore(&this.pool).await;
+        let mut discovery_cache = DiscoveryCache::default();
+        let health = Arc::new(Health::new());
+        let mut health_addr = None;
+
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            log::info!("Shutdown signal received, finishing in-flight feed groups");
+            shutdown_tx.send_replace(true);
+        });
+
+        let (reload_tx, mut reload_rx) = watch::channel(());
+        tokio::spawn(reload_signal(reload_tx));
+        let mut reload_requested = false;
 
         loop {
-            let modified = tokio::fs::metadata(&this.config_path)
-                .await
-                .wrap_err("failed to get config file metadata")?
-                .modified()?;
-            if modified != last_modified {
-                let config = load_config(&this.config_path).await?;
-                log::info!("Config file update reloaded");
-                feeds = config.feeds.into_iter().map(Arc::new).collect();
-                feed_map = feeds.iter().map(|feed| (feed.urls_hash, feed)).collect();
-                feed_hashes = feeds
-                    .iter()
-                    .map(|feed| feed.urls_hash.as_bytes().to_vec())
-                    .collect();
-                keep_old = config.global_settings.keep_old;
-                failure_tracker.set_report_to(config.error_report_to);
+            let (modified, config_hash) = match config_version(&this.config_path).await {
+                Ok(version) => version,
+                Err(e) => match last_config_hash {
+                    // there is no previous config to keep running
+                    None => return Err(e),
+                    // e.g. a file removed while the directory is being updated
+                    Some(last_config_hash) => {
+                        log::error!("Failed to read config, keeping the previous one: {e:#}");
+                        (last_modified, last_config_hash)
+                    }
+                },
+            };
+            // some editors and bind mounts keep the mtime, so the content is compared as well
+            reload_requested |= reload_rx.has_changed().unwrap_or(false);
+            let reload_trigger = if reload_requested {
+                Some("SIGHUP")
+            } else if last_config_hash.is_none() {
+                Some("startup")
+            } else if modified != last_modified {
+                Some("modification time changed")
+            } else if last_config_hash != Some(config_hash) {
+                Some("content changed")
+            } else if retry_discovery {
+                Some("retrying discovery")
+            } else {
+                None
+            };
+            if let Some(trigger) = reload_trigger {
+                reload_rx.mark_unchanged();
+                reload_requested = false;
+                retry_discovery = false;
+                log::info!("Loading config ({trigger})");
+                let loaded = load_checked_config(&this.config_path, &mut discovery_cache).await;
+                let startup = last_config_hash.is_none();
+                health.record_reload(loaded.is_ok(), config_hash);
+                // an invalid config is not loaded again until it changes, unless discovery failed
                 last_modified = modified;
+                last_config_hash = Some(config_hash);
+                match loaded {
+                    Ok(config) => {
+                        log_config_diff(&feeds, &config.feeds);
+                        this.held_feeds
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .clear();
+                        feeds = config.feeds.into_iter().map(Arc::new).collect();
+                        feeds.sort_by_key(|feed| processing_order(feed));
+                        feed_map = feeds.iter().map(|feed| (feed.urls_hash, feed)).collect();
+                        feed_hashes = feeds
+                            .iter()
+                            .map(|feed| feed.urls_hash.as_bytes().to_vec())
+                            .collect();
+                        if config.prune_removed_feeds {
+                            db::prune_removed_feeds(&this.pool, &feed_hashes)
+                                .await
+                                .inspect_err(|e| {
+                                    log::error!("Failed to delete removed feed groups: {e:?}");
+                                })
+                                .ok();
+                        }
+                        keep_old = config.global_settings.keep_old;
+                        poll_interval = config.poll_interval;
+                        poll_jitter = config.poll_jitter;
+                        health.set_stale_after(config.health_stale_after);
+                        if health_addr.is_none() {
+                            if let Some(addr) = config.health_addr {
+                                match serve_health(addr, Arc::clone(&health)).await {
+                                    Ok(()) => health_addr = Some(addr),
+                                    Err(e) if startup => return Err(e),
+                                    // e.g. the port is taken, which is retried on the next reload
+                                    Err(e) => log::error!(
+                                        "Failed to serve the health check endpoint: {e:?}"
+                                    ),
+                                }
+                            }
+                        } else if config.health_addr != health_addr {
+                            log::warn!("Changing health-addr requires a restart");
+                        }
+                        // a feed group holds the SQLite write lock until it is done, so others
+                        // would only wait for it
+                        let concurrent_feeds = if this.pool.is_sqlite() {
+                            1
+                        } else {
+                            config.max_concurrent_feeds
+                        };
+                        if concurrent_feeds != max_concurrent_feeds {
+                            max_concurrent_feeds = concurrent_feeds;
+                            semaphore = Arc::new(Semaphore::new(max_concurrent_feeds));
+                        }
+                        failure_tracker.set_report_to(
+                            config.error_report_to,
+                            config.error_report_debounce,
+                            config.error_report_recovery,
+                        );
+                        failure_tracker.set_timezone(config.timezone.as_deref());
+                    }
+                    // there is no previous config to keep running
+                    Err(e) if startup => return Err(e),
+                    // the index may be back on the next cycle, so this is not a config error
+                    Err(e) if e.downcast_ref::<DiscoveryFailed>().is_some() => {
+                        log::error!(
+                            "Failed to load config, keeping the previous one until the next \
+                             cycle: {e:#}"
+                        );
+                        retry_discovery = true;
+                    }
+                    Err(e) => {
+                        log::error!("Invalid config, keeping the previous one: {e:#}");
+                        if let Err(e) = failure_tracker.send_config_error(&e, &this.mailer).await {
+                            log::error!("Failed to send config error report: {e:?}");
+                        }
+                    }
+                }
             }
 
             let mut set = JoinSet::new();
 
             for feed in feeds.iter().map(Arc::clone) {
                 let worker = Arc::clone(&this);
+                // feed groups that are not due return at once, so they are not delayed
+                let delay = if poll_jitter.is_zero()
+                    || is_feed_group_waiting(&this.pool, &feed)
+                        .await
+                        .unwrap_or(false)
+                {
+                    Duration::ZERO
+                } else {
+                    jitter_delay(poll_jitter, feed.settings.interval)
+                };
+                // acquire before spawning to start feeds in priority order
+                // the permit is dropped when the task finishes, even if it panics
+                // no more feed groups are started after a shutdown signal
+                let permit = if delay.is_zero() {
+                    tokio::select! {
+                        biased;
+                        _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
+                        permit = Arc::clone(&semaphore).acquire_owned() => {
+                            Some(permit.wrap_err("feed semaphore closed")?)
+                        }
+                    }
+                } else {
+                    None
+                };
+                let semaphore = Arc::clone(&semaphore);
+                let mut shutdown_rx = shutdown_rx.clone();
                 set.spawn(async move {
-                    if let Err(e) = worker.process_feed(&feed).await {
-                        log::warn!("Error processing feed group {:?}: {e}", feed.urls);
+                    // jittered feeds do not hold a permit while sleeping
+                    let _permit = match permit {
+                        Some(permit) => permit,
+                        None => {
+                            tokio::select! {
+                                () = tokio::time::sleep(delay) => {}
+                                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => return,
+                            }
+                            let permit = tokio::select! {
+                                biased;
+                                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => return,
+                                permit = semaphore.acquire_owned() => permit,
+                            };
+                            let Ok(permit) = permit else {
+                                log::error!("Feed semaphore closed");
+                                return;
+                            };
+                            permit
+                        }
+                    };
+                    if *shutdown_rx.borrow() {
+                        return;
+                    }
+                    let started = Instant::now();
+                    let result = worker.process_feed(&feed).await;
+                    let elapsed = started.elapsed();
+                    log::debug!(
+                        urls_hash:% = feed.urls_hash,
+                        duration_ms = elapsed.as_millis();
+                        "Feed group {:?} processed in {elapsed:?}",
+                        feed.urls
+                    );
+                    if let Err(e) = result {
+                        log::warn!(
+                            urls_hash:% = feed.urls_hash;
+                            "Error processing feed group {:?}: {e}",
+                            feed.urls
+                        );
                         log::debug!("Error details: {}", format!("{e:?}").replace('\n', "\\n"));
-                        match db::is_feed_group_waiting(&worker.pool, &feed).await {
+                        match is_feed_group_waiting(&worker.pool, &feed).await {
                             Err(e) => log::error!(
                                 "Failed to check if feed group {:?} is waiting: {e:?}",
                                 feed.urls
@@ -97,26 +298,36 @@ impl Worker {
                 }
             }
 
+            if *shutdown_rx.borrow() {
+                log::info!("Worker shut down gracefully");
+                return Ok(());
+            }
+
             match db::get_failing_feeds(&this.pool).await {
                 Ok(failures) => {
-                    let failures = failures
+                    let mut failures = failures
                         .into_iter()
-                        .filter_map(|(urls_hash, error)| {
+                        .filter_map(|failure| {
                             feed_map
-                                .get(&urls_hash)
-                                .map(|feed| (Arc::clone(feed), error))
+                                .get(&failure.urls_hash)
+                                .map(|feed| (Arc::clone(feed), failure))
                         })
                         .collect::<Vec<_>>();
+                    failures.sort_by_key(|(feed, _)| processing_order(feed));
                     log::log!(
                         if failures.is_empty() {
                             log::Level::Debug
                         } else {
                             log::Level::Warn
                         },
+                        failing = failures.len();
                         "{} feeds are failing",
                         failures.len()
                     );
-                    failure_tracker.record(failures, &this.mailer).await;
+                    health.set_failing_feeds(failures.len());
+                    failure_tracker
+                        .record(failures, &this.mailer, &this.pool)
+                        .await;
                 }
                 Err(e) => log::error!("Failed to get failing feeds: {e:?}"),
             }
@@ -135,9 +346,27 @@ impl Worker {
                 })
                 .ok();
 
-            log::debug!("Worker cycle completed, sleeping for 1 minute");
+            health.record_cycle();
+
+            if once {
+                log::info!("Worker cycle completed, exiting");
+                return Ok(());
+            }
+
+            log::debug!(
+                "Worker cycle completed, sleeping for {}",
+                humantime::format_duration(poll_interval)
+            );
 
-            tokio::time::sleep(Duration::from_mins(1)).await;
+            tokio::select! {
+                () = tokio::time::sleep(poll_interval) => {}
+                // disabled if SIGHUP cannot be listened for
+                Ok(()) = reload_rx.changed() => reload_requested = true,
+                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => {
+                    log::info!("Worker shut down gracefully");
+                    return Ok(());
+                }
+            }
         }
     }
 
@@ -148,32 +377,134 @@ impl Worker {
 
         let mut tx = self.pool.begin().await?;
 
-        let status = db::try_check_feed_group(&mut *tx, feed_group).await?;
-        log::debug!("Feed group {:?} status: {status:?}", feed_group.urls);
+        let update_cutoff = update_cutoff(&mut tx, feed_group).await?;
+        let status = db::try_check_feed_group(&mut tx, feed_group, update_cutoff).await?;
 
         if status == FeedStatus::Wait {
+            if log::log_enabled!(log::Level::Debug) {
+                let next_check = next_check_time(&mut tx, feed_group).await?;
+                log::debug!(
+                    "Feed group {:?} waiting, next check due at {}",
+                    feed_group.urls,
+                    next_check.map_or_else(|| "unknown".into(), |time| time.to_rfc3339())
+                );
+            }
             return Ok(());
         }
 
+        if lacks_recipients(&feed_group.settings, status) {
+            match feed_group.settings.no_recipients {
+                // warned when sending the mails
+                NoRecipients::MarkSeen => {}
+                NoRecipients::Hold => {
+                    // the check is not recorded, so it is held again on every cycle
+                    let first_hold = self
+                        .held_feeds
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(feed_group.urls_hash);
+                    log::log!(
+                        if first_hold {
+                            log::Level::Warn
+                        } else {
+                            log::Level::Debug
+                        },
+                        urls_hash:% = feed_group.urls_hash;
+                        "No recipients specified for feed group {:?}, new items are held until \
+                         recipients are added",
+                        feed_group.urls
+                    );
+                    // rolled back, so that a pending new feed or new criteria status is kept
+                    drop(tx);
+                    return Ok(());
+                }
+                NoRecipients::Error => bail!("No recipients specified"),
+            }
+        }
+        log::debug!("Feed group {:?} status: {status:?}", feed_group.urls);
+
+        // left by an earlier check, and posted before anything new so that they are not lost
+        post_pending_webhooks((&mut tx).into(), feed_group).await?;
+
         let renderer = Renderer::from_feed(feed_group)?;
 
-        let mut all_feeds = Vec::new();
+        // new feeds and criteria must be checked even if the feed content is unchanged, and so must
+        // feeds sending a digest on every check, which needs the full content
+        let use_cache = status == FeedStatus::Update && !feed_group.settings.digest_even_if_empty;
+
+        let fetch_started = Instant::now();
+        let mut outcomes = Vec::new();
+        let mut any_changed = false;
 
         // reverse order to prioritize earlier URLs
         // otherwise, if the feeds update during fetching, later URLs may override earlier ones
         for url in feed_group.urls.iter().rev() {
-            let feed = fetch_feed(url, &feed_group.settings)
+            let cache = if use_cache {
+                db::get_feed_cache(&mut tx, feed_group.urls_hash, url).await?
+            } else {
+                None
+            };
+            let outcome = fetch_feed(url, &feed_group.settings, cache.as_ref())
                 .await
                 .wrap_err_with(|| format!("failed to fetch feed from {url}"))?;
+            if let FetchOutcome::Fetched {
+                cache, unchanged, ..
+            } = &outcome
+            {
+                any_changed |= !unchanged;
+                db::set_feed_cache(&mut tx, feed_group.urls_hash, url, cache).await?;
+            }
+            outcomes.push((url, outcome));
+        }
+
+        if !any_changed {
+            log::debug!("Feed group {:?} not modified", feed_group.urls);
+            db::clear_failure(&mut tx, feed_group.urls_hash).await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+
+        let mut all_feeds = Vec::new();
+
+        for (url, outcome) in outcomes.into_iter().rev() {
+            let feed = match outcome {
+                FetchOutcome::Fetched { feed, .. } => feed,
+                // other feeds in the group changed, so the full content is needed
+                FetchOutcome::NotModified => match fetch_feed(url, &feed_group.settings, None)
+                    .await
+                    .wrap_err_with(|| format!("failed to fetch feed from {url}"))?
+                {
+                    FetchOutcome::Fetched { feed, cache, .. } => {
+                        db::set_feed_cache(&mut tx, feed_group.urls_hash, url, &cache).await?;
+                        feed
+                    }
+                    FetchOutcome::NotModified => {
+                        bail!("unexpected 304 Not Modified from {url} without validators")
+                    }
+                },
+            };
             log::trace!("Fetched feed from {url}: {:?}", feed.borrow_feed());
-            all_feeds.push(feed);
+            all_feeds.push(transform_items(feed_group, &renderer, feed)?);
         }
-        all_feeds.reverse();
 
-        let mut new_items = Vec::new();
+        let fetch_duration = fetch_started.elapsed();
+
+        let advertised_interval = all_feeds
+            .iter()
+            .filter_map(|feed| *feed.borrow_advertised_interval())
+            .max();
+        db::set_advertised_interval(&mut tx, feed_group.urls_hash, advertised_interval).await?;
+
+        let checked_at = Utc::now();
+        let mut items_seen = 0;
+        let mut recorded_items = Vec::new();
+        let mut dedupe = Dedupe::new(&feed_group.settings);
+        let mut hashed_items = 0;
+        let mut empty_key_items = 0;
 
         for item in all_feeds.iter().flat_map(|feed| feed.borrow_items()) {
-            if !renderer.filter(item)? {
+            items_seen += 1;
+            if !filter_item(feed_group, &renderer, item)? {
                 log::trace!(
                     "Item filtered out:\n{}",
                     render!("{{ item }}", item => item.item)
@@ -182,9 +513,33 @@ impl Worker {
             }
 
             let update_hash = renderer.update_hash(item)?;
+            let first = dedupe.is_first(item, update_hash);
+            hashed_items += 1;
+            if feed_group.settings.fallback_update_keys.is_empty()
+                && renderer.update_keys_empty(item)?
+            {
+                empty_key_items += 1;
+            }
 
-            let new =
-                db::upsert_and_check_item_new(&mut *tx, feed_group.urls_hash, update_hash).await?;
+            let item_key = blake3::hash(item.item.id.as_bytes());
+            let title = item
+                .item
+                .title
+                .as_ref()
+                .map_or("", |title| title.content.as_str());
+            // checked before recording, which overwrites the date
+            let advanced = updated_date_advanced(&mut tx, feed_group, item, item_key).await?;
+            // duplicates are still recorded, so that they are not sent when the first one is gone
+            let recorded = db::upsert_and_check_item_new(
+                &mut tx,
+                feed_group.urls_hash,
+                update_hash,
+                item_key,
+                title,
+                item.item.updated,
+            )
+            .await?;
+            let new = advanced.unwrap_or(recorded.new);
 
             log::trace!(
                 "hash: {}, new: {}, item:\n{}",
@@ -193,209 +548,2050 @@ impl Worker {
                 render!("{{ item }}", item => item.item)
             );
 
-            if new {
-                new_items.push(item);
+            if new && first {
+                recorded_items.push((item, recorded));
+            } else if new {
+                log::debug!(
+                    "Feed group {:?}: duplicate item {:?} skipped",
+                    feed_group.urls,
+                    item.item.id
+                );
             }
         }
 
-        if feed_group.settings.sort_by_last_modified {
-            new_items.sort_by_key(|item| Reverse(item.item.updated.or(item.item.published)));
+        // all items share one hash, so only the first one is ever sent
+        if hashed_items > 1 && empty_key_items == hashed_items {
+            log::warn!(
+                urls_hash:% = feed_group.urls_hash;
+                "Feed group {:?}: update-keys are empty for all {hashed_items} items, so they are \
+                 treated as the same item. Set update-keys to something that identifies items, \
+                 e.g. 'item.links[0].href', or set fallback-update-keys",
+                feed_group.urls,
+            );
         }
 
+        let new_items = recorded_items
+            .iter()
+            .map(|(item, recorded)| FeedItemContext {
+                item_first_seen: Some(recorded.first_seen),
+                previous: recorded
+                    .previous_title
+                    .as_deref()
+                    .map(|title| PreviousItem { title }),
+                ..**item
+            })
+            .collect::<Vec<_>>();
+
         log::log!(
             if new_items.is_empty() {
                 log::Level::Debug
             } else {
                 log::Level::Info
             },
+            urls_hash:% = feed_group.urls_hash,
+            items = new_items.len();
             "Feed group {:?}: {} new items found",
             feed_group.urls,
             new_items.len()
         );
 
-        // Send emails
-        if !new_items.is_empty() {
-            let mails = if matches!(status, FeedStatus::NewFeed | FeedStatus::NewCriteria)
-                || feed_group.settings.digest
-                || new_items.len() > feed_group.settings.max_mails_per_check
-            {
-                let feeds = all_feeds
-                    .iter()
-                    .map(|feed| feed.borrow_feed())
-                    .collect::<Vec<_>>();
-                let ctx = minijinja::context! { feeds => feeds, items => new_items };
-                let subject_prefix = match status {
-                    FeedStatus::NewFeed => "[New Feed] ",
-                    FeedStatus::NewCriteria => "[New Criteria] ",
-                    _ => "",
-                };
-                let subject = format!(
-                    "{subject_prefix}{}",
-                    renderer.render(TemplateName::DigestSubject, &ctx)?
+        let mut mail_count = 0;
+        let full_texts;
+        let mut webhooks_pending = false;
+        if !new_items.is_empty() && seeds_silently(&feed_group.settings, status) {
+            log::info!(
+                urls_hash:% = feed_group.urls_hash,
+                items = new_items.len();
+                "Feed group {:?} ({status:?}): recorded {} items without sending mails",
+                feed_group.urls,
+                new_items.len()
+            );
+            db::set_feed_group_update_time(&mut tx, feed_group.urls_hash).await?;
+        } else if !new_items.is_empty() || sends_empty_digest(&feed_group.settings, status) {
+            // Deliver the new items
+            let limited_items = limit_new_items(&feed_group.settings, &new_items);
+            if limited_items.len() < new_items.len() {
+                log::warn!(
+                    urls_hash:% = feed_group.urls_hash,
+                    items = new_items.len();
+                    "Feed group {:?}: only sending the newest {} of {} new items",
+                    feed_group.urls,
+                    limited_items.len(),
+                    new_items.len()
                 );
-                let body = renderer.render(TemplateName::DigestBody, &ctx)?;
-                vec![Mail { subject, body }]
+            }
+            full_texts = fetch_full_texts(feed_group, &limited_items).await;
+            let new_items = with_full_texts(&limited_items, &full_texts);
+            let item_refs = new_items.iter().collect::<Vec<_>>();
+
+            let delivery = feed_group.settings.delivery;
+            let webhooks = if delivery.webhook() {
+                render_webhooks(feed_group, &renderer, status, &all_feeds, &item_refs)?
             } else {
-                new_items
-                    .into_iter()
-                    .map(|item| {
-                        let subject = renderer.render(TemplateName::ItemSubject, item)?;
-                        let body = renderer.render(TemplateName::ItemBody, item)?;
-                        Ok(Mail { subject, body })
-                    })
-                    .collect::<Result<_>>()?
+                Vec::new()
             };
-
-            let mail_count = mails.len();
-
-            if feed_group.settings.to.is_empty()
-                && feed_group.settings.cc.is_empty()
-                && feed_group.settings.bcc.is_empty()
-            {
-                log::warn!(
-                    "No recipients specified for feed group {:?}",
-                    feed_group.urls
-                );
+            if delivery.email() {
+                mail_count = self
+                    .send_mails(feed_group, &renderer, status, &all_feeds, &item_refs)
+                    .await?;
+            }
+            if !new_items.is_empty() {
+                db::set_feed_group_update_time(&mut tx, feed_group.urls_hash).await?;
+            }
+            if delivery.email() {
+                // posted after committing, so that a failing webhook does not resend the mails
+                for body in &webhooks {
+                    db::add_pending_webhook(&mut tx, feed_group.urls_hash, body).await?;
+                }
+                webhooks_pending = !webhooks.is_empty();
             } else {
-                send_email_with_backoff(
-                    &self.mailer,
-                    &feed_group.settings.to,
-                    &feed_group.settings.cc,
-                    &feed_group.settings.bcc,
-                    mails,
-                )
-                .await?;
-                log::info!("Feed group {:?}: Sent {mail_count} emails", feed_group.urls);
+                post_webhooks(feed_group, &webhooks).await?;
             }
-
-            db::set_feed_group_update_time(&mut *tx, feed_group.urls_hash).await?;
         }
 
-        db::clear_failure(&mut *tx, feed_group.urls_hash).await?;
+        db::clear_failure(&mut tx, feed_group.urls_hash).await?;
 
-        db::delete_old_items(&mut *tx, feed_group.urls_hash, feed_group.settings.keep_old).await?;
+        let stats = db::CycleStats {
+            check_time: checked_at,
+            items_seen,
+            new_items: new_items.len().try_into()?,
+            mails_sent: mail_count.try_into()?,
+            fetch_duration,
+        };
+        db::record_cycle_stats(&mut tx, feed_group.urls_hash, &stats).await?;
+
+        db::delete_old_items(&mut tx, feed_group.urls_hash, feed_group.settings.keep_old).await?;
+        db::delete_old_stats(&mut tx, feed_group.urls_hash, feed_group.settings.keep_old).await?;
+        if let Some(keep_max) = feed_group.settings.keep_max_items {
+            db::delete_excess_items(&mut tx, feed_group.urls_hash, keep_max, checked_at).await?;
+        }
 
         tx.commit().await?;
 
+        if webhooks_pending
+            && let Err(e) = post_pending_webhooks((&self.pool).into(), feed_group).await
+        {
+            log::warn!(
+                urls_hash:% = feed_group.urls_hash;
+                "Feed group {:?}: mails were sent, but posting to the webhook failed, retrying on \
+                 the next check: {e:?}",
+                feed_group.urls
+            );
+        }
+
         Ok(())
     }
-}
 
-struct FailureTracker {
-    failing_hash: Hash,
-    debouncing_hash: Hash,
-    debounce_count: u8,
-    report_to: Vec<Mailbox>,
-    minijinja_env: Environment<'static>,
+    /// Sends the new items by email, returning the number of mails sent.
+    async fn send_mails(
+        &self,
+        feed_group: &FeedGroup,
+        renderer: &Renderer<'_>,
+        status: FeedStatus,
+        all_feeds: &[FetchedFeed],
+        new_items: &[&FeedItemContext<'_>],
+    ) -> Result<usize> {
+        if !has_recipients(&feed_group.settings) {
+            log::warn!(
+                "No recipients specified for feed group {:?}",
+                feed_group.urls
+            );
+            return Ok(0);
+        }
+
+        let mut mail_count = 0;
+        let mut inliner = ImageInliner::new(&feed_group.settings);
+        for recipient in mail_recipients(&feed_group.settings) {
+            let mut mails = compose_mails(
+                feed_group,
+                renderer,
+                status,
+                all_feeds,
+                new_items.to_vec(),
+                recipient,
+            )?;
+            mail_count += mails.len();
+            if feed_group.settings.inline_images {
+                for mail in &mut mails {
+                    inliner.inline(mail).await;
+                }
+            }
+            match recipient {
+                Some(recipient) => {
+                    let list_unsubscribe =
+                        personalized_list_unsubscribe(feed_group, renderer, recipient)?;
+                    let headers = MailHeaders::personalized(
+                        &feed_group.settings,
+                        recipient,
+                        &list_unsubscribe,
+                    );
+                    send_email_with_backoff(&self.mailer, &headers, mails).await?;
+                }
+                None => {
+                    let headers = MailHeaders::from(&feed_group.settings);
+                    send_email_with_backoff(&self.mailer, &headers, mails).await?;
+                }
+            }
+        }
+        log::info!(
+            urls_hash:% = feed_group.urls_hash,
+            mails = mail_count;
+            "Feed group {:?}: Sent {mail_count} emails",
+            feed_group.urls
+        );
+        Ok(mail_count)
+    }
 }
 
-const FAILURE_REPORT_TEMPLATE: &str = r#"
-<div>🔴 {{ failures | length }} feed{{ failures | pluralize(" is", "s are") }} not working ({{ now() | datetimeformat(format="iso") }}):
-<ul>
-  {% for failure in failures %}
-  <li>
-    URL{{ failure.urls | pluralize }}: {{ failure.urls | join(", ") }}<br>
-    <blockquote><pre>{{ failure.error | safe }}</pre></blockquote>
-  </li>
-  {% endfor %}
-</ul>
-</div>
-"#;
-const FAILURE_REPORT_TEMPLATE_NAME: &str = "failure-report.html";
+/// The JSON body posted to `webhook-url`, one per mail that would be sent.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    feed_urls: &'a [String],
+    subject: &'a str,
+    body: &'a str,
+    text_body: Option<&'a str>,
+    items: Vec<WebhookItem<'a>>,
+}
 
-impl FailureTracker {
-    const DEBOUNCE_TIMES: u8 = 5;
+#[derive(Serialize)]
+struct WebhookItem<'a> {
+    id: &'a str,
+    title: Option<&'a str>,
+    link: Option<&'a str>,
+    published: Option<DateTime<Utc>>,
+    updated: Option<DateTime<Utc>>,
+    source: &'a str,
+}
 
-    fn new() -> Self {
-        let mut minijinja_env = Environment::new();
-        add_to_environment(&mut minijinja_env);
-        minijinja_env
-            .add_template(FAILURE_REPORT_TEMPLATE_NAME, FAILURE_REPORT_TEMPLATE)
-            .expect("failed to add failure report template");
-        let empty_hash = Hasher::new().finalize();
+impl<'a> From<&'a FeedItemContext<'a>> for WebhookItem<'a> {
+    fn from(item: &'a FeedItemContext<'a>) -> Self {
         Self {
-            failing_hash: empty_hash,
-            debouncing_hash: empty_hash,
-            debounce_count: 0,
-            report_to: Vec::new(),
-            minijinja_env,
+            id: &item.item.id,
+            title: item.item.title.as_ref().map(|title| title.content.as_str()),
+            link: item.item.links.first().map(|link| link.href.as_str()),
+            published: item.item.published,
+            updated: item.item.updated,
+            source: &item.source.url,
         }
     }
+}
 
-    fn set_report_to(&mut self, report_to: Vec<Mailbox>) {
-        self.report_to = report_to;
+/// Renders the JSON bodies of the webhooks of the new items, with the same templates as the mails.
+fn render_webhooks(
+    feed_group: &FeedGroup,
+    renderer: &Renderer<'_>,
+    status: FeedStatus,
+    all_feeds: &[FetchedFeed],
+    new_items: &[&FeedItemContext<'_>],
+) -> Result<Vec<String>> {
+    compose_messages(
+        feed_group,
+        renderer,
+        status,
+        all_feeds,
+        new_items.to_vec(),
+        None,
+    )?
+    .iter()
+    .map(|(mail, items)| {
+        let payload = WebhookPayload {
+            feed_urls: &feed_group.urls,
+            subject: &mail.subject,
+            body: &mail.body,
+            text_body: mail.text_body.as_deref(),
+            items: items.iter().map(|item| WebhookItem::from(*item)).collect(),
+        };
+        serde_json::to_string(&payload).wrap_err("Failed to serialize webhook payload")
+    })
+    .collect()
+}
+
+fn webhook_url(feed_group: &FeedGroup) -> Result<&str> {
+    match &feed_group.settings.webhook_url {
+        Some(url) => Ok(url),
+        None => bail!(
+            "webhook-url is not set for feed group {:?}",
+            feed_group.urls
+        ),
     }
+}
 
-    async fn record(&mut self, mut failures: Vec<(Arc<FeedGroup>, String)>, mailer: &Mailer) {
-        failures.sort_unstable_by_key(|(feed, _)| *feed.urls_hash.as_bytes());
-        let failing_hash = failures
-            .iter()
-            .fold(Hasher::new(), |mut hasher, (feed, _)| {
-                hasher.update(feed.urls_hash.as_bytes());
-                hasher
-            })
-            .finalize();
-        if failing_hash == self.debouncing_hash {
-            if self.debounce_count == 1 && failing_hash != self.failing_hash {
-                if let Err(e) = self.send_failure_report(failures, mailer).await {
-                    log::error!("Failed to send failure report email: {e:?}");
-                    return;
-                }
-                self.failing_hash = failing_hash;
-            }
-            self.debounce_count = self.debounce_count.saturating_sub(1);
-        } else {
-            log::info!("Failing feed groups changed ({} failures)", failures.len(),);
-            self.debouncing_hash = failing_hash;
-            self.debounce_count = Self::DEBOUNCE_TIMES;
+async fn post_webhooks(feed_group: &FeedGroup, bodies: &[String]) -> Result<()> {
+    let url = webhook_url(feed_group)?;
+    for body in bodies {
+        post_webhook(url, &feed_group.settings, body).await?;
+    }
+    log_posted_webhooks(feed_group, bodies.len());
+    Ok(())
+}
+
+/// Posts the webhooks kept by `delivery = "both"`, deleting each once it is posted.
+async fn post_pending_webhooks(mut e: db::Executor<'_>, feed_group: &FeedGroup) -> Result<()> {
+    let pending = db::get_pending_webhooks(e.reborrow(), feed_group.urls_hash).await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let url = webhook_url(feed_group)?;
+    for (id, body) in &pending {
+        post_webhook(url, &feed_group.settings, body)
+            .await
+            .wrap_err("failed to post a pending webhook")?;
+        db::delete_pending_webhook(e.reborrow(), *id).await?;
+    }
+    log_posted_webhooks(feed_group, pending.len());
+    Ok(())
+}
+
+fn log_posted_webhooks(feed_group: &FeedGroup, count: usize) {
+    log::info!(
+        urls_hash:% = feed_group.urls_hash,
+        webhooks = count;
+        "Feed group {:?}: Posted {count} webhooks",
+        feed_group.urls,
+    );
+}
+
+/// The latest modification time and a hash of the names and contents of the config files. For a
+/// config directory, the time of the directory itself is included, which changes when files are
+/// added or removed.
+async fn config_version(config_path: &Path) -> Result<(SystemTime, Hash)> {
+    let mut modified = tokio::fs::metadata(config_path)
+        .await
+        .wrap_err("failed to get config file metadata")?
+        .modified()?;
+    let mut hasher = Hasher::new();
+    for file in config_files(config_path).await? {
+        let file_modified = tokio::fs::metadata(&file)
+            .await
+            .wrap_err("failed to get config file metadata")?
+            .modified()?;
+        modified = modified.max(file_modified);
+        let content = tokio::fs::read(&file)
+            .await
+            .wrap_err("failed to read config file")?;
+        hasher.update(blake3::hash(file.as_os_str().as_encoded_bytes()).as_bytes());
+        hasher.update(blake3::hash(&content).as_bytes());
+    }
+    Ok((modified, hasher.finalize()))
+}
+
+/// Loads the config and compiles the templates, filters and update keys of every feed group, so
+/// that a typo is reported at reload instead of failing the feed groups one by one.
+async fn load_checked_config(
+    config_path: &Path,
+    discovery_cache: &mut DiscoveryCache,
+) -> Result<Config> {
+    let config = load_config(config_path, discovery_cache).await?;
+    check_config(&config)?;
+    Ok(config)
+}
+
+fn check_config(config: &Config) -> Result<()> {
+    for feed_group in &config.feeds {
+        Renderer::from_feed(feed_group)
+            .and_then(|renderer| renderer.check_templates())
+            .wrap_err_with(|| format!("Invalid feed group {:?}", feed_group.urls))?;
+    }
+    Ok(())
+}
+
+/// A random delay before checking the feed group, at most `poll_jitter` and its `interval`.
+fn jitter_delay(poll_jitter: Duration, interval: TimeDelta) -> Duration {
+    let max = poll_jitter.min(interval.to_std().unwrap_or_default());
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    rand::random_range(Duration::ZERO..=max)
+}
+
+/// The configured `interval`, or the interval advertised by the feeds if it is longer and
+/// `respect-ttl` is set. The advertised interval is capped at half of `keep-old`, otherwise items
+/// would be deleted between checks and sent again.
+async fn check_interval<'a>(
+    e: impl Into<db::Executor<'a>>,
+    feed_group: &FeedGroup,
+) -> Result<TimeDelta> {
+    let interval = feed_group.settings.interval;
+    if !feed_group.settings.respect_ttl {
+        return Ok(interval);
+    }
+    let advertised = db::get_advertised_interval(e, feed_group.urls_hash).await?;
+    Ok(advertised.map_or(interval, |advertised| {
+        interval.max(advertised.min(feed_group.settings.keep_old / 2))
+    }))
+}
+
+/// Feed groups last checked before the returned time are due. With a `schedule`, it is the latest
+/// scheduled time, so that the feed group is checked once after each scheduled time.
+async fn update_cutoff<'a>(
+    e: impl Into<db::Executor<'a>>,
+    feed_group: &FeedGroup,
+) -> Result<DateTime<Utc>> {
+    let now = Utc::now();
+    match &feed_group.settings.schedule {
+        Some(schedule) => last_scheduled_time(schedule, feed_group.timezone.as_deref(), now),
+        None => {
+            let interval = check_interval(e, feed_group).await?;
+            Ok(db::saturating_sub_datetime(now, interval))
         }
     }
+}
 
-    async fn send_failure_report(
-        &self,
-        failures: Vec<(Arc<FeedGroup>, String)>,
-        mailer: &Mailer,
-    ) -> Result<()> {
-        if self.report_to.is_empty() {
-            return Ok(());
+/// When the feed group becomes due after its last check, the counterpart of [`update_cutoff`].
+/// It is checked in the first worker cycle after that.
+async fn next_check_time(tx: &mut db::Tx, feed_group: &FeedGroup) -> Result<Option<DateTime<Utc>>> {
+    let Some(last_check) = db::get_last_check(&mut *tx, feed_group.urls_hash).await? else {
+        return Ok(None);
+    };
+    match &feed_group.settings.schedule {
+        Some(schedule) => {
+            let timezone = schedule_timezone(feed_group.timezone.as_deref())?;
+            Ok(schedule
+                .after(&last_check.with_timezone(&timezone))
+                .next()
+                .map(|time| time.to_utc()))
         }
-        log::info!(
-            "Sending failure report email for {} failing feed groups",
-            failures.len(),
-        );
-        let mail = if failures.is_empty() {
-            Mail {
-                subject: "✅ All feeds are working".to_string(),
-                body: format!(
-                    "All feeds are back to normal now ({}).",
-                    Utc::now().to_rfc3339()
-                ),
+        None => Ok(last_check.checked_add_signed(check_interval(tx, feed_group).await?)),
+    }
+}
+
+/// The latest time before `now` matching the schedule in `timezone`, or UTC if it is not set.
+fn last_scheduled_time(
+    schedule: &Schedule,
+    timezone: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>> {
+    let timezone = schedule_timezone(timezone)?;
+    Ok(schedule
+        .after(&now.with_timezone(&timezone))
+        .next_back()
+        .map_or(DateTime::UNIX_EPOCH, |time| time.to_utc()))
+}
+
+fn schedule_timezone(timezone: Option<&str>) -> Result<Tz> {
+    match timezone {
+        Some(timezone) => timezone
+            .parse::<Tz>()
+            .map_err(|e| eyre!("Invalid timezone {timezone:?}: {e}")),
+        None => Ok(Tz::UTC),
+    }
+}
+
+async fn is_feed_group_waiting(pool: &Pool, feed_group: &FeedGroup) -> Result<bool> {
+    let update_cutoff = update_cutoff(pool, feed_group).await?;
+    db::is_feed_group_waiting(pool, feed_group, update_cutoff).await
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Failed to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
             }
-        } else {
-            let failure_ctx = failures
-                .iter()
-                .map(|failure| FailureCtx {
-                    urls: &failure.0.urls,
-                    error: failure.1.to_string(),
-                })
-                .collect::<Vec<_>>();
-            let body = self
-                .minijinja_env
-                .get_template(FAILURE_REPORT_TEMPLATE_NAME)
-                .expect("failed to load failure report template")
-                .render(minijinja::context! { failures => failure_ctx })
-                .expect("failed to render failure report");
-            Mail {
-                subject: "🔴 Error processing feeds".into(),
-                body,
+            Err(e) => {
+                log::error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Requests a config reload on each SIGHUP.
+async fn reload_signal(reload_tx: watch::Sender<()>) {
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(mut signal) => {
+            while signal.recv().await.is_some() {
+                log::debug!("SIGHUP received");
+                reload_tx.send_replace(());
+            }
+        }
+        Err(e) => log::error!("Failed to listen for SIGHUP: {e}"),
+    }
+
+    #[cfg(not(unix))]
+    drop(reload_tx);
+}
+
+/// Tracks the items seen in a check, so that duplicates from mirrors are sent only once.
+struct Dedupe<'a> {
+    /// The query parameters to ignore, if links are compared.
+    ignore_params: Option<&'a [String]>,
+    update_hashes: HashSet<Hash>,
+    links: HashSet<String>,
+}
+
+impl<'a> Dedupe<'a> {
+    fn new(settings: &'a Settings) -> Self {
+        Self {
+            ignore_params: (settings.dedupe_by == DedupeBy::Link)
+                .then_some(&*settings.dedupe_ignore_params),
+            update_hashes: HashSet::new(),
+            links: HashSet::new(),
+        }
+    }
+
+    /// Whether no earlier item has the same update hash, or the same link if enabled.
+    fn is_first(&mut self, item: &FeedItemContext, update_hash: Hash) -> bool {
+        let new_hash = self.update_hashes.insert(update_hash);
+        let new_link = match (self.ignore_params, item.item.links.first()) {
+            (Some(ignore_params), Some(link)) => {
+                self.links.insert(dedupe_link(&link.href, ignore_params))
             }
+            _ => true,
         };
-        send_email_with_backoff(mailer, &self.report_to, &[], &[], vec![mail]).await
+        new_hash && new_link
     }
 }
 
-#[derive(Serialize)]
-struct FailureCtx<'a> {
-    urls: &'a [String],
-    error: String,
+/// Normalizes a link for comparison: the scheme, the fragment, trailing slashes and the ignored
+/// query parameters are removed. Parameters ending with `*` are prefixes.
+fn dedupe_link(link: &str, ignore_params: &[String]) -> String {
+    let Ok(mut url) = Url::parse(link.trim()) else {
+        return link.trim().to_owned();
+    };
+
+    let query = url
+        .query_pairs()
+        .filter(|(name, _)| !param_matches(ignore_params, name))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    let mut normalized = url.host_str().unwrap_or_default().to_owned();
+    normalized.push_str(url.path().trim_end_matches('/'));
+    if let Some(query) = url.query() {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    normalized
+}
+
+/// The order in which feed groups are spawned, independent of their order in the config.
+/// Higher-priority feed groups come first so that they acquire connections first.
+fn processing_order(feed: &FeedGroup) -> (Reverse<i32>, [u8; 32]) {
+    (Reverse(feed.priority), *feed.urls_hash.as_bytes())
+}
+
+/// Applies `item-transform` to the items, before they are filtered.
+pub fn transform_items(
+    feed_group: &FeedGroup,
+    renderer: &Renderer,
+    feed: FetchedFeed,
+) -> Result<FetchedFeed> {
+    if feed_group.item_transform.is_none() {
+        return Ok(feed);
+    }
+    feed.transform_items(&feed_group.settings, |item| renderer.transform_item(item))
+}
+
+/// Whether the item passes the filter, or is pinned.
+pub fn filter_item(
+    feed_group: &FeedGroup,
+    renderer: &Renderer,
+    item: &FeedItemContext,
+) -> Result<bool> {
+    Ok(feed_group.pin.contains(&item.item.id) || renderer.filter(item)?)
+}
+
+fn sends_digest(feed_group: &FeedGroup, status: FeedStatus, new_item_count: usize) -> bool {
+    matches!(status, FeedStatus::NewFeed | FeedStatus::NewCriteria)
+        || feed_group.settings.digest
+        || new_item_count == 0
+        || new_item_count > feed_group.settings.max_mails_per_check
+}
+
+/// The newest `max-new-items-per-check` new items, in their original order. Items are ordered by
+/// their last modified time with `sort-by-last-modified`, or else by their order in the feeds.
+fn limit_new_items<'a>(
+    settings: &Settings,
+    new_items: &[FeedItemContext<'a>],
+) -> Vec<FeedItemContext<'a>> {
+    let Some(limit) = settings.max_new_items_per_check else {
+        return new_items.to_vec();
+    };
+    if new_items.len() <= limit {
+        return new_items.to_vec();
+    }
+    let mut indices = (0..new_items.len()).collect::<Vec<_>>();
+    if settings.sort_by_last_modified {
+        // stable, so that ties are kept in feed order
+        indices
+            .sort_by_key(|&i| Reverse(new_items[i].item.updated.or(new_items[i].item.published)));
+    }
+    indices.truncate(limit);
+    indices.sort_unstable();
+    indices.into_iter().map(|i| new_items[i]).collect()
+}
+
+/// Fetches the full articles of new items if `full-text` is set. Items whose articles cannot be
+/// fetched keep their original content.
+async fn fetch_full_texts(
+    feed_group: &FeedGroup,
+    new_items: &[FeedItemContext<'_>],
+) -> Vec<Option<Entry>> {
+    if !feed_group.settings.full_text {
+        return vec![None; new_items.len()];
+    }
+    // the futures are created upfront, as a stream mapping over borrowed items is not `Send`
+    let fetches = new_items
+        .iter()
+        .map(|item| fetch_item_full_text(feed_group, item.item))
+        .collect::<Vec<_>>();
+    stream::iter(fetches)
+        .buffered(MAX_CONCURRENT_FULL_TEXT)
+        .collect()
+        .await
+}
+
+async fn fetch_item_full_text(feed_group: &FeedGroup, item: &Entry) -> Option<Entry> {
+    fetch_full_text(item, &feed_group.settings)
+        .await
+        .inspect_err(|e| {
+            log::warn!(
+                "Feed group {:?}: failed to fetch full text of item {:?}: {e:#}",
+                feed_group.urls,
+                item.id
+            );
+        })
+        .ok()
+}
+
+fn with_full_texts<'a>(
+    new_items: &[FeedItemContext<'a>],
+    full_texts: &'a [Option<Entry>],
+) -> Vec<FeedItemContext<'a>> {
+    new_items
+        .iter()
+        .zip(full_texts)
+        .map(|(item, full_text)| match full_text {
+            Some(entry) => FeedItemContext {
+                item: entry,
+                ..*item
+            },
+            None => *item,
+        })
+        .collect()
+}
+
+/// Whether mails of the feed group would be sent to anyone.
+fn has_recipients(settings: &Settings) -> bool {
+    if settings.personalize {
+        !settings.to.is_empty()
+    } else {
+        !(settings.to.is_empty() && settings.cc.is_empty() && settings.bcc.is_empty())
+    }
+}
+
+/// Each `to` address if the mails are personalized, otherwise `None` for a single batch of mails
+/// to all recipients.
+fn mail_recipients(settings: &Settings) -> Vec<Option<&Mailbox>> {
+    if settings.personalize {
+        settings.to.iter().map(Some).collect()
+    } else {
+        vec![None]
+    }
+}
+
+/// Personalized mails are previewed for the first recipient.
+pub fn preview_recipient(settings: &Settings) -> Option<&Mailbox> {
+    settings.to.first().filter(|_| settings.personalize)
+}
+
+/// The recipient of personalized mails, exposed to templates as `recipient`.
+#[derive(Serialize)]
+struct Recipient<'a> {
+    name: Option<&'a str>,
+    email: &'a str,
+}
+
+impl<'a> From<&'a Mailbox> for Recipient<'a> {
+    fn from(mailbox: &'a Mailbox) -> Self {
+        Self {
+            name: mailbox.name.as_deref(),
+            email: mailbox.email.as_ref(),
+        }
+    }
+}
+
+/// `list-unsubscribe` URIs are templates with `recipient` in personalized mails.
+fn personalized_list_unsubscribe(
+    feed_group: &FeedGroup,
+    renderer: &Renderer,
+    recipient: &Mailbox,
+) -> Result<Vec<String>> {
+    let ctx = minijinja::context! { recipient => Recipient::from(recipient) };
+    feed_group
+        .settings
+        .list_unsubscribe
+        .iter()
+        .map(|uri| renderer.render_str(uri, &ctx))
+        .collect()
+}
+
+/// The context of item templates, with `recipient` in personalized mails.
+pub fn item_context(item: &FeedItemContext, recipient: Option<&Mailbox>) -> Value {
+    match recipient {
+        Some(recipient) => minijinja::context! {
+            recipient => Recipient::from(recipient),
+            ..Value::from_serialize(item)
+        },
+        None => Value::from_serialize(item),
+    }
+}
+
+/// Renders new items into a digest or individual mails, personalized for `recipient` if it is
+/// set.
+pub fn compose_mails(
+    feed_group: &FeedGroup,
+    renderer: &Renderer,
+    status: FeedStatus,
+    all_feeds: &[FetchedFeed],
+    new_items: Vec<&FeedItemContext>,
+    recipient: Option<&Mailbox>,
+) -> Result<Vec<Mail>> {
+    let messages = compose_messages(
+        feed_group, renderer, status, all_feeds, new_items, recipient,
+    )?;
+    Ok(messages.into_iter().map(|(mail, _)| mail).collect())
+}
+
+/// Like [`compose_mails`], but also returns the items in each mail.
+fn compose_messages<'a, 'b>(
+    feed_group: &FeedGroup,
+    renderer: &Renderer,
+    status: FeedStatus,
+    all_feeds: &[FetchedFeed],
+    mut new_items: Vec<&'b FeedItemContext<'a>>,
+    recipient: Option<&Mailbox>,
+) -> Result<Vec<(Mail, Vec<&'b FeedItemContext<'a>>)>> {
+    if feed_group.settings.sort_by_last_modified {
+        new_items.sort_by_key(|item| Reverse(item.item.updated.or(item.item.published)));
+    }
+
+    if sends_digest(feed_group, status, new_items.len()) {
+        let feeds = all_feeds
+            .iter()
+            .map(|feed| feed.borrow_feed())
+            .collect::<Vec<_>>();
+        let recipient = recipient.map(Recipient::from);
+        let ctx = minijinja::context! { feeds => feeds, items => new_items, recipient };
+        let subject_prefix = match status {
+            FeedStatus::NewFeed => "[New Feed] ",
+            FeedStatus::NewCriteria => "[New Criteria] ",
+            _ => "",
+        };
+        let subject = format!(
+            "{subject_prefix}{}",
+            renderer.render(TemplateName::DigestSubject, &ctx)?
+        );
+        let body = renderer.render(TemplateName::DigestBody, &ctx)?;
+        let text_body = render_text_body(
+            feed_group,
+            renderer,
+            TemplateName::DigestTextBody,
+            &ctx,
+            &body,
+        )?;
+        let thread = mail_thread(feed_group, None);
+        let attachments = if feed_group.settings.attach_source {
+            new_items
+                .iter()
+                .enumerate()
+                .filter_map(|(i, item)| source_attachment(item, format!("item-{}.html", i + 1)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mail = Mail {
+            subject,
+            body,
+            text_body,
+            thread,
+            images: Vec::new(),
+            attachments,
+        };
+        Ok(vec![(mail, new_items)])
+    } else {
+        new_items
+            .into_iter()
+            .map(|item| {
+                let ctx = item_context(item, recipient);
+                let subject = renderer.render(TemplateName::ItemSubject, &ctx)?;
+                let body = renderer.render(TemplateName::ItemBody, &ctx)?;
+                let text_body = render_text_body(
+                    feed_group,
+                    renderer,
+                    TemplateName::ItemTextBody,
+                    &ctx,
+                    &body,
+                )?;
+                let id = (feed_group.settings.thread_by == ThreadBy::Feed)
+                    .then(|| renderer.update_hash(item))
+                    .transpose()?
+                    .map(|update_hash| item_message_id(&feed_group.settings, update_hash, item));
+                let thread = mail_thread(feed_group, id);
+                let attachments = feed_group
+                    .settings
+                    .attach_source
+                    .then(|| source_attachment(item, "item.html".into()))
+                    .flatten()
+                    .into_iter()
+                    .collect();
+                let mail = Mail {
+                    subject,
+                    body,
+                    text_body,
+                    thread,
+                    images: Vec::new(),
+                    attachments,
+                };
+                Ok((mail, vec![item]))
+            })
+            .collect()
+    }
+}
+
+/// The raw HTML content of the item, or its summary if there is no content.
+fn source_attachment(
+    item: &FeedItemContext,
+    filename: String,
+) -> Option<(String, Vec<u8>, ContentType)> {
+    let html = item
+        .item
+        .content
+        .as_ref()
+        .and_then(|content| content.body.as_deref())
+        .or_else(|| item.item.summary.as_ref().map(|summary| &*summary.content))?;
+    Some((filename, html.as_bytes().to_vec(), ContentType::TEXT_HTML))
+}
+
+/// Identifies the mail of an item in its thread. With `update-on-updated-date`, an item is resent
+/// with the same update hash when its `updated` date advances, so the date is mixed in, otherwise
+/// the resent mail would have the same `Message-ID` and be dropped as a duplicate.
+fn item_message_id(settings: &Settings, update_hash: Hash, item: &FeedItemContext) -> Hash {
+    if !settings.update_on_updated_date {
+        return update_hash;
+    }
+    let mut hasher = Hasher::new();
+    hasher.update(update_hash.as_bytes());
+    if let Some(updated) = item.item.updated {
+        hasher.update(updated.to_rfc3339().as_bytes());
+    }
+    hasher.finalize()
+}
+
+fn mail_thread(feed_group: &FeedGroup, id: Option<Hash>) -> Option<Thread> {
+    match feed_group.settings.thread_by {
+        ThreadBy::Feed => Some(Thread {
+            root: feed_group.urls_hash,
+            id,
+        }),
+        ThreadBy::None => None,
+    }
+}
+
+/// Renders the plain-text alternative body with the text template, or converts the HTML body if
+/// there is no text template.
+fn render_text_body<S: Serialize>(
+    feed_group: &FeedGroup,
+    renderer: &Renderer,
+    name: TemplateName,
+    ctx: S,
+    html_body: &str,
+) -> Result<Option<String>> {
+    let template = match name {
+        TemplateName::ItemTextBody => &feed_group.settings.item_text_body,
+        TemplateName::DigestTextBody => &feed_group.settings.digest_text_body,
+        _ => bail!("{} is not a text body template", name.as_ref()),
+    };
+    if template.is_some() {
+        renderer.render(name, ctx).map(Some)
+    } else if feed_group.settings.text_body {
+        html2text::from_read(html_body.as_bytes(), TEXT_BODY_WIDTH)
+            .map(Some)
+            .wrap_err("Failed to convert HTML body to text")
+    } else {
+        Ok(None)
+    }
+}
+
+/// Checks all feed groups against the database without sending mails or writing to the database,
+/// and prints how many items would be sent in the next check, and the mails if `render` is set.
+pub async fn dry_run(pool: Pool, feeds: Vec<FeedGroup>, render: bool) -> Result<()> {
+    let mut set = JoinSet::new();
+
+    for feed_group in feeds {
+        let pool = pool.clone();
+        set.spawn(async move {
+            let result = dry_run_feed(&pool, &feed_group).await;
+            (feed_group, result)
+        });
+    }
+
+    let mut total_items = 0;
+    let mut total_mails = 0;
+    let mut errors = 0;
+
+    while let Some(res) = set.join_next().await {
+        let (feed_group, result) = res?;
+        match result {
+            Ok(DryRunOutcome {
+                status,
+                new_items: 0,
+                ..
+            }) => {
+                log::debug!(
+                    "Feed group {:?} ({status:?}): no new items",
+                    feed_group.urls
+                );
+            }
+            Ok(DryRunOutcome {
+                status,
+                new_items,
+                sent_items,
+                mails,
+            }) => {
+                if sent_items < new_items {
+                    println!(
+                        "Feed group {:?} ({status:?}): {new_items} new items, the newest \
+                         {sent_items} in {} mails",
+                        feed_group.urls,
+                        mails.len()
+                    );
+                } else {
+                    println!(
+                        "Feed group {:?} ({status:?}): {new_items} new items in {} mails",
+                        feed_group.urls,
+                        mails.len()
+                    );
+                }
+                if render {
+                    println!();
+                    for mail in &mails {
+                        print_mail(mail);
+                    }
+                }
+                total_items += new_items;
+                total_mails += mails.len();
+            }
+            Err(e) => {
+                println!("Feed group {:?}: error: {e}", feed_group.urls);
+                errors += 1;
+            }
+        }
+    }
+
+    println!("Total: {total_items} new items in {total_mails} mails, {errors} errors");
+    Ok(())
+}
+
+/// What the next check of a feed group would do.
+struct DryRunOutcome {
+    status: FeedStatus,
+    new_items: usize,
+    /// The new items that would be sent, which are fewer with `max-new-items-per-check`.
+    sent_items: usize,
+    mails: Vec<Mail>,
+}
+
+/// Unlike `process_feed`, this only reads from the database, so it does not hold a transaction
+/// that blocks the worker.
+async fn dry_run_feed(pool: &Pool, feed_group: &FeedGroup) -> Result<DryRunOutcome> {
+    let status = db::peek_feed_status(pool, feed_group).await?;
+    let renderer = Renderer::from_feed(feed_group)?;
+
+    let mut all_feeds = Vec::new();
+
+    for url in &feed_group.urls {
+        let feed = match fetch_feed(url, &feed_group.settings, None)
+            .await
+            .wrap_err_with(|| format!("failed to fetch feed from {url}"))?
+        {
+            FetchOutcome::Fetched { feed, .. } => feed,
+            FetchOutcome::NotModified => {
+                bail!("unexpected 304 Not Modified from {url} without validators")
+            }
+        };
+        all_feeds.push(transform_items(feed_group, &renderer, feed)?);
+    }
+
+    let mut dedupe = Dedupe::new(&feed_group.settings);
+    let mut new_items = Vec::new();
+
+    for item in all_feeds.iter().flat_map(|feed| feed.borrow_items()) {
+        if !filter_item(feed_group, &renderer, item)? {
+            continue;
+        }
+        let update_hash = renderer.update_hash(item)?;
+        if !dedupe.is_first(item, update_hash) {
+            continue;
+        }
+        let item_key = blake3::hash(item.item.id.as_bytes());
+        let new = match updated_date_advanced(pool, feed_group, item, item_key).await? {
+            Some(advanced) => advanced,
+            None => db::check_item_new(pool, feed_group.urls_hash, update_hash).await?,
+        };
+        if new {
+            // the item would be recorded now
+            new_items.push(FeedItemContext {
+                item_first_seen: Some(Utc::now()),
+                ..*item
+            });
+        }
+    }
+
+    let mut sent_items = new_items.len();
+    let mails = if (new_items.is_empty() && !sends_empty_digest(&feed_group.settings, status))
+        || seeds_silently(&feed_group.settings, status)
+    {
+        Vec::new()
+    } else {
+        let limited_items = limit_new_items(&feed_group.settings, &new_items);
+        sent_items = limited_items.len();
+        let full_texts = fetch_full_texts(feed_group, &limited_items).await;
+        let limited_items = with_full_texts(&limited_items, &full_texts);
+        compose_mails(
+            feed_group,
+            &renderer,
+            status,
+            &all_feeds,
+            limited_items.iter().collect(),
+            preview_recipient(&feed_group.settings),
+        )?
+    };
+
+    Ok(DryRunOutcome {
+        status,
+        new_items: new_items.len(),
+        sent_items,
+        mails,
+    })
+}
+
+/// With `update-on-updated-date`, whether the feed-declared `updated` date of the item is later than
+/// the latest recorded one. `None` means falling back to comparing the update hash, e.g. for items
+/// without an `updated` date or without a recorded one.
+async fn updated_date_advanced<'a>(
+    e: impl Into<db::Executor<'a>>,
+    feed_group: &FeedGroup,
+    item: &FeedItemContext<'_>,
+    item_key: Hash,
+) -> Result<Option<bool>> {
+    if !feed_group.settings.update_on_updated_date {
+        return Ok(None);
+    }
+    let Some(updated) = item.item.updated else {
+        return Ok(None);
+    };
+    let last_updated = db::last_item_updated(e, feed_group.urls_hash, item_key).await?;
+    Ok(last_updated.map(|last_updated| updated > last_updated))
+}
+
+/// Whether the items are only recorded instead of sent, when a feed group is checked for the first
+/// time or with new criteria.
+fn seeds_silently(settings: &Settings, status: FeedStatus) -> bool {
+    match status {
+        FeedStatus::NewFeed => settings.seed_silently,
+        FeedStatus::NewCriteria => settings.seed_silently_on_new_criteria,
+        FeedStatus::Update | FeedStatus::Wait => false,
+    }
+}
+
+/// Whether new items of a check with `status` would be mailed, but there is no one to mail them to.
+fn lacks_recipients(settings: &Settings, status: FeedStatus) -> bool {
+    settings.delivery.email() && !has_recipients(settings) && !seeds_silently(settings, status)
+}
+
+/// Whether a digest is sent even if there are no new items, with `digest-even-if-empty`.
+fn sends_empty_digest(settings: &Settings, status: FeedStatus) -> bool {
+    settings.digest_even_if_empty && !seeds_silently(settings, status)
+}
+
+/// Prints a mail to stdout, for previewing mails without sending them.
+pub fn print_mail(mail: &Mail) {
+    println!("Subject: {}\n\n{}\n", mail.subject, mail.body);
+    if let Some(text_body) = &mail.text_body {
+        println!("Text body:\n\n{text_body}\n");
+    }
+}
+
+/// Logs which feed groups are added, removed, or have new criteria compared to the previous config.
+fn log_config_diff(old: &[Arc<FeedGroup>], new: &[FeedGroup]) {
+    let old_map = old
+        .iter()
+        .map(|feed| (feed.urls_hash, feed.criteria_hash))
+        .collect::<HashMap<_, _>>();
+    let new_hashes = new
+        .iter()
+        .map(|feed| feed.urls_hash)
+        .collect::<HashSet<_>>();
+
+    let mut added = 0;
+    let mut changed = 0;
+    for feed in new {
+        match old_map.get(&feed.urls_hash) {
+            None => {
+                added += 1;
+                log::debug!("Feed group {:?} added", feed.urls);
+            }
+            Some(criteria_hash) if *criteria_hash != feed.criteria_hash => {
+                changed += 1;
+                log::debug!("Feed group {:?} criteria changed", feed.urls);
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut removed = 0;
+    for feed in old {
+        if !new_hashes.contains(&feed.urls_hash) {
+            removed += 1;
+            log::debug!("Feed group {:?} removed", feed.urls);
+        }
+    }
+
+    log::info!(
+        "Config file update reloaded: {} feed groups, {added} added, {removed} removed, {changed} changed",
+        new.len()
+    );
+}
+
+/// Persisted in the database, so that debouncing works across restarts and `--once` runs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DebounceState {
+    pub failing_hash: Hash,
+    pub debouncing_hash: Hash,
+    pub debounce_count: u8,
+}
+
+struct FailureTracker {
+    state: DebounceState,
+    report_to: ErrorReportTo,
+    /// How many consecutive cycles the failing feeds must stay the same before reporting.
+    debounce_times: u8,
+    report_recovery: bool,
+    minijinja_env: Environment<'static>,
+}
+
+const FAILURE_REPORT_TEMPLATE: &str = r#"
+<div>🔴 {{ failures | length }} feed{{ failures | pluralize(" is", "s are") }} not working ({{ now() | datetimeformat(format="iso") }}):
+<ul>
+  {% for failure in failures | sort(attribute="fail_count", reverse=true) %}
+  <li>
+    URL{{ failure.urls | pluralize }}: {{ failure.urls | join(", ") }}<br>
+    Failing for {{ failure.fail_count }} checks since {{ failure.first_fail_time | datetimeformat(format="iso") }}<br>
+    <blockquote><pre>{{ failure.error | safe }}</pre></blockquote>
+  </li>
+  {% endfor %}
+</ul>
+</div>
+"#;
+const FAILURE_REPORT_TEMPLATE_NAME: &str = "failure-report.html";
+
+const CONFIG_ERROR_TEMPLATE: &str = r#"
+<div>🔴 The config file is invalid, the previous config is still used ({{ now() | datetimeformat(format="iso") }}):
+<blockquote><pre>{{ error }}</pre></blockquote>
+</div>
+"#;
+const CONFIG_ERROR_TEMPLATE_NAME: &str = "config-error.html";
+
+impl FailureTracker {
+    fn new() -> Self {
+        let mut minijinja_env = Environment::new();
+        add_to_environment(&mut minijinja_env);
+        minijinja_env
+            .add_template(FAILURE_REPORT_TEMPLATE_NAME, FAILURE_REPORT_TEMPLATE)
+            .expect("failed to add failure report template");
+        minijinja_env
+            .add_template(CONFIG_ERROR_TEMPLATE_NAME, CONFIG_ERROR_TEMPLATE)
+            .expect("failed to add config error template");
+        let empty_hash = Hasher::new().finalize();
+        Self {
+            state: DebounceState {
+                failing_hash: empty_hash,
+                debouncing_hash: empty_hash,
+                debounce_count: 0,
+            },
+            report_to: ErrorReportTo::default(),
+            debounce_times: 5,
+            report_recovery: true,
+            minijinja_env,
+        }
+    }
+
+    fn set_report_to(
+        &mut self,
+        report_to: ErrorReportTo,
+        debounce_times: u8,
+        report_recovery: bool,
+    ) {
+        self.report_to = report_to;
+        self.debounce_times = debounce_times;
+        self.report_recovery = report_recovery;
+    }
+
+    fn set_timezone(&mut self, timezone: Option<&str>) {
+        match timezone {
+            Some(timezone) => self.minijinja_env.add_global(TIMEZONE_GLOBAL, timezone),
+            None => self.minijinja_env.remove_global(TIMEZONE_GLOBAL),
+        }
+    }
+
+    async fn restore(&mut self, pool: &Pool) {
+        match db::get_debounce_state(pool).await {
+            Ok(Some(state)) => self.state = state,
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to restore failure debounce state: {e:?}"),
+        }
+    }
+
+    async fn record(
+        &mut self,
+        mut failures: Vec<(Arc<FeedGroup>, Failure)>,
+        mailer: &Mailer,
+        pool: &Pool,
+    ) {
+        let old_state = self.state;
+
+        failures.sort_unstable_by_key(|(feed, _)| *feed.urls_hash.as_bytes());
+        let failing_hash = failures
+            .iter()
+            .fold(Hasher::new(), |mut hasher, (feed, _)| {
+                hasher.update(feed.urls_hash.as_bytes());
+                hasher
+            })
+            .finalize();
+        if failing_hash == self.state.debouncing_hash {
+            if self.state.debounce_count == 1 && failing_hash != self.state.failing_hash {
+                if let Err(e) = self.send_failure_report(failures, mailer).await {
+                    log::error!("Failed to send failure report email: {e:?}");
+                    return;
+                }
+                self.state.failing_hash = failing_hash;
+            }
+            self.state.debounce_count = self.state.debounce_count.saturating_sub(1);
+        } else {
+            log::info!("Failing feed groups changed ({} failures)", failures.len(),);
+            self.state.debouncing_hash = failing_hash;
+            self.state.debounce_count = self.debounce_times;
+        }
+        if self.state != old_state
+            && let Err(e) = db::set_debounce_state(pool, &self.state).await
+        {
+            log::error!("Failed to save failure debounce state: {e:?}");
+        }
+    }
+
+    async fn send_failure_report(
+        &self,
+        failures: Vec<(Arc<FeedGroup>, Failure)>,
+        mailer: &Mailer,
+    ) -> Result<()> {
+        let report_to = &self.report_to;
+        if failures.is_empty() {
+            if !self.report_recovery {
+                return Ok(());
+            }
+            log::info!("Sending recovery report email");
+            let mail = || Mail {
+                subject: "✅ All feeds are working".to_string(),
+                body: format!(
+                    "All feeds are back to normal now ({}).",
+                    Utc::now().to_rfc3339()
+                ),
+                text_body: None,
+                thread: None,
+                images: Vec::new(),
+                attachments: Vec::new(),
+            };
+            let persistent = &report_to.persistent;
+            send_report(mailer, &report_to.to, &report_to.cc, &report_to.bcc, mail()).await?;
+            send_report(
+                mailer,
+                &persistent.to,
+                &persistent.cc,
+                &persistent.bcc,
+                mail(),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        log::info!(
+            "Sending failure report email for {} failing feed groups",
+            failures.len(),
+        );
+        let persistent_failures = failures
+            .iter()
+            .filter(|(_, failure)| failure.fail_count >= i64::from(report_to.persistent_after))
+            .collect::<Vec<_>>();
+        let mail = self.render_failure_report(failures.iter());
+        send_report(mailer, &report_to.to, &report_to.cc, &report_to.bcc, mail).await?;
+        if !persistent_failures.is_empty() {
+            let mail = self.render_failure_report(persistent_failures.into_iter());
+            let persistent = &report_to.persistent;
+            send_report(
+                mailer,
+                &persistent.to,
+                &persistent.cc,
+                &persistent.bcc,
+                mail,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Reports an invalid config right away, as it is not retried until the file changes.
+    async fn send_config_error(&self, error: &Report, mailer: &Mailer) -> Result<()> {
+        log::info!("Sending config error report email");
+        let body = self
+            .minijinja_env
+            .get_template(CONFIG_ERROR_TEMPLATE_NAME)
+            .expect("failed to load config error template")
+            .render(minijinja::context! { error => format!("{error:#}") })
+            .expect("failed to render config error report");
+        let mail = || Mail {
+            subject: "🔴 Invalid config".into(),
+            body: body.clone(),
+            text_body: None,
+            thread: None,
+            images: Vec::new(),
+            attachments: Vec::new(),
+        };
+        let report_to = &self.report_to;
+        let persistent = &report_to.persistent;
+        send_report(mailer, &report_to.to, &report_to.cc, &report_to.bcc, mail()).await?;
+        send_report(
+            mailer,
+            &persistent.to,
+            &persistent.cc,
+            &persistent.bcc,
+            mail(),
+        )
+        .await
+    }
+
+    fn render_failure_report<'a>(
+        &self,
+        failures: impl Iterator<Item = &'a (Arc<FeedGroup>, Failure)>,
+    ) -> Mail {
+        let failure_ctx = failures
+            .map(|(feed, failure)| FailureCtx {
+                urls: &feed.urls,
+                error: &failure.error,
+                fail_count: failure.fail_count,
+                fail_time: failure.fail_time,
+                first_fail_time: failure.first_fail_time,
+            })
+            .collect::<Vec<_>>();
+        let body = self
+            .minijinja_env
+            .get_template(FAILURE_REPORT_TEMPLATE_NAME)
+            .expect("failed to load failure report template")
+            .render(minijinja::context! { failures => failure_ctx })
+            .expect("failed to render failure report");
+        Mail {
+            subject: "🔴 Error processing feeds".into(),
+            body,
+            text_body: None,
+            thread: None,
+            images: Vec::new(),
+            attachments: Vec::new(),
+        }
+    }
+}
+
+async fn send_report(
+    mailer: &Mailer,
+    to: &[Mailbox],
+    cc: &[Mailbox],
+    bcc: &[Mailbox],
+    mail: Mail,
+) -> Result<()> {
+    if to.is_empty() && cc.is_empty() && bcc.is_empty() {
+        return Ok(());
+    }
+    let headers = MailHeaders {
+        to,
+        cc,
+        bcc,
+        ..Default::default()
+    };
+    send_email_with_backoff(mailer, &headers, vec![mail]).await
+}
+
+#[derive(Serialize)]
+struct FailureCtx<'a> {
+    urls: &'a [String],
+    error: &'a str,
+    fail_count: i64,
+    fail_time: DateTime<Utc>,
+    first_fail_time: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Delivery, parse_config};
+    use crate::feed::{FeedSource, parse_feed};
+
+    fn mirror(url: &str, items: &[(&str, &str)], settings: &Settings) -> Result<FetchedFeed> {
+        let items = items
+            .iter()
+            .map(|(id, link)| format!("<item><guid>{id}</guid><link>{link}</link></item>"))
+            .collect::<String>();
+        let content =
+            format!("<rss version=\"2.0\"><channel><title>Mirror</title>{items}</channel></rss>");
+        parse_feed(content.as_bytes(), settings, FeedSource::new(url, None))
+    }
+
+    fn first_item_ids(dedupe_by: DedupeBy) -> Result<Vec<String>> {
+        let settings = Settings {
+            dedupe_by,
+            ..Default::default()
+        };
+        let all_feeds = [
+            mirror(
+                "https://a.example/feed.xml",
+                &[
+                    ("a1", "https://example.com/post/1?utm_source=a"),
+                    ("a2", "https://example.com/post/2"),
+                ],
+                &settings,
+            )?,
+            mirror(
+                "https://b.example/feed.xml",
+                &[
+                    ("b1", "http://example.com/post/1/?utm_source=b#top"),
+                    ("a2", "https://example.com/post/2"),
+                    ("b3", "https://example.com/post/1?page=2"),
+                ],
+                &settings,
+            )?,
+        ];
+
+        let mut dedupe = Dedupe::new(&settings);
+        Ok(all_feeds
+            .iter()
+            .flat_map(|feed| feed.borrow_items())
+            .filter(|item| dedupe.is_first(item, blake3::hash(item.item.id.as_bytes())))
+            .map(|item| item.item.id.clone())
+            .collect())
+    }
+
+    #[test]
+    fn dedupes_items_across_mirrors() -> Result<()> {
+        assert_eq!(
+            first_item_ids(DedupeBy::UpdateKey)?,
+            ["a1", "a2", "b1", "b3"]
+        );
+        assert_eq!(first_item_ids(DedupeBy::Link)?, ["a1", "a2", "b3"]);
+        Ok(())
+    }
+
+    fn limited_item_ids(sort_by_last_modified: bool) -> Result<Vec<String>> {
+        let settings = Settings {
+            max_new_items_per_check: Some(2),
+            sort_by_last_modified,
+            ..Default::default()
+        };
+        let items = [("a", 3), ("b", 1), ("c", 4), ("d", 4)]
+            .iter()
+            .map(|(id, day)| {
+                format!(
+                    "<item><guid>{id}</guid><pubDate>Thu, 0{day} Jan 2026 00:00:00 GMT</pubDate></item>"
+                )
+            })
+            .collect::<String>();
+        let content =
+            format!("<rss version=\"2.0\"><channel><title>Flood</title>{items}</channel></rss>");
+        let feed = parse_feed(
+            content.as_bytes(),
+            &settings,
+            FeedSource::new("https://example.com/feed.xml", None),
+        )?;
+        Ok(limit_new_items(&settings, feed.borrow_items())
+            .iter()
+            .map(|item| item.item.id.clone())
+            .collect())
+    }
+
+    #[test]
+    fn limits_new_items_to_the_newest() -> Result<()> {
+        assert_eq!(limited_item_ids(false)?, ["a", "b"]);
+        assert_eq!(limited_item_ids(true)?, ["c", "d"]);
+        Ok(())
+    }
+
+    /// A worker with an SQLite database in `dir`, writing mails into `dir`.
+    async fn file_worker(dir: &Path) -> Result<Worker> {
+        let pool = Pool::connect(&format!("sqlite://{}", dir.join("db.sqlite").display())).await?;
+        db::init_db(&pool).await?;
+        let mailer = Mailer {
+            from: "from@example.com".parse()?,
+            transport: crate::email::Transport::from_url(
+                &format!("file://{}", dir.display()),
+                None,
+            )?,
+            retry: crate::email::SendRetry {
+                retries: 0,
+                backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            rate_limit: None,
+        };
+        Ok(Worker::new(pool, dir.join("config.toml"), mailer))
+    }
+
+    #[tokio::test]
+    async fn holding_items_keeps_the_new_feed_status() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("yaf2m-hold-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(
+            dir.join("feed.xml"),
+            "<rss version=\"2.0\"><channel><title>Held</title>\
+             <item><guid>a</guid><title>a</title></item></channel></rss>",
+        )
+        .await?;
+        let config = parse_config(&format!(
+            r#"
+            [[feeds]]
+            url = "file://{}"
+            no-recipients = "hold"
+            "#,
+            dir.join("feed.xml").display()
+        ))?;
+        let feed_group = &config.feeds[0];
+        let outcome = async {
+            let worker = file_worker(&dir).await?;
+            worker.process_feed(feed_group).await?;
+            worker.process_feed(feed_group).await?;
+            let status = db::peek_feed_status(&worker.pool, feed_group).await?;
+            let held = worker.held_feeds.lock().unwrap().len();
+            Ok::<_, color_eyre::Report>((status, held))
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+
+        // warned about once, not on every check
+        assert_eq!(outcome?, (FeedStatus::NewFeed, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_webhooks_are_kept_after_sending_mails() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("yaf2m-webhook-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(
+            dir.join("feed.xml"),
+            "<rss version=\"2.0\"><channel><title>Hook</title>\
+             <item><guid>a</guid><title>a</title></item></channel></rss>",
+        )
+        .await?;
+        // nothing listens on the port once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let config = parse_config(&format!(
+            r#"
+            [[feeds]]
+            url = "file://{}"
+            to = "you@example.com"
+            delivery = "both"
+            webhook-url = "http://{addr}/hook"
+            fetch-retries = 0
+            "#,
+            dir.join("feed.xml").display()
+        ))?;
+        let feed_group = &config.feeds[0];
+        let outcome = async {
+            let worker = file_worker(&dir).await?;
+            worker.process_feed(feed_group).await?;
+            let pending = db::get_pending_webhooks(&worker.pool, feed_group.urls_hash).await?;
+            // the next check retries them first, and fails while the webhook is down
+            let retried = post_pending_webhooks((&worker.pool).into(), feed_group).await;
+            let still_pending =
+                db::get_pending_webhooks(&worker.pool, feed_group.urls_hash).await?;
+            Ok::<_, color_eyre::Report>((pending, retried, still_pending))
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+
+        let (pending, retried, still_pending) = outcome?;
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].1.contains(r#""id":"a""#));
+        assert!(retried.is_err());
+        assert_eq!(still_pending, pending);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_limits_new_items() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("yaf2m-dry-run-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let items = ["a", "b", "c", "d"]
+            .iter()
+            .map(|id| format!("<item><guid>{id}</guid><title>{id}</title></item>"))
+            .collect::<String>();
+        tokio::fs::write(
+            dir.join("feed.xml"),
+            format!("<rss version=\"2.0\"><channel><title>Flood</title>{items}</channel></rss>"),
+        )
+        .await?;
+        let config = parse_config(&format!(
+            r#"
+            [[feeds]]
+            url = "file://{}"
+            to = "you@example.com"
+            max-new-items-per-check = 2
+            "#,
+            dir.join("feed.xml").display()
+        ))?;
+        let outcome = async {
+            let pool =
+                Pool::connect(&format!("sqlite://{}", dir.join("db.sqlite").display())).await?;
+            db::init_db(&pool).await?;
+            dry_run_feed(&pool, &config.feeds[0]).await
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+
+        let outcome = outcome?;
+        assert_eq!(outcome.new_items, 4);
+        assert_eq!(outcome.sent_items, 2);
+        // the first check of a feed group is mailed as a digest
+        assert_eq!(outcome.mails.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_link_ignores_params() {
+        let ignore_params = ["utm_*".to_string(), "ref".to_string()];
+        assert_eq!(
+            dedupe_link(
+                "https://Example.com/a/?utm_source=x&id=1&ref=y#c",
+                &ignore_params
+            ),
+            "example.com/a?id=1"
+        );
+        assert_eq!(
+            dedupe_link("http://example.com/a?utm_medium=x", &ignore_params),
+            "example.com/a"
+        );
+        assert_eq!(dedupe_link(" not a url ", &ignore_params), "not a url");
+    }
+
+    #[test]
+    fn jitter_delay_is_capped_by_interval() {
+        assert_eq!(
+            jitter_delay(Duration::ZERO, TimeDelta::hours(1)),
+            Duration::ZERO
+        );
+        for _ in 0..100 {
+            assert!(
+                jitter_delay(Duration::from_mins(10), TimeDelta::hours(1))
+                    <= Duration::from_mins(10)
+            );
+            assert!(
+                jitter_delay(Duration::from_mins(10), TimeDelta::seconds(30))
+                    <= Duration::from_secs(30)
+            );
+        }
+    }
+
+    #[test]
+    fn last_scheduled_time_uses_timezone() -> Result<()> {
+        let schedule = "0 0 8 * * *".parse::<Schedule>()?;
+        let time = |s: &str| -> Result<DateTime<Utc>> { Ok(s.parse()?) };
+
+        assert_eq!(
+            last_scheduled_time(&schedule, None, time("2026-01-02T09:00:00Z")?)?,
+            time("2026-01-02T08:00:00Z")?
+        );
+        assert_eq!(
+            last_scheduled_time(&schedule, None, time("2026-01-02T07:00:00Z")?)?,
+            time("2026-01-01T08:00:00Z")?
+        );
+        // 08:00 in Shanghai is 00:00 UTC
+        assert_eq!(
+            last_scheduled_time(
+                &schedule,
+                Some("Asia/Shanghai"),
+                time("2026-01-02T07:00:00Z")?
+            )?,
+            time("2026-01-02T00:00:00Z")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reload_rejects_invalid_templates() -> Result<()> {
+        let config = parse_config(
+            r#"
+            [[feeds]]
+            url = "https://example.com/feed.xml"
+            item-subject.inline = "{{ item.title"
+            "#,
+        )?;
+
+        let error = format!(
+            "{:#}",
+            check_config(&config).expect_err("invalid template accepted")
+        );
+        assert!(error.contains("https://example.com/feed.xml"));
+        assert!(error.contains("item-subject.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn text_body_is_converted_from_html() -> Result<()> {
+        let config = parse_config(
+            r#"
+            [[feeds]]
+            url = "https://example.com/a.xml"
+            text-body = true
+
+            [[feeds]]
+            url = "https://example.com/b.xml"
+            "#,
+        )?;
+        let html = "<h1>Title</h1><p>Hello <a href=\"https://example.com/post\">post</a></p>";
+        let text_body = |feed_group: &FeedGroup| {
+            let renderer = Renderer::from_feed(feed_group)?;
+            render_text_body(
+                feed_group,
+                &renderer,
+                TemplateName::ItemTextBody,
+                minijinja::context! {},
+                html,
+            )
+        };
+
+        let text = text_body(&config.feeds[0])?.expect("text body not converted");
+        assert!(text.contains("Title"));
+        assert!(text.contains("Hello"));
+        assert!(text.contains("https://example.com/post"));
+        assert!(!text.contains("<p>"));
+        assert_eq!(text_body(&config.feeds[1])?, None);
+
+        let renderer = Renderer::from_feed(&config.feeds[0])?;
+        assert!(
+            render_text_body(
+                &config.feeds[0],
+                &renderer,
+                TemplateName::ItemBody,
+                minijinja::context! {},
+                html,
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn updated_items_get_a_new_message_id() -> Result<()> {
+        let config = parse_config(
+            r#"
+            [[feeds]]
+            url = "https://example.com/feed.xml"
+            thread-by = "feed"
+            update-on-updated-date = true
+            "#,
+        )?;
+        let feed_group = &config.feeds[0];
+        let renderer = Renderer::from_feed(feed_group)?;
+        let message_id = |updated: &str| -> Result<Option<Hash>> {
+            let content = format!(
+                "<feed xmlns=\"http://www.w3.org/2005/Atom\"><title>Updates</title>\
+                 <entry><id>a</id><title>a</title><updated>{updated}</updated></entry></feed>"
+            );
+            let feed = parse_feed(
+                content.as_bytes(),
+                &feed_group.settings,
+                FeedSource::new("https://example.com/feed.xml", None),
+            )?;
+            let item = &feed.borrow_items()[0];
+            let messages = compose_messages(
+                feed_group,
+                &renderer,
+                FeedStatus::Update,
+                std::slice::from_ref(&feed),
+                vec![item],
+                None,
+            )?;
+            Ok(messages[0].0.thread.as_ref().and_then(|thread| thread.id))
+        };
+
+        let first = message_id("2026-01-01T00:00:00Z")?;
+        assert!(first.is_some());
+        assert_eq!(first, message_id("2026-01-01T00:00:00Z")?);
+        assert_ne!(first, message_id("2026-01-02T00:00:00Z")?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_digest_without_new_items_when_enabled() -> Result<()> {
+        let mut settings = Settings {
+            digest_even_if_empty: true,
+            seed_silently: true,
+            ..Default::default()
+        };
+        assert!(sends_empty_digest(&settings, FeedStatus::Update));
+        assert!(!sends_empty_digest(&settings, FeedStatus::NewFeed));
+        settings.digest_even_if_empty = false;
+        assert!(!sends_empty_digest(&settings, FeedStatus::Update));
+
+        let config = parse_config(
+            r#"
+            [[feeds]]
+            url = "https://example.com/feed.xml"
+            digest-even-if-empty = true
+        "#,
+        )?;
+        let feed_group = &config.feeds[0];
+
+        let renderer = Renderer::from_feed(feed_group)?;
+        let feed = mirror("https://example.com/feed.xml", &[], &feed_group.settings)?;
+        let mails = compose_mails(
+            feed_group,
+            &renderer,
+            FeedStatus::Update,
+            std::slice::from_ref(&feed),
+            Vec::new(),
+            None,
+        )?;
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].subject.ends_with("0 new items from Mirror"));
+        assert!(mails[0].body.contains("Nothing new since the last check."));
+        Ok(())
+    }
+
+    #[test]
+    fn lacks_recipients_only_when_mailing() {
+        let mut settings = Settings {
+            seed_silently: true,
+            ..Default::default()
+        };
+        assert!(lacks_recipients(&settings, FeedStatus::Update));
+        assert!(!lacks_recipients(&settings, FeedStatus::NewFeed));
+
+        settings.delivery = Delivery::Webhook;
+        assert!(!lacks_recipients(&settings, FeedStatus::Update));
+
+        settings.delivery = Delivery::Both;
+        settings.personalize = true;
+        settings.cc = Arc::new(["cc@example.com".parse().unwrap()]);
+        assert!(lacks_recipients(&settings, FeedStatus::Update));
+        settings.personalize = false;
+        assert!(!lacks_recipients(&settings, FeedStatus::Update));
+    }
+
+    #[tokio::test]
+    async fn digest_items_follow_sort_by_last_modified() -> Result<()> {
+        let config = parse_config(
+            r#"
+            [[feeds]]
+            url = "https://example.com/feed.xml"
+            digest = true
+            sort-by-last-modified = true
+            digest-body.inline = "{% for ctx in items %}{{ ctx.item.id }} {% endfor %}"
+        "#,
+        )?;
+        let feed_group = &config.feeds[0];
+
+        let items = [("a", 2), ("b", 3), ("c", 1)]
+            .iter()
+            .map(|(id, day)| {
+                format!(
+                    "<item><guid>{id}</guid><pubDate>Thu, 0{day} Jan 2026 00:00:00 GMT</pubDate></item>"
+                )
+            })
+            .collect::<String>();
+        let content =
+            format!("<rss version=\"2.0\"><channel><title>Sorted</title>{items}</channel></rss>");
+        let feed = parse_feed(
+            content.as_bytes(),
+            &feed_group.settings,
+            FeedSource::new("https://example.com/feed.xml", None),
+        )?;
+
+        let renderer = Renderer::from_feed(feed_group)?;
+        let mails = compose_mails(
+            feed_group,
+            &renderer,
+            FeedStatus::Update,
+            std::slice::from_ref(&feed),
+            feed.borrow_items().iter().collect(),
+            None,
+        )?;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].body, "b a c ");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn processing_order_ignores_config_order() -> Result<()> {
+        let feeds = [
+            "[[feeds]]\nurl = \"https://example.com/a.xml\"",
+            "[[feeds]]\nurl = \"https://example.com/b.xml\"",
+            "[[feeds]]\nurl = \"https://example.com/c.xml\"\npriority = 1",
+        ];
+        let mut orders = Vec::new();
+        for config in [
+            feeds.join("\n"),
+            feeds.iter().rev().cloned().collect::<Vec<_>>().join("\n"),
+        ] {
+            let mut feeds = parse_config(&config)?.feeds;
+            feeds.sort_by_key(processing_order);
+            orders.push(
+                feeds
+                    .into_iter()
+                    .map(|feed| feed.urls[0].clone())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(orders[0], orders[1]);
+        assert_eq!(orders[0][0], "https://example.com/c.xml");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transforms_items_before_filtering() -> Result<()> {
+        let config = parse_config(
+            r#"
+            [[feeds]]
+            url = "https://example.com/feed.xml"
+            item-transform.title = '"[" ~ item.id ~ "]"'
+            item-transform.summary = '"<b>" ~ item.id ~ "</b>" if item.id == "a1" else none'
+            item-transform.link = 'item.links[0].href | regex_replace("[?#].*", "")'
+            filter.title-regex = "^\\[a"
+        "#,
+        )?;
+        let feed_group = &config.feeds[0];
+
+        let renderer = Renderer::from_feed(feed_group)?;
+        let feed = mirror(
+            "https://example.com/feed.xml",
+            &[
+                ("a1", "https://example.com/post/1?utm_source=a"),
+                ("a2", "https://example.com/post/2#top"),
+                ("b1", "https://example.com/post/3"),
+            ],
+            &feed_group.settings,
+        )?;
+        let feed = transform_items(feed_group, &renderer, feed)?;
+        let items = feed
+            .borrow_items()
+            .iter()
+            .filter_map(|item| match filter_item(feed_group, &renderer, item) {
+                Ok(true) => Some(Ok(item.item)),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let fields = items
+            .iter()
+            .map(|item| {
+                (
+                    item.title.as_ref().map(|t| t.content.as_str()),
+                    item.summary.as_ref().map(|t| t.content.as_str()),
+                    item.links[0].href.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                (
+                    Some("[a1]"),
+                    // the new summary is plain text, so it is escaped by the sanitizer
+                    Some("&lt;b&gt;a1&lt;&#47;b&gt;"),
+                    "https://example.com/post/1"
+                ),
+                (Some("[a2]"), None, "https://example.com/post/2"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn personalizes_mails_per_recipient() -> Result<()> {
+        let config = parse_config(
+            r#"
+            [[feeds]]
+            url = "https://example.com/feed.xml"
+            to = ["Alice <alice@example.com>", "bob@example.com"]
+            cc = "carol@example.com"
+            personalize = true
+            item-subject.inline = "Hi {{ recipient.name or recipient.email }}: {{ item.id }}"
+            list-unsubscribe = ["mailto:unsubscribe@example.com?subject={{ recipient.email }}"]
+        "#,
+        )?;
+        let feed_group = &config.feeds[0];
+
+        let renderer = Renderer::from_feed(feed_group)?;
+        let feed = mirror(
+            "https://example.com/feed.xml",
+            &[("a1", "https://example.com/post/1")],
+            &feed_group.settings,
+        )?;
+        let feeds = std::slice::from_ref(&feed);
+
+        let subjects = mail_recipients(&feed_group.settings)
+            .into_iter()
+            .map(|recipient| {
+                let items = feed.borrow_items().iter().collect();
+                let mails = compose_mails(
+                    feed_group,
+                    &renderer,
+                    FeedStatus::Update,
+                    feeds,
+                    items,
+                    recipient,
+                )?;
+                Ok(mails
+                    .into_iter()
+                    .map(|mail| mail.subject)
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            subjects,
+            [
+                vec!["Hi Alice: a1".to_string()],
+                vec!["Hi bob@example.com: a1".to_string()]
+            ]
+        );
+
+        let bob = &feed_group.settings.to[1];
+        assert_eq!(
+            personalized_list_unsubscribe(feed_group, &renderer, bob)?,
+            ["mailto:unsubscribe@example.com?subject=bob@example.com"]
+        );
+        let headers = MailHeaders::personalized(&feed_group.settings, bob, &[]);
+        assert_eq!(headers.to, std::slice::from_ref(bob));
+        assert!(headers.cc.is_empty());
+        Ok(())
+    }
 }